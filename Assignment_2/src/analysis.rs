@@ -0,0 +1,445 @@
+use std::{cmp::Reverse, collections::BTreeMap, fs, path::Path};
+
+use serde_derive::Serialize;
+
+use crate::{
+    clocks::{ClockStats, VectorClock},
+    utils::{Action, LogEntry, Pid},
+};
+
+/// One critical section as seen in a node's log. Times are microseconds since
+/// the epoch, so sections of different nodes can be compared.
+#[derive(Debug, Clone, Copy)]
+pub struct Section {
+    pub pid: Pid,
+    pub ts: u128, // Lamport timestamp of the request
+    pub ask: u128,
+    pub acquire: u128,
+    pub exit: u128,
+    pub msgs: u64, // Sent and received by the requester for this section
+}
+
+impl Section {
+    /// Time from request to entry
+    pub fn response(&self) -> u128 {
+        self.acquire - self.ask
+    }
+}
+
+/// Reads every `node_*.log` in `dir`. Lines that don't parse are skipped.
+pub fn read_logs(dir: impl AsRef<Path>) -> BTreeMap<String, Vec<LogEntry>> {
+    let mut out = BTreeMap::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        if !name.starts_with("node_") {
+            continue;
+        }
+        let log = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .filter_map(|l| l.parse().ok())
+            .collect();
+        out.insert(name, log);
+    }
+    out
+}
+
+/// Pairs up each request of a node with the entry and exit that follow it.
+/// Requests that never got in are left out; see `unmatched`.
+pub fn sections(log: &[LogEntry]) -> Vec<Section> {
+    let mut start = 0;
+    let mut open: Option<Section> = None;
+    let mut out = vec![];
+    for entry in log {
+        let at = start + entry.ts;
+        match entry.act {
+            Action::Start(x) => start = x,
+            Action::Ask(ts) => {
+                open = Some(Section {
+                    pid: entry.pid,
+                    ts,
+                    ask: at,
+                    acquire: 0,
+                    exit: 0,
+                    msgs: 0,
+                })
+            }
+            Action::Acquire => {
+                if let Some(s) = open.as_mut() {
+                    s.acquire = at;
+                }
+            }
+            Action::Exit => {
+                if let Some(mut s) = open.take().filter(|s| s.acquire > 0) {
+                    s.exit = at;
+                    out.push(s);
+                }
+            }
+            Action::Messages(x) => {
+                if let Some(s) = out.last_mut() {
+                    s.msgs = x;
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+/// A request that never got into the CS before its log ends
+#[derive(Debug, Clone, Copy)]
+pub struct Unmatched {
+    pub pid: Pid,
+    pub ts: u128,
+    pub ask: u128,
+}
+
+/// Requests of a node with no entry after them, either because the log ends
+/// first or because the node asked again.
+pub fn unmatched(log: &[LogEntry]) -> Vec<Unmatched> {
+    let mut start = 0;
+    let mut open: Option<Unmatched> = None;
+    let mut out = vec![];
+    for entry in log {
+        let at = start + entry.ts;
+        match entry.act {
+            Action::Start(x) => start = x,
+            Action::Ask(ts) => {
+                out.extend(open.replace(Unmatched {
+                    pid: entry.pid,
+                    ts,
+                    ask: at,
+                }));
+            }
+            Action::Acquire => open = None,
+            _ => {}
+        }
+    }
+    out.extend(open);
+    out
+}
+
+/// Time of the last entry of a log, on the same clock as `Section`
+pub fn last_entry(log: &[LogEntry]) -> u128 {
+    let mut start = 0;
+    let mut last = 0;
+    for entry in log {
+        last = last.max(start + entry.ts);
+        if let Action::Start(x) = entry.act {
+            start = x;
+        }
+    }
+    last
+}
+
+/// Summary of a set of durations, in microseconds
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Stats {
+    pub count: usize,
+    pub mean: f64,
+    pub min: u128,
+    pub p50: u128,
+    pub p90: u128,
+    pub p99: u128,
+    pub max: u128,
+}
+
+impl Stats {
+    pub fn new(xs: &[u128]) -> Self {
+        if xs.is_empty() {
+            return Self::default();
+        }
+        let mut xs = xs.to_vec();
+        xs.sort();
+        // Nearest rank
+        let pct = |p: usize| xs[(xs.len() * p).div_ceil(100) - 1];
+        Self {
+            count: xs.len(),
+            mean: xs.iter().sum::<u128>() as f64 / xs.len() as f64,
+            min: xs[0],
+            p50: pct(50),
+            p90: pct(90),
+            p99: pct(99),
+            max: xs[xs.len() - 1],
+        }
+    }
+}
+
+/// Jain's fairness index: 1 when all values are equal, 1/n when one node
+/// gets everything.
+pub fn jain(xs: &[f64]) -> f64 {
+    let sum: f64 = xs.iter().sum();
+    let sq: f64 = xs.iter().map(|x| x * x).sum();
+    if sq == 0.0 {
+        return 1.0;
+    }
+    sum * sum / (xs.len() as f64 * sq)
+}
+
+/// Pairs `(a, b)` where `a` entered first although `b` was already waiting
+/// with an older request. Ties on the timestamp go to the higher pid, as in
+/// the queues of `Request`.
+pub fn out_of_order(cs: &[Section]) -> Vec<(Section, Section)> {
+    let mut cs = cs.to_vec();
+    cs.sort_by_key(|x| x.acquire);
+    let mut out = vec![];
+    for (i, a) in cs.iter().enumerate() {
+        for b in cs[i + 1..].iter() {
+            if b.ask < a.acquire && (b.ts, Reverse(b.pid)) < (a.ts, Reverse(a.pid)) {
+                out.push((*a, *b));
+            }
+        }
+    }
+    out
+}
+
+/// A critical section by causal clock, from a run with `--clock`
+#[derive(Debug, Clone)]
+pub struct CausalSection {
+    pub pid: Pid,
+    pub acquire: VectorClock,
+    pub exit: VectorClock,
+}
+
+/// Sections of a log whose entry and exit are stamped
+pub fn causal_sections(log: &[LogEntry]) -> Vec<CausalSection> {
+    let mut open = None;
+    let mut out = vec![];
+    for entry in log {
+        match (&entry.act, &entry.clock) {
+            (Action::Acquire, Some(x)) => open = Some(x.clone()),
+            (Action::Exit, Some(x)) => {
+                if let Some(acquire) = open.take() {
+                    out.push(CausalSection {
+                        pid: entry.pid,
+                        acquire,
+                        exit: x.clone(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Pairs of sections neither of which exited before the other entered, by
+/// happened-before. Mutual exclusion over messages orders every pair, so
+/// each of these is a violation, or a node entered without hearing from a
+/// crashed one.
+pub fn unordered(cs: &[CausalSection]) -> Vec<(CausalSection, CausalSection)> {
+    let mut out = vec![];
+    for (i, a) in cs.iter().enumerate() {
+        for b in cs[i + 1..].iter() {
+            if !a.exit.happened_before(&b.acquire) && !b.exit.happened_before(&a.acquire) {
+                out.push((a.clone(), b.clone()));
+            }
+        }
+    }
+    out
+}
+
+/// Stamped entries of one node that are not in happened-before order. The
+/// events of a node are totally ordered, so there should be none.
+pub fn not_sequential(log: &[LogEntry]) -> Vec<(LogEntry, LogEntry)> {
+    let mut stamped: Vec<_> = log.iter().filter(|x| x.clock.is_some()).collect();
+    // Any linear extension of happened-before orders them the same way
+    stamped.sort_by_key(|x| x.clock.as_ref().unwrap().0.iter().sum::<u64>());
+    stamped
+        .windows(2)
+        .filter(|w| {
+            let (a, b) = (w[0].clock.as_ref().unwrap(), w[1].clock.as_ref().unwrap());
+            !a.happened_before(b)
+        })
+        .map(|w| (w[0].clone(), w[1].clone()))
+        .collect()
+}
+
+/// Pairs of deliveries in a node's log where the first was delivered before
+/// the second, yet the second's broadcast happened before the first's.
+pub fn out_of_causal_order(log: &[LogEntry]) -> Vec<(LogEntry, LogEntry)> {
+    let delivered: Vec<_> = log
+        .iter()
+        .filter(|x| matches!(x.act, Action::Deliver(..)) && x.clock.is_some())
+        .collect();
+    let mut out = vec![];
+    for (i, a) in delivered.iter().enumerate() {
+        for b in delivered[i + 1..].iter() {
+            if b.clock
+                .as_ref()
+                .unwrap()
+                .happened_before(a.clock.as_ref().unwrap())
+            {
+                out.push(((*a).clone(), (*b).clone()));
+            }
+        }
+    }
+    out
+}
+
+/// The senders and values of the messages a node delivered, in order
+pub fn deliveries(log: &[LogEntry]) -> Vec<(Pid, u128)> {
+    log.iter()
+        .filter_map(|x| match x.act {
+            Action::Deliver(p, v) => Some((p, v)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Where two delivery sequences first differ, if anywhere. A sequence that
+/// stops short of the other differs where it stops.
+pub fn divergence<T: PartialEq>(a: &[T], b: &[T]) -> Option<usize> {
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+        Some(i) => Some(i),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+/// Metrics of one node over a run
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeStats {
+    pub name: String,
+    pub sections: usize,
+    pub messages: f64,   // per CS
+    pub throughput: f64, // CS per second over the whole run
+    pub response: Stats,
+}
+
+/// Metrics of a whole run, from the logs in its directory
+#[derive(Debug, Clone, Serialize)]
+pub struct RunStats {
+    pub nodes: Vec<NodeStats>,
+    pub sections: usize,
+    pub sent: u64,     // total messages, from `out_*.log`
+    pub elapsed: f64,  // mean running time of a node in ms, from `out_*.log`
+    pub messages: f64, // per CS
+    pub throughput: f64,
+    pub response: Stats,
+    /// Time from one exit to the next entry, when someone was waiting
+    pub sync_delay: Stats,
+    /// Clock bytes sent, from `clock_*.json`, if the nodes kept clocks
+    pub clock: Option<ClockStats>,
+}
+
+impl RunStats {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        let logs: Vec<_> = read_logs(dir)
+            .into_iter()
+            .map(|(name, log)| (name, sections(&log)))
+            .collect();
+        let all: Vec<_> = logs.iter().flat_map(|x| x.1.iter().copied()).collect();
+
+        let span = match (
+            all.iter().map(|x| x.ask).min(),
+            all.iter().map(|x| x.exit).max(),
+        ) {
+            (Some(a), Some(b)) if b > a => (b - a) as f64 / 1e6,
+            _ => 0.0,
+        };
+        let rate = |n: usize| if span > 0.0 { n as f64 / span } else { 0.0 };
+        let per_cs = |cs: &[Section]| {
+            if cs.is_empty() {
+                return 0.0;
+            }
+            cs.iter().map(|x| x.msgs).sum::<u64>() as f64 / cs.len() as f64
+        };
+        let response =
+            |cs: &[Section]| Stats::new(&cs.iter().map(|x| x.response()).collect::<Vec<_>>());
+
+        let nodes = logs
+            .iter()
+            .map(|(name, cs)| NodeStats {
+                name: name.clone(),
+                sections: cs.len(),
+                messages: per_cs(cs),
+                throughput: rate(cs.len()),
+                response: response(cs),
+            })
+            .collect();
+
+        let mut order = all.clone();
+        order.sort_by_key(|x| x.acquire);
+        let delays: Vec<_> = order
+            .windows(2)
+            .filter(|w| w[1].ask < w[0].exit && w[1].acquire >= w[0].exit)
+            .map(|w| w[1].acquire - w[0].exit)
+            .collect();
+
+        let (mut sent, mut elapsed, mut outs) = (0, 0, 0);
+        let mut clock: Option<ClockStats> = None;
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap().to_string();
+            if name.starts_with("out_") {
+                let buf = fs::read_to_string(&path).unwrap();
+                let mut it = buf
+                    .split_whitespace()
+                    .map(|x| x.parse::<u64>().unwrap_or(0));
+                sent += it.next().unwrap_or(0);
+                elapsed += it.next().unwrap_or(0);
+                outs += 1;
+            } else if name.starts_with("clock_") {
+                let x = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+                clock.get_or_insert_with(ClockStats::default).add(&x);
+            }
+        }
+
+        Self {
+            nodes,
+            sections: all.len(),
+            sent,
+            elapsed: if outs > 0 {
+                elapsed as f64 / outs as f64
+            } else {
+                0.0
+            },
+            messages: per_cs(&all),
+            throughput: rate(all.len()),
+            response: response(&all),
+            sync_delay: Stats::new(&delays),
+            clock,
+        }
+    }
+
+    /// Writes the stats as JSON.
+    pub fn write(&self, path: impl AsRef<Path>) {
+        fs::write(path, serde_json::to_string_pretty(self).unwrap()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use either::Either::Right;
+
+    fn entry(ts: u128, act: Action) -> LogEntry {
+        LogEntry {
+            pid: Right(1),
+            ts,
+            act,
+            clock: None,
+        }
+    }
+
+    #[test]
+    fn unmatched_requests_are_kept() {
+        let log = [
+            entry(0, Action::Start(1000)),
+            entry(10, Action::Ask(1)),
+            entry(20, Action::Acquire),
+            entry(30, Action::Exit),
+            // Asked again without ever getting in
+            entry(40, Action::Ask(2)),
+            entry(50, Action::Ask(3)),
+            entry(60, Action::Internal),
+        ];
+        assert_eq!(sections(&log).len(), 1);
+        let left: Vec<_> = unmatched(&log).iter().map(|x| (x.ts, x.ask)).collect();
+        assert_eq!(left, [(2, 1040), (3, 1050)]);
+        assert_eq!(last_entry(&log), 1060);
+    }
+}
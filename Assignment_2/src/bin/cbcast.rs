@@ -0,0 +1,205 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::Write,
+    process,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use either::Either::Right;
+use rand::thread_rng;
+
+use assignment_2::{
+    analysis::out_of_causal_order,
+    broadcast::{CausalBroadcast, TotalBroadcast},
+    chaos::Chaos,
+    clocks::VectorClock,
+    config::{Algorithm, ClusterConfig},
+    dist::Distribution,
+    utils::{epoch_micros, Action, LogEntry},
+};
+
+/// Set in the last value a node broadcasts, along with how many came before,
+/// since total order need not keep a node's broadcasts in the order sent
+const DONE: u128 = 1 << 127;
+
+/// Either broadcast, as the demo uses them
+#[derive(Clone)]
+enum Group {
+    Causal(Arc<CausalBroadcast>),
+    Total(Arc<TotalBroadcast>),
+}
+
+impl Group {
+    fn broadcast(&self, value: u128) -> Option<VectorClock> {
+        match self {
+            Group::Causal(x) => Some(x.broadcast(value)),
+            Group::Total(x) => {
+                x.broadcast(value);
+                None
+            }
+        }
+    }
+
+    /// Sender, value, stamp and whether it was held back
+    fn deliver(&self, timeout: Duration) -> Option<(usize, u128, Option<VectorClock>, bool)> {
+        match self {
+            Group::Causal(x) => x
+                .deliver(timeout)
+                .map(|d| (d.from, d.value, Some(d.stamp), d.held)),
+            Group::Total(x) => x.deliver(timeout).map(|d| (d.from, d.value, None, false)),
+        }
+    }
+}
+
+/// Causal broadcast demo, on an RC config. Every node broadcasts `k` values,
+/// `out_l` ms apart on average, then a last one to say it is done, and
+/// delivers until it has everyone's last. Deliveries are logged to
+/// `node_<id>.log` in `log/cbcast` (or `--log-dir`) with their stamps, and
+/// checked for causal order.
+///
+/// With `--total`, the values go by total-order broadcast instead, and are
+/// logged to `log/abcast`, for the `total` checker.
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let total = args.iter().any(|x| x == "--total");
+    args.retain(|x| x != "--total");
+    let (cfg, rest) = ClusterConfig::from_args(Algorithm::Rc, &args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let id: usize = match &rest[..] {
+        [x] => x.parse().expect("Node id must be a number"),
+        _ => {
+            eprintln!("usage: cbcast <id> [--total] [--config <file>] [flags]");
+            process::exit(2);
+        }
+    };
+    if id >= cfg.n {
+        eprintln!("No node {}: n is {}", id, cfg.n);
+        process::exit(2);
+    }
+    let mut cfg = cfg;
+    let rx = cfg.bind(id).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let default = if total { "log/abcast" } else { "log/cbcast" };
+    let dir = cfg.log_dir.clone().unwrap_or(default.to_string());
+    fs::create_dir_all(&dir).unwrap();
+
+    let addrs = cfg.addrs()[..cfg.n].to_vec();
+    let chaos = cfg
+        .chaos
+        .as_ref()
+        .map(|path| Arc::new(Chaos::new(cfg.nodes[id].addr, path, &cfg.addrs())));
+    let group = if total {
+        let mut group = TotalBroadcast::new(id, addrs);
+        if let Some(chaos) = chaos {
+            group = group.with_chaos(chaos);
+        }
+        if let Some(router) = cfg.router(id) {
+            group = group.with_router(router);
+        }
+        let group = Arc::new(group);
+        group.start(rx);
+        Group::Total(group)
+    } else {
+        let mut group = CausalBroadcast::new(id, addrs);
+        if let Some(chaos) = chaos {
+            group = group.with_chaos(chaos);
+        }
+        if let Some(router) = cfg.router(id) {
+            group = group.with_router(router);
+        }
+        let group = Arc::new(group);
+        group.start(rx);
+        Group::Causal(group)
+    };
+    println!("Connections established.");
+
+    let init = Instant::now();
+    let entry = |act, clock| LogEntry {
+        pid: Right(id as u128),
+        ts: init.elapsed().as_micros(),
+        act,
+        clock,
+    };
+    let mut log = vec![LogEntry {
+        pid: Right(id as u128),
+        ts: 0,
+        act: Action::Start(epoch_micros()),
+        clock: None,
+    }];
+
+    let (k, gap) = (cfg.k, Distribution::Exponential(cfg.out_l));
+    let sender = {
+        let group = group.clone();
+        thread::spawn(move || {
+            let mut rng = thread_rng();
+            let mut out = vec![];
+            for i in 0..k {
+                thread::sleep(Duration::from_secs_f64(gap.sample(&mut rng) / 1000.0));
+                let stamp = group.broadcast(i as u128);
+                out.push((Action::Broadcast(i as u128), stamp, init.elapsed()));
+            }
+            let stamp = group.broadcast(DONE | k as u128);
+            out.push((Action::Broadcast(DONE | k as u128), stamp, init.elapsed()));
+            out
+        })
+    };
+
+    // Per node, how many it broadcast once its last has come, and how many
+    // we have
+    let mut want = vec![None; cfg.n];
+    let mut got = vec![0; cfg.n];
+    let (mut held, mut count) = (0, 0);
+    while (0..cfg.n).any(|i| want[i] != Some(got[i])) {
+        let Some((from, value, stamp, was_held)) = group.deliver(Duration::from_secs(1)) else {
+            continue;
+        };
+        count += 1;
+        held += was_held as usize;
+        got[from] += 1;
+        if value & DONE != 0 {
+            want[from] = Some((value & !DONE) + 1);
+        }
+        log.push(entry(Action::Deliver(Right(from as u128), value), stamp));
+    }
+    for (act, stamp, at) in sender.join().unwrap() {
+        log.push(LogEntry {
+            pid: Right(id as u128),
+            ts: at.as_micros(),
+            act,
+            clock: stamp,
+        });
+    }
+    log.sort_by_key(|x| x.ts);
+    // Late copies from `Chaos`, and whatever we relay, still have to get out
+    thread::sleep(Duration::from_secs(1));
+
+    let mut file = File::create(format!("{}/node_{}.log", dir, id)).unwrap();
+    for x in log.iter() {
+        writeln!(file, "{}", x).unwrap();
+    }
+    if total {
+        // Whether everyone agrees takes all the logs
+        println!("Delivered {} messages", count);
+        return;
+    }
+    let wrong = out_of_causal_order(&log);
+    for (a, b) in wrong.iter().take(5) {
+        println!("Delivered {} before {}", a, b);
+    }
+    println!(
+        "Delivered {} messages, {} of them held back, {} out of causal order",
+        count,
+        held,
+        wrong.len()
+    );
+    if !wrong.is_empty() {
+        process::exit(1);
+    }
+}
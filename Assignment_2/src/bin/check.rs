@@ -0,0 +1,54 @@
+use assignment_2::model::{check, Maekawa, Rc, Violation};
+use std::{env, process};
+
+/// Model checks the protocol logic: `check <maekawa|rc> [k] [n] [--max-states N]`
+///
+/// States are compared by a 64 bit hash, so "No violations found" leaves a
+/// small chance that a state lost to a collision hides one.
+fn main() {
+    let args = env::args().collect::<Vec<String>>();
+    let algo = args.get(1).map(|x| x.as_str()).unwrap_or("maekawa");
+    let k = args.get(2).map_or(1, |x| x.parse().unwrap());
+    let n = args.get(3).map_or(4, |x| x.parse().unwrap());
+    let max = args
+        .iter()
+        .position(|x| x == "--max-states")
+        .map_or(25_000_000, |i| args[i + 1].parse().unwrap());
+
+    let report = match algo {
+        "maekawa" => check::<Maekawa>(n, k, max),
+        "rc" => check::<Rc>(n, k, max),
+        x => panic!("Unknown algorithm: {}", x),
+    };
+    println!(
+        "{} states, {} transitions{}",
+        report.states,
+        report.transitions,
+        if report.complete {
+            ""
+        } else {
+            " (state limit reached: starvation not checked, trace may not be shortest)"
+        }
+    );
+
+    let Err(cex) = report.result else {
+        println!("No violations found, barring hash collisions.");
+        return;
+    };
+    match cex.violation {
+        Violation::Exclusion(x) => println!("Mutual exclusion violated: nodes {:?} in CS", x),
+        Violation::Deadlock(x) => println!("Deadlock: nodes {:?} can't finish", x),
+        Violation::Starvation(x) => println!("Starvation: node {} can wait forever", x),
+        Violation::Error(e) => println!("Protocol error: {}", e),
+    }
+    for (i, step) in cex.trace.iter().enumerate() {
+        println!("{:>4}: {}", i, step);
+    }
+    if !cex.cycle.is_empty() {
+        println!("then forever:");
+        for (i, step) in cex.cycle.iter().enumerate() {
+            println!("{:>4}: {}", i, step);
+        }
+    }
+    process::exit(1);
+}
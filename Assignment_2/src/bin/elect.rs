@@ -0,0 +1,143 @@
+use std::{env, fs, process, time::Duration};
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use assignment_2::{
+    config::{Algorithm, ClusterConfig},
+    election::{Election, ElectionNode},
+};
+
+/// Removes `flag` and its value from `args`, returning the value
+fn take(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let i = args.iter().position(|x| x == flag)?;
+    let Some(val) = args.get(i + 1).cloned() else {
+        eprintln!("{} needs a value", flag);
+        process::exit(2);
+    };
+    args.drain(i..i + 2);
+    Some(val)
+}
+
+/// One node of a leader election, on an RC config. Every node must get the
+/// same flags:
+///
+/// - `--alg <bully|cr|hs>`, bully by default
+/// - `--ranks <asc|desc|seed>`: node `i` has rank `i`, `n - 1 - i`, or a
+///   random permutation from the seed. The highest rank wins.
+/// - `--initiator <id>`: only that node starts the election, instead of all
+/// - `--crash <id,...>`: those nodes take no part, for the bully algorithm
+///
+/// The config's timeout, if set, is how long bully nodes wait for answers.
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let alg: Election = take(&mut args, "--alg")
+        .unwrap_or("bully".to_string())
+        .parse()
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2);
+        });
+    let ranks = take(&mut args, "--ranks");
+    let initiator = take(&mut args, "--initiator").map(|x| {
+        x.parse::<usize>().unwrap_or_else(|_| {
+            eprintln!("--initiator takes a node id, got {:?}", x);
+            process::exit(2);
+        })
+    });
+    let crash: Vec<usize> = take(&mut args, "--crash")
+        .map(|x| {
+            x.split(',')
+                .map(|y| y.parse())
+                .collect::<Result<_, _>>()
+                .unwrap_or_else(|_| {
+                    eprintln!(
+                        "--crash takes a comma separated list of node ids, got {:?}",
+                        x
+                    );
+                    process::exit(2);
+                })
+        })
+        .unwrap_or_default();
+    let (mut cfg, rest) = ClusterConfig::from_args(Algorithm::Rc, &args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let id: usize = match &rest[..] {
+        [x] => x.parse().expect("Node id must be a number"),
+        _ => {
+            eprintln!("usage: elect <id> [--alg <bully|cr|hs>] [--ranks <asc|desc|seed>] [--initiator <id>] [--crash <ids>] [--config <file>] [flags]");
+            process::exit(2);
+        }
+    };
+    if id >= cfg.n {
+        eprintln!("No node {}: n is {}", id, cfg.n);
+        process::exit(2);
+    }
+    if !crash.is_empty() && alg != Election::Bully {
+        eprintln!("Only the bully algorithm copes with --crash");
+        process::exit(2);
+    }
+    // Nobody would stand, and every node would wait for good
+    match initiator {
+        Some(x) if x >= cfg.n => {
+            eprintln!("No node {} to initiate: n is {}", x, cfg.n);
+            process::exit(2);
+        }
+        Some(x) if crash.contains(&x) => {
+            eprintln!("The initiator, node {}, is in --crash", x);
+            process::exit(2);
+        }
+        _ => {}
+    }
+    let n = cfg.n;
+    let ranks: Vec<u64> = match ranks.as_deref() {
+        None | Some("asc") => (0..n as u64).collect(),
+        Some("desc") => (0..n as u64).rev().collect(),
+        Some(seed) => {
+            let seed = seed.parse().unwrap_or_else(|_| {
+                eprintln!("--ranks takes asc, desc or a seed, got {:?}", seed);
+                process::exit(2);
+            });
+            let mut x: Vec<u64> = (0..n as u64).collect();
+            x.shuffle(&mut StdRng::seed_from_u64(seed));
+            x
+        }
+    };
+    let rx = cfg.bind(id).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let dir = cfg.log_dir.clone().unwrap_or("log/election".to_string());
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut node = ElectionNode::new(id, alg, cfg.addrs()[..n].to_vec())
+        .with_ranks(ranks)
+        .with_log_dir(&dir);
+    if cfg.timeout > 0 {
+        node = node.with_timeout(Duration::from_millis(cfg.timeout));
+    }
+    if initiator.is_some_and(|x| x != id) {
+        node = node.passive();
+    }
+    if crash.contains(&id) {
+        node = node.crashed();
+    }
+    if let Some(path) = &cfg.chaos {
+        node = node.with_chaos(path, &cfg.addrs());
+    }
+    if let Some(router) = cfg.router(id) {
+        node = node.with_router(router);
+    }
+    let leader = node.run(rx);
+
+    let elap = node.init.elapsed().as_millis();
+    fs::write(
+        format!("{}/out_{}.log", dir, id),
+        format!("{} {}", node.mc, elap),
+    )
+    .unwrap();
+    match leader {
+        Some(l) => println!("Elected node {} with {} messages sent", l, node.mc),
+        None => println!("Crashed"),
+    }
+}
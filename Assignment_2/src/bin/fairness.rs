@@ -0,0 +1,87 @@
+use std::env;
+
+use either::Either::{Left, Right};
+
+use assignment_2::{
+    analysis::{jain, last_entry, out_of_order, read_logs, sections, unmatched, Stats},
+    utils::Pid,
+};
+
+/// Response times per node and how fairly the CS was handed out, from the
+/// `node_*.log` files of a run. Requests still waiting when the run ends
+/// count towards the fairness index with the time they waited so far, so a
+/// starved node drags it down instead of dropping out of it.
+fn main() {
+    let dir = env::args().nth(1).unwrap_or("log/maekawa".to_string());
+    let logs = read_logs(&dir);
+    let end = logs.values().map(|x| last_entry(x)).max().unwrap_or(0);
+
+    let mut all = vec![];
+    let mut means = vec![];
+    let mut starved = 0;
+    println!(
+        "{:<20} {:>6} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "node", "count", "mean", "min", "p50", "p90", "p99", "max"
+    );
+    for (name, log) in logs {
+        let cs = sections(&log);
+        let mut waits: Vec<_> = cs.iter().map(|x| x.response()).collect();
+        let s = Stats::new(&waits);
+        println!(
+            "{:<20} {:>6} {:>10.0} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            name, s.count, s.mean, s.min, s.p50, s.p90, s.p99, s.max
+        );
+
+        let pending = unmatched(&log);
+        if let Some(longest) = pending.iter().map(|x| end - x.ask).max() {
+            println!(
+                "{} has {} request(s) that never entered the CS, the oldest waiting {}us",
+                name,
+                pending.len(),
+                longest
+            );
+            starved += pending.len();
+        }
+        waits.extend(pending.iter().map(|x| end - x.ask));
+        if !waits.is_empty() {
+            means.push(Stats::new(&waits).mean);
+        }
+        all.extend(cs);
+    }
+
+    let s = Stats::new(&all.iter().map(|x| x.response()).collect::<Vec<_>>());
+    println!(
+        "{:<20} {:>6} {:>10.0} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "all", s.count, s.mean, s.min, s.p50, s.p90, s.p99, s.max
+    );
+    println!("Response times are in us");
+    println!(
+        "Jain's fairness index: {:.4} ({} request(s) never entered)",
+        jain(&means),
+        starved
+    );
+
+    let bad = out_of_order(&all);
+    for (a, b) in bad.iter().take(10) {
+        println!(
+            "{} (ts {}) entered before {} (ts {}), which had been waiting {}us",
+            name(a.pid),
+            a.ts,
+            name(b.pid),
+            b.ts,
+            a.acquire - b.ask
+        );
+    }
+    println!(
+        "{} critical sections, {} entered out of timestamp order",
+        all.len(),
+        bad.len()
+    );
+}
+
+fn name(pid: Pid) -> String {
+    match pid {
+        Left(x) => format!("{:?}", x),
+        Right(x) => format!("{}", x),
+    }
+}
@@ -0,0 +1,118 @@
+use std::{env, fs, net::IpAddr, process};
+
+use assignment_2::config::ClusterConfig;
+
+const USAGE: &str = "usage: manifest <rc|maekawa|central|naimi|ring> <n> [--hosts 127.0.0.1,...] \
+                     [--port 8080] [--rendezvous rendezvous.txt] [--spares 0] [--topology <spec>] \
+                     [--coordinator 0] [-o cluster.json] [--ips]";
+
+/// Writes a cluster config for `n` nodes. Without `--port` every node picks a
+/// free port when it starts and the others learn it from the rendezvous file,
+/// which is emptied here. `--spares` lists that many more nodes, to be started
+/// later with `--join`. `--topology` takes a `ring`, `star`, `tree`,
+/// `random <p> [seed]` or an adjacency list file. `--coordinator` picks the
+/// node that grants the CS for `central`. `--ips` writes `ips.txt` instead,
+/// which needs fixed ports and no spares.
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let fail = |e: String| -> ! {
+        eprintln!("{}", e);
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    };
+    if args.len() < 2 {
+        fail("Missing algorithm or n".to_string());
+    }
+    let alg = args[0].parse().unwrap_or_else(|e| fail(e));
+    let n = args[1]
+        .parse()
+        .unwrap_or_else(|_| fail(format!("Bad n: {}", args[1])));
+
+    let mut hosts: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap()];
+    let mut port = None;
+    let mut rendezvous = "rendezvous.txt".to_string();
+    let mut path = "cluster.json".to_string();
+    let mut ips = false;
+    let mut spares = 0;
+    let mut topology = None;
+    let mut coordinator = 0;
+
+    let mut it = args[2..].iter();
+    while let Some(flag) = it.next() {
+        let mut val = || {
+            it.next()
+                .cloned()
+                .unwrap_or_else(|| fail(format!("{} needs a value", flag)))
+        };
+        match flag.as_str() {
+            "--hosts" => {
+                hosts = val()
+                    .split(',')
+                    .map(|x| {
+                        x.parse()
+                            .unwrap_or_else(|_| fail(format!("Bad host: {}", x)))
+                    })
+                    .collect()
+            }
+            "--port" => {
+                let x = val();
+                port = Some(
+                    x.parse()
+                        .unwrap_or_else(|_| fail(format!("Bad port: {}", x))),
+                )
+            }
+            "--rendezvous" => rendezvous = val(),
+            "--spares" => {
+                let x = val();
+                spares = x
+                    .parse()
+                    .unwrap_or_else(|_| fail(format!("Bad spare count: {}", x)))
+            }
+            "--topology" => topology = Some(val()),
+            "--coordinator" => {
+                let x = val();
+                coordinator = x
+                    .parse()
+                    .unwrap_or_else(|_| fail(format!("Bad coordinator: {}", x)))
+            }
+            "-o" => path = val(),
+            "--ips" => ips = true,
+            x => fail(format!("Unknown flag: {}", x)),
+        }
+    }
+
+    let mut cfg = ClusterConfig::generate(alg, n + spares, &hosts, port, &rendezvous);
+    cfg.n = n;
+    cfg.spares = spares;
+    cfg.topology = topology;
+    cfg.coordinator = coordinator;
+    cfg.validate().unwrap_or_else(|e| fail(e));
+
+    if ips {
+        if port.is_none() {
+            fail("ips.txt needs fixed ports: pass --port".to_string());
+        }
+        if spares > 0 || cfg.topology.is_some() || coordinator != 0 {
+            fail(
+                "ips.txt cannot list spares, a topology or a coordinator: write a config instead"
+                    .to_string(),
+            );
+        }
+        let out: String = (0..n)
+            .map(|i| {
+                let (a, b) = cfg.grid(i);
+                let addr = cfg.nodes[i].addr;
+                format!("{} {} {} {}\n", a, b, addr.ip(), addr.port())
+            })
+            .collect();
+        fs::write("ips.txt", out).unwrap();
+        println!("Written to ips.txt");
+        return;
+    }
+
+    fs::write(&path, serde_json::to_string_pretty(&cfg).unwrap()).unwrap();
+    if let Some(r) = &cfg.rendezvous {
+        fs::write(r, "").unwrap();
+    }
+    println!("Written to {}", path);
+}
@@ -0,0 +1,54 @@
+use assignment_2::config::{Algorithm, ClusterConfig};
+use assignment_2::naimi::NaimiTrehelNode;
+use assignment_2::Params;
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (mut cfg, rest) =
+        ClusterConfig::from_args(Algorithm::NaimiTrehel, &args).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2);
+        });
+    let id: usize = match &rest[..] {
+        [x] => x.parse().expect("Node id must be a number"),
+        _ => {
+            eprintln!("usage: naimi <id> [--config <file>] [flags]");
+            process::exit(2);
+        }
+    };
+    if id >= cfg.n {
+        eprintln!("No node {}: n is {}", id, cfg.n);
+        process::exit(2);
+    }
+    let rx = cfg.bind(id).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let params = Params::from_config(&cfg, id);
+    let dir = cfg.log_dir();
+    fs::create_dir_all(&dir).unwrap();
+    let mut node = NaimiTrehelNode::new(id, cfg.addrs()[..cfg.n].to_vec()).with_log_dir(&dir);
+    if let Some(path) = &cfg.chaos {
+        node = node.with_chaos(path, &cfg.addrs());
+    }
+    if let Some(router) = cfg.router(id) {
+        node = node.with_router(router);
+    }
+    let node = Arc::new(node);
+    node.run(rx, params);
+    let mc = node.mc.load(std::sync::atomic::Ordering::SeqCst);
+    let elap = node.init.elapsed().as_millis();
+    let mut f = File::create(format!("{}/out_{}.log", dir, id)).unwrap();
+    write!(f, "{} {}", mc, elap).unwrap();
+
+    // Late copies from `Chaos`, and whatever we relay, still have to get out
+    thread::sleep(Duration::from_secs(1));
+}
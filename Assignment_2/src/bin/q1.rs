@@ -1,22 +1,107 @@
-use assignment_2::{maekawa::MaekawaNode, utils::get_ips, Params};
+use assignment_2::{
+    config::{Algorithm, ClusterConfig},
+    maekawa::MaekawaNode,
+    Params,
+};
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
+use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 
 fn main() {
-    let params = Params::new();
-    let (ips, _) = get_ips();
-    let id = (
-        env::args().nth(1).unwrap().parse().unwrap(),
-        env::args().nth(2).unwrap().parse().unwrap(),
-    );
-    let node = Arc::new(MaekawaNode::new(id, ips));
-    let mut f = File::create(format!("log/maekawa/out_{}_{}.log", id.0, id.1)).unwrap();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // About this node rather than the cluster
+    let join = args.iter().any(|x| x == "--join");
+    let leave = args.iter().any(|x| x == "--leave");
+    args.retain(|x| x != "--join" && x != "--leave");
+    let snapshots = match args.iter().position(|x| x == "--snapshot") {
+        Some(i) => {
+            let ms = args.get(i + 1).and_then(|x| x.parse().ok());
+            let ms: u64 = ms.unwrap_or_else(|| {
+                eprintln!("--snapshot needs a period in ms");
+                process::exit(2);
+            });
+            args.drain(i..i + 2);
+            Some(Duration::from_millis(ms))
+        }
+        None => None,
+    };
+    let (mut cfg, rest) = ClusterConfig::from_args(Algorithm::Maekawa, &args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let (id, line) = match &rest[..] {
+        [a, b] => {
+            let coord = |x: &String, what: &str| {
+                x.parse::<u64>()
+                    .map_err(|_| format!("{} must be a whole number, got {:?}", what, x))
+            };
+            match (coord(a, "Row"), coord(b, "Column")) {
+                (Ok(a), Ok(b)) => ((a, b), cfg.find_grid((a, b))),
+                (Err(e), _) | (_, Err(e)) => ((0, 0), Err(e)),
+            }
+        }
+        _ => (
+            (0, 0),
+            Err("usage: q1 <row> <col> [--join] [--leave] [--snapshot <ms>] [--config <file>] [flags]".to_string()),
+        ),
+    };
+    let line = line.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    if line >= cfg.n && !join {
+        eprintln!("Node {:?} is a spare: start it with --join", id);
+        process::exit(2);
+    }
+    let rx = cfg.bind(line).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let params = Params::from_config(&cfg, line);
+    let dir = cfg.log_dir();
+    fs::create_dir_all(&dir).unwrap();
+    let mut node = MaekawaNode::from_listener(id, cfg.grid_ips(), rx)
+        .with_log_dir(&dir)
+        .with_spares(&cfg.spare_cells());
+    if join {
+        node = node.with_join();
+    }
+    if leave {
+        node = node.with_leave();
+    }
+    if let Some(t) = snapshots {
+        node = node.with_snapshots(t);
+    }
+    if let Some(t) = params.timeout {
+        node = node.with_detector(t);
+    }
+    if let Some(path) = &cfg.chaos {
+        node = node.with_chaos(path, &cfg.addrs());
+    }
+    if let Some(router) = cfg.router(line) {
+        node = node.with_router(router);
+    }
+    if let Some(kind) = cfg.clock {
+        node = node.with_clock(kind);
+    }
+    if cfg.wal {
+        node = node.with_wal(&format!("{}/wal_{}_{}.log", dir, id.0, id.1));
+    }
+    let node = Arc::new(node);
+    let mut f = File::create(format!("{}/out_{}_{}.log", dir, id.0, id.1)).unwrap();
     println!("Node {:?} spawned", id);
     node.clone().spawn(params);
     println!("Node {:?} terminated.", id);
     let mc = node.as_ref().mc.load(std::sync::atomic::Ordering::SeqCst);
     let elap = node.as_ref().init.elapsed().as_millis();
-    write!(f, "{} {}", mc, elap).unwrap();
+    let tmc = node
+        .as_ref()
+        .term_mc
+        .load(std::sync::atomic::Ordering::SeqCst);
+    // Termination detection messages go last, outside the message count
+    write!(f, "{} {} {}", mc, elap, tmc).unwrap();
 }
@@ -1,20 +1,99 @@
+use assignment_2::config::{Algorithm, ClusterConfig};
 use assignment_2::rc::RCNode;
-use assignment_2::{utils::get_ips, Params};
+use assignment_2::Params;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
+use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 
 fn main() {
-    let params = Params::new();
-    let (_, ips) = get_ips();
-    let id = env::args().nth(1).unwrap().parse().unwrap();
-    let node = Arc::new(RCNode::new(id, ips));
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // About this node rather than the cluster
+    let join = args.iter().any(|x| x == "--join");
+    let leave = args.iter().any(|x| x == "--leave");
+    args.retain(|x| x != "--join" && x != "--leave");
+    let snapshots = match args.iter().position(|x| x == "--snapshot") {
+        Some(i) => {
+            let ms = args.get(i + 1).and_then(|x| x.parse().ok());
+            let ms: u64 = ms.unwrap_or_else(|| {
+                eprintln!("--snapshot needs a period in ms");
+                process::exit(2);
+            });
+            args.drain(i..i + 2);
+            Some(Duration::from_millis(ms))
+        }
+        None => None,
+    };
+    let (mut cfg, rest) = ClusterConfig::from_args(Algorithm::Rc, &args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let id: u128 = match &rest[..] {
+        [x] => x.parse().unwrap_or_else(|_| {
+            eprintln!("Node id must be a whole number, got {:?}", x);
+            process::exit(2);
+        }),
+        _ => {
+            eprintln!(
+                "usage: q2 <id> [--join] [--leave] [--snapshot <ms>] [--config <file>] [flags]"
+            );
+            process::exit(2);
+        }
+    };
+    if id as usize >= cfg.nodes.len() {
+        eprintln!("No node {}: only {} are listed", id, cfg.nodes.len());
+        process::exit(2);
+    }
+    if id as usize >= cfg.n && !join {
+        eprintln!("Node {} is a spare: start it with --join", id);
+        process::exit(2);
+    }
+    let rx = cfg.bind(id as usize).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let params = Params::from_config(&cfg, id as usize);
+    let dir = cfg.log_dir();
+    fs::create_dir_all(&dir).unwrap();
+    let mut node = RCNode::from_listener(id, cfg.ips(), rx).with_log_dir(&dir);
+    if join {
+        node = node.with_join(cfg.all_ips());
+    }
+    if leave {
+        node = node.with_leave();
+    }
+    if let Some(t) = snapshots {
+        node = node.with_snapshots(t);
+    }
+    if let Some(t) = params.timeout {
+        node = node.with_detector(t);
+    }
+    if let Some(path) = &cfg.chaos {
+        node = node.with_chaos(path, &cfg.addrs());
+    }
+    if let Some(router) = cfg.router(id as usize) {
+        node = node.with_router(router);
+    }
+    if let Some(kind) = cfg.clock {
+        node = node.with_clock(kind);
+    }
+    if cfg.wal {
+        node = node.with_wal(&format!("{}/wal_{}.log", dir, id));
+    }
+    let node = Arc::new(node);
     node.clone().spawn(params);
     let mc = node.as_ref().mc.load(std::sync::atomic::Ordering::SeqCst);
     let elap = node.as_ref().init.elapsed().as_millis();
-    let mut f = File::create(format!("log/rc/out_{}.log", id)).unwrap();
-    write!(f, "{} {}", mc, elap).unwrap();
+    let tmc = node
+        .as_ref()
+        .term_mc
+        .load(std::sync::atomic::Ordering::SeqCst);
+    let mut f = File::create(format!("{}/out_{}.log", dir, id)).unwrap();
+    // Termination detection messages go last, outside the message count
+    write!(f, "{} {} {}", mc, elap, tmc).unwrap();
 
     // Ok((mc, elap))
 }
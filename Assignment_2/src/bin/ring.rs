@@ -0,0 +1,57 @@
+use assignment_2::config::{Algorithm, ClusterConfig};
+use assignment_2::token_ring::TokenRingNode;
+use assignment_2::Params;
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (mut cfg, rest) =
+        ClusterConfig::from_args(Algorithm::TokenRing, &args).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2);
+        });
+    let id: u128 = match &rest[..] {
+        [x] => x.parse().expect("Node id must be a number"),
+        _ => {
+            eprintln!("usage: ring <id> [--timeout <ms>] [--config <file>] [flags]");
+            process::exit(2);
+        }
+    };
+    if id as usize >= cfg.n {
+        eprintln!("No node {}: n is {}", id, cfg.n);
+        process::exit(2);
+    }
+    let rx = cfg.bind(id as usize).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let params = Params::from_config(&cfg, id as usize);
+    let dir = cfg.log_dir();
+    fs::create_dir_all(&dir).unwrap();
+    let mut node = TokenRingNode::new(id, cfg.ips()).with_log_dir(&dir);
+    if let Some(t) = params.timeout {
+        node = node.with_detector(t);
+    }
+    if let Some(path) = &cfg.chaos {
+        node = node.with_chaos(path, &cfg.addrs());
+    }
+    if let Some(router) = cfg.router(id as usize) {
+        node = node.with_router(router);
+    }
+    let node = Arc::new(node);
+    node.run(rx, params);
+    let mc = node.mc.load(std::sync::atomic::Ordering::SeqCst);
+    let elap = node.init.elapsed().as_millis();
+    let mut f = File::create(format!("{}/out_{}.log", dir, id)).unwrap();
+    write!(f, "{} {}", mc, elap).unwrap();
+
+    // Late copies from `Chaos`, and whatever we relay, still have to get out
+    thread::sleep(Duration::from_secs(1));
+}
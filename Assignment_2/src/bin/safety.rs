@@ -0,0 +1,56 @@
+use std::{env, fs, process};
+
+/// Checks the CS intervals recorded by nodes run with `--chaos` for overlaps.
+fn main() {
+    let dir = env::args().nth(1).unwrap_or("log/maekawa".to_string());
+
+    let mut cs = vec![];
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        if !name.starts_with("cs_") {
+            continue;
+        }
+        for (i, l) in fs::read_to_string(&path).unwrap().lines().enumerate() {
+            let Some((cs_in, cs_out)) = parse(l) else {
+                eprintln!("{}:{}: expected two timestamps, got {:?}", name, i + 1, l);
+                process::exit(2);
+            };
+            cs.push((cs_in, cs_out, name.clone()));
+        }
+    }
+    cs.sort();
+
+    // Every section is checked against the one that ends last so far, which
+    // covers sections it overlaps besides its neighbour
+    let mut bad = 0;
+    let mut last: Option<(u128, &String)> = None;
+    for (b_in, b_out, b) in cs.iter() {
+        if let Some((a_out, a)) = last {
+            if *b_in < a_out {
+                println!(
+                    "Mutual exclusion violated: {} and {} overlap by {}us",
+                    a,
+                    b,
+                    a_out.min(*b_out) - b_in
+                );
+                bad += 1;
+            }
+        }
+        if last.is_none_or(|(a_out, _)| *b_out > a_out) {
+            last = Some((*b_out, b));
+        }
+    }
+    println!("{} critical sections, {} violations", cs.len(), bad);
+    if bad > 0 {
+        process::exit(1);
+    }
+}
+
+/// The entry and exit time on a line of a `cs_*` file
+fn parse(l: &str) -> Option<(u128, u128)> {
+    match l.split_whitespace().collect::<Vec<_>>()[..] {
+        [a, b] => Some((a.parse().ok()?, b.parse().ok()?)),
+        _ => None,
+    }
+}
@@ -0,0 +1,53 @@
+use std::{env, path::Path};
+
+use assignment_2::analysis::{RunStats, Stats};
+
+/// Message complexity, response time, synchronization delay and throughput of
+/// a run, from its logs. Also written to `summary.json` in the log directory.
+fn main() {
+    let dir = env::args().nth(1).unwrap_or("log/maekawa".to_string());
+    let stats = RunStats::new(&dir);
+
+    println!(
+        "{:<20} {:>6} {:>10} {:>10} {:>10} {:>10}",
+        "node", "count", "msgs/cs", "cs/s", "resp mean", "resp p99"
+    );
+    for x in stats.nodes.iter() {
+        println!(
+            "{:<20} {:>6} {:>10.2} {:>10.2} {:>10.0} {:>10}",
+            x.name, x.sections, x.messages, x.throughput, x.response.mean, x.response.p99
+        );
+    }
+    println!(
+        "{:<20} {:>6} {:>10.2} {:>10.2} {:>10.0} {:>10}",
+        "all",
+        stats.sections,
+        stats.messages,
+        stats.throughput,
+        stats.response.mean,
+        stats.response.p99
+    );
+    println!("{} messages sent in total", stats.sent);
+    if let Some(clock) = &stats.clock {
+        println!(
+            "Clocks: {} bytes over {} messages, {} as vector clocks ({:.1}% saved)",
+            clock.bytes,
+            clock.messages,
+            clock.vector_bytes,
+            clock.saved() * 100.0
+        );
+    }
+    show("Response time", &stats.response);
+    show("Synchronization delay", &stats.sync_delay);
+
+    let path = Path::new(&dir).join("summary.json");
+    stats.write(&path);
+    println!("Written to {}", path.display());
+}
+
+fn show(what: &str, s: &Stats) {
+    println!(
+        "{} (us): mean {:.0}, min {}, p50 {}, p90 {}, p99 {}, max {} over {}",
+        what, s.mean, s.min, s.p50, s.p90, s.p99, s.max, s.count
+    );
+}
@@ -0,0 +1,235 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::Write,
+    process,
+    str::FromStr,
+    time::Duration,
+};
+
+use assignment_2::{
+    analysis::RunStats,
+    config::Algorithm,
+    experiment::{Config, Summary},
+};
+
+const USAGE: &str = "usage: sweep [--alg rc,maekawa,central,naimi,ring] [--n 4,9] \
+                     [--k 5..25:5] [--out 5,10..20:5] [--in 2.5..10:2.5] [--reps 3] \
+                     [--deadline 120] [-o results.csv]";
+
+type Metric = fn(&RunStats) -> f64;
+
+/// Metrics written for every configuration
+const METRICS: [(&str, Metric); 7] = [
+    ("messages_per_cs", |x| x.messages),
+    ("messages", |x| x.sent as f64),
+    ("response_us", |x| x.response.mean),
+    ("response_p99_us", |x| x.response.p99 as f64),
+    ("sync_delay_us", |x| x.sync_delay.mean),
+    ("throughput", |x| x.throughput),
+    ("elapsed_ms", |x| x.elapsed),
+];
+
+/// Runs every combination of the given parameters a number of times and
+/// writes the mean, standard deviation and 95% confidence interval of each
+/// metric as CSV, one row per configuration and metric.
+fn main() {
+    let mut algs = vec![
+        Algorithm::Rc,
+        Algorithm::Maekawa,
+        Algorithm::Central,
+        Algorithm::NaimiTrehel,
+        Algorithm::TokenRing,
+    ];
+    let mut ns = vec![4];
+    let mut ks = vec![5];
+    let mut outs = vec![5.0];
+    let mut ins = vec![5.0];
+    let mut reps = 3;
+    let mut deadline = 120;
+    let mut path = "results.csv".to_string();
+
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let Some(val) = args.next() else {
+            usage(&format!("Missing value for {}", flag));
+        };
+        let res = match flag.as_str() {
+            "--alg" => list(&val).map(|x| algs = x),
+            "--n" => range(&val).map(|x| ns = x),
+            "--k" => range(&val).map(|x| ks = x),
+            "--out" => frange(&val).map(|x| outs = x),
+            "--in" => frange(&val).map(|x| ins = x),
+            "--reps" => value(&val).map(|x| reps = x),
+            "--deadline" => value(&val).map(|x| deadline = x),
+            "-o" => {
+                path = val;
+                Ok(())
+            }
+            x => Err(format!("Unknown option: {}", x)),
+        };
+        if let Err(e) = res {
+            usage(&e);
+        }
+    }
+
+    // The node binaries are built next to this one
+    let exe = env::current_exe().unwrap();
+    let bins = exe.parent().unwrap();
+    for alg in algs.iter() {
+        if !bins.join(alg.bin()).exists() {
+            eprintln!(
+                "{} not found in {}, build it first",
+                alg.bin(),
+                bins.display()
+            );
+            process::exit(2);
+        }
+    }
+
+    let mut csv = File::create(&path).unwrap();
+    writeln!(
+        csv,
+        "alg,n,k,out_l,in_l,runs,failed,metric,mean,stddev,ci95"
+    )
+    .unwrap();
+
+    for &alg in algs.iter() {
+        for &n in ns.iter() {
+            let q = (n as f64).sqrt() as usize;
+            if alg == Algorithm::Maekawa && q * q != n {
+                println!("Skipping maekawa with n = {}: not a square", n);
+                continue;
+            }
+            for &k in ks.iter() {
+                for &out_l in outs.iter() {
+                    for &in_l in ins.iter() {
+                        let cfg = Config {
+                            alg,
+                            n,
+                            k,
+                            out_l,
+                            in_l,
+                        };
+                        let mut runs = vec![];
+                        for rep in 0..reps {
+                            let dir = env::temp_dir().join(format!(
+                                "sweep_{}_{}_n{}_k{}_out{}_in{}_{}",
+                                process::id(),
+                                alg,
+                                n,
+                                k,
+                                out_l,
+                                in_l,
+                                rep
+                            ));
+                            let res = cfg.run(&dir, bins, Duration::from_secs(deadline));
+                            println!(
+                                "{:?} run {}: {}",
+                                cfg,
+                                rep,
+                                if res.is_some() { "done" } else { "failed" }
+                            );
+                            match res {
+                                Some(stats) => {
+                                    runs.push(stats);
+                                    fs::remove_dir_all(&dir).unwrap();
+                                }
+                                None => println!("Logs kept in {}", dir.display()),
+                            }
+                        }
+
+                        for (metric, get) in METRICS.iter() {
+                            let s = Summary::new(&runs.iter().map(get).collect::<Vec<_>>());
+                            writeln!(
+                                csv,
+                                "{},{},{},{},{},{},{},{},{},{},{}",
+                                alg,
+                                n,
+                                k,
+                                out_l,
+                                in_l,
+                                runs.len(),
+                                reps - runs.len(),
+                                metric,
+                                field(s.mean),
+                                field(s.stddev),
+                                field(s.ci95)
+                            )
+                            .unwrap();
+                        }
+                        csv.flush().unwrap();
+                    }
+                }
+            }
+        }
+    }
+    println!("Written to {}", path);
+}
+
+/// A CSV field, empty if there is nothing to report
+fn field(x: f64) -> String {
+    if x.is_nan() {
+        String::new()
+    } else {
+        x.to_string()
+    }
+}
+
+/// Prints what was wrong and how to call us, then exits
+fn usage(e: &str) -> ! {
+    eprintln!("{}", e);
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn value<T: FromStr>(x: &str) -> Result<T, String> {
+    x.parse().map_err(|_| format!("Bad value: {}", x))
+}
+
+/// Comma separated values
+fn list<T: FromStr>(x: &str) -> Result<Vec<T>, String> {
+    x.split(',').map(value).collect()
+}
+
+/// Comma separated values or inclusive ranges such as `5..25:5`. The step
+/// defaults to 1.
+fn range(x: &str) -> Result<Vec<usize>, String> {
+    let mut out = vec![];
+    for x in x.split(',') {
+        let Some((lo, hi)) = x.split_once("..") else {
+            out.push(value(x)?);
+            continue;
+        };
+        let (hi, step) = hi.split_once(':').unwrap_or((hi, "1"));
+        let (lo, hi, step): (usize, usize, usize) = (value(lo)?, value(hi)?, value(step)?);
+        if step == 0 || lo > hi {
+            return Err(format!("Bad range: {}", x));
+        }
+        out.extend((lo..=hi).step_by(step));
+    }
+    Ok(out)
+}
+
+/// Like `range`, for fractional values such as `2.5..10:2.5`
+fn frange(x: &str) -> Result<Vec<f64>, String> {
+    let mut out = vec![];
+    for x in x.split(',') {
+        let Some((lo, hi)) = x.split_once("..") else {
+            out.push(value(x)?);
+            continue;
+        };
+        let (hi, step) = hi.split_once(':').unwrap_or((hi, "1"));
+        let (lo, hi, step): (f64, f64, f64) = (value(lo)?, value(hi)?, value(step)?);
+        if !(step > 0.0 && lo <= hi && hi.is_finite()) {
+            return Err(format!("Bad range: {}", x));
+        }
+        // Counted rather than summed, so steps like 0.1 still end on `hi`
+        let count = ((hi - lo) / step + 1e-9).floor() as usize;
+        out.extend((0..=count).map(|i| lo + i as f64 * step));
+    }
+    if out.iter().any(|x| !x.is_finite() || *x < 0.0) {
+        return Err(format!("Bad value in {}", x));
+    }
+    Ok(out)
+}
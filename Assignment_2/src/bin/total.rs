@@ -0,0 +1,66 @@
+use std::{env, process};
+
+use either::Either::{Left, Right};
+
+use assignment_2::{
+    analysis::{deliveries, divergence, read_logs},
+    utils::Pid,
+};
+
+/// Checks the `node_*.log` files of a total-order broadcast run: every node
+/// must have delivered the same messages in the same order.
+fn main() {
+    let dir = env::args().nth(1).unwrap_or("log/abcast".to_string());
+
+    let logs: Vec<_> = read_logs(&dir)
+        .into_iter()
+        .map(|(name, log)| (name, deliveries(&log)))
+        .collect();
+    let Some((first, want)) = logs.first() else {
+        println!("No logs in {}", dir);
+        process::exit(1);
+    };
+
+    let mut bad = 0;
+    for (name, got) in logs.iter().skip(1) {
+        let Some(i) = divergence(want, got) else {
+            continue;
+        };
+        bad += 1;
+        match (want.get(i), got.get(i)) {
+            (Some(a), Some(b)) => println!(
+                "{}: delivery {} is {} from {}, but {} delivered {} from {}",
+                name,
+                i,
+                b.1,
+                name_of(b.0),
+                first,
+                a.1,
+                name_of(a.0)
+            ),
+            _ => println!(
+                "{}: delivered {} messages, but {} delivered {}",
+                name,
+                got.len(),
+                first,
+                want.len()
+            ),
+        }
+    }
+    println!(
+        "{} nodes, {} deliveries each, {} out of agreement",
+        logs.len(),
+        want.len(),
+        bad
+    );
+    if bad > 0 {
+        process::exit(1);
+    }
+}
+
+fn name_of(pid: Pid) -> String {
+    match pid {
+        Left(x) => format!("{:?}", x),
+        Right(x) => format!("{}", x),
+    }
+}
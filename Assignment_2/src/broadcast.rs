@@ -0,0 +1,661 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Read,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    chaos::Chaos,
+    clocks::VectorClock,
+    conn::Conn,
+    topology::Router,
+    utils::{get_msgs, Message, MessageType},
+};
+
+/// Birman–Schiper–Stephenson causal delivery.
+///
+/// Entry `j` of a stamp counts the broadcasts of node `j` the sender had
+/// delivered, its own included. A message from `j` is delivered once it is
+/// the next one from `j` and everything it depends on from the others has
+/// been delivered; until then it is held back. Duplicates are dropped.
+#[derive(Debug, Clone)]
+pub struct CausalOrder<T> {
+    me: usize,
+    delivered: VectorClock,
+    held: Vec<(usize, VectorClock, T)>,
+}
+
+impl<T> CausalOrder<T> {
+    pub fn new(me: usize) -> Self {
+        Self {
+            me,
+            delivered: VectorClock::default(),
+            held: vec![],
+        }
+    }
+
+    /// Stamp of our next broadcast, which we deliver at once
+    pub fn stamp(&mut self) -> VectorClock {
+        self.delivered.tick(self.me);
+        self.delivered.clone()
+    }
+
+    fn ready(&self, from: usize, stamp: &VectorClock) -> bool {
+        stamp.get(from) == self.delivered.get(from) + 1
+            && (0..stamp.0.len()).all(|k| k == from || stamp.get(k) <= self.delivered.get(k))
+    }
+
+    /// Takes in a broadcast of node `from`. Returns the messages it lets us
+    /// deliver, in delivery order.
+    pub fn receive(
+        &mut self,
+        from: usize,
+        stamp: VectorClock,
+        x: T,
+    ) -> Vec<(usize, VectorClock, T)> {
+        let copy =
+            |(j, s, _): &(usize, VectorClock, T)| *j == from && s.get(from) == stamp.get(from);
+        if stamp.get(from) <= self.delivered.get(from) || self.held.iter().any(copy) {
+            return vec![];
+        }
+        self.held.push((from, stamp, x));
+        let mut out = vec![];
+        while let Some(i) = self.held.iter().position(|(j, s, _)| self.ready(*j, s)) {
+            let (j, s, x) = self.held.swap_remove(i);
+            self.delivered.tick(j);
+            out.push((j, s, x));
+        }
+        out
+    }
+
+    /// Messages received but not yet deliverable
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn delivered(&self) -> &VectorClock {
+        &self.delivered
+    }
+}
+
+/// A broadcast as delivered.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub from: usize,
+    pub stamp: VectorClock,
+    pub value: u128,
+    /// Whether it had to wait for messages it depends on
+    pub held: bool,
+}
+
+/// Where a message goes in the total order: a Lamport time, and the node
+/// that proposed it to break ties.
+pub type Priority = (u64, usize);
+
+/// ISIS total-order delivery among `n` members.
+///
+/// A message is named by its sender and the sender's count of earlier
+/// broadcasts. Every member queues it and proposes a priority above any it
+/// has seen, from its Lamport clock `seq`; the sender picks the highest
+/// proposal and announces it. A member delivers the message at the head of
+/// its queue once its priority is agreed on, as no message still waiting
+/// for one can end up ahead of it. Channels need not be FIFO, and
+/// duplicates are dropped, but every message must get through.
+#[derive(Debug, Clone)]
+pub struct TotalOrder<T> {
+    me: usize,
+    n: usize,
+    seq: u64,
+    sent: u64,
+    queue: BTreeMap<(Priority, usize, u64), (T, bool)>, // value, and whether agreed on
+    priority: HashMap<(usize, u64), Priority>,          // of each message queued
+    proposals: HashMap<u64, BTreeMap<usize, Priority>>, // for our own messages
+    delivered: HashSet<(usize, u64)>,
+}
+
+impl<T> TotalOrder<T> {
+    pub fn new(me: usize, n: usize) -> Self {
+        Self {
+            me,
+            n,
+            seq: 0,
+            sent: 0,
+            queue: BTreeMap::new(),
+            priority: HashMap::new(),
+            proposals: HashMap::new(),
+            delivered: HashSet::new(),
+        }
+    }
+
+    /// Queues a broadcast of ours. Returns its number and our own proposal,
+    /// which goes to `propose` like the others'.
+    pub fn send(&mut self, x: T) -> (u64, Priority) {
+        let no = self.sent;
+        self.sent += 1;
+        self.proposals.insert(no, BTreeMap::new());
+        (no, self.receive(self.me, no, x).unwrap())
+    }
+
+    /// Queues message `no` of node `from`. Returns the priority to propose
+    /// to it, or `None` for a duplicate.
+    pub fn receive(&mut self, from: usize, no: u64, x: T) -> Option<Priority> {
+        if self.delivered.contains(&(from, no)) || self.priority.contains_key(&(from, no)) {
+            return None;
+        }
+        self.seq += 1;
+        let p = (self.seq, self.me);
+        self.queue.insert((p, from, no), (x, false));
+        self.priority.insert((from, no), p);
+        Some(p)
+    }
+
+    /// Takes in the priority `by` proposed for our message `no`. Returns the
+    /// agreed priority once every member has proposed one.
+    pub fn propose(&mut self, no: u64, by: usize, p: Priority) -> Option<Priority> {
+        let got = self.proposals.get_mut(&no)?;
+        got.insert(by, p);
+        if got.len() < self.n {
+            return None;
+        }
+        self.proposals.remove(&no).unwrap().into_values().max()
+    }
+
+    /// Takes in the agreed priority of message `no` of node `from`. Returns
+    /// the messages it lets us deliver, in delivery order.
+    pub fn agree(&mut self, from: usize, no: u64, p: Priority) -> Vec<(usize, Priority, T)> {
+        self.seq = self.seq.max(p.0);
+        // A duplicate finds it agreed on already, or delivered
+        if let Some(&old) = self.priority.get(&(from, no)) {
+            if !self.queue[&(old, from, no)].1 {
+                let (x, _) = self.queue.remove(&(old, from, no)).unwrap();
+                self.queue.insert((p, from, no), (x, true));
+                self.priority.insert((from, no), p);
+            }
+        }
+        let mut out = vec![];
+        while let Some(entry) = self.queue.first_entry() {
+            if !entry.get().1 {
+                break;
+            }
+            let ((p, from, no), (x, _)) = entry.remove_entry();
+            self.priority.remove(&(from, no));
+            self.delivered.insert((from, no));
+            out.push((from, p, x));
+        }
+        out
+    }
+
+    /// Messages queued but not yet delivered
+    pub fn held(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+/// A total-order broadcast as delivered.
+#[derive(Debug, Clone)]
+pub struct Ordered {
+    pub from: usize,
+    pub priority: Priority,
+    pub value: u128,
+}
+
+/// Connections among a fixed group of members, numbered by their place in
+/// `addrs`: a `Conn` to every other member, optionally through `Chaos` and a
+/// `Router`, and a listener for theirs. Members are assumed not to crash:
+/// messages to one that is gone stay queued.
+pub(crate) struct Group {
+    me: usize,
+    addrs: Vec<SocketAddr>,
+    conns: Mutex<Vec<(usize, Conn)>>,
+    pub(crate) chaos: Option<Arc<Chaos>>,
+    pub(crate) router: Option<Arc<Router>>,
+}
+
+pub(crate) type Handler = Arc<dyn Fn(Message) + Send + Sync>;
+
+impl Group {
+    pub(crate) fn new(me: usize, addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            me,
+            addrs,
+            conns: Mutex::new(vec![]),
+            chaos: None,
+            router: None,
+        }
+    }
+
+    /// Hands every message read on `rx` to `handler`, then connects to the
+    /// others, waiting until they are all up. Replies sent meanwhile wait
+    /// for the connections.
+    pub(crate) fn start(&self, rx: TcpListener, handler: Handler) {
+        let mut conns = self.conns.lock().unwrap();
+        let router = self.router.clone();
+        thread::spawn(move || accept_thread(rx, router, handler));
+        *conns = (0..self.addrs.len())
+            .filter(|&i| i != self.me)
+            .map(|i| {
+                let conn = Conn::new(self.addrs[i], self.router.clone());
+                (i, conn.with_chaos(self.chaos.clone()))
+            })
+            .collect();
+    }
+
+    pub(crate) fn send(&self, to: usize, msg: Message) {
+        let mut conns = self.conns.lock().unwrap();
+        if let Some((_, conn)) = conns.iter_mut().find(|(i, _)| *i == to) {
+            conn.send(msg);
+        }
+    }
+
+    pub(crate) fn send_all(&self, msg: Message) {
+        for (_, conn) in self.conns.lock().unwrap().iter_mut() {
+            conn.send(msg.clone());
+        }
+    }
+}
+
+fn accept_thread(rx: TcpListener, router: Option<Arc<Router>>, handler: Handler) {
+    for stream in rx.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let stream = match &router {
+            Some(router) => router.accept(stream),
+            None => Some(stream),
+        };
+        if let Some(stream) = stream {
+            let handler = handler.clone();
+            thread::spawn(move || read_thread(stream, handler));
+        }
+    }
+}
+
+fn read_thread(mut stream: TcpStream, handler: Handler) {
+    let mut pending = vec![];
+    let mut buf = [0; 1024];
+    while let Ok(n) = stream.read(&mut buf) {
+        if n == 0 {
+            return;
+        }
+        for mut msg in get_msgs(&mut pending, &buf[..n]) {
+            msg.flip();
+            handler(msg);
+        }
+    }
+}
+
+/// Causally ordered broadcast of `u128` values among a fixed `Group`, over
+/// the same transport as the nodes.
+pub struct CausalBroadcast {
+    me: usize,
+    group: Group,
+    order: Mutex<CausalOrder<u128>>,
+    tx: Sender<Delivery>,
+    rx: Mutex<Receiver<Delivery>>,
+}
+
+impl CausalBroadcast {
+    /// Member `me` of the group at `addrs`. Nothing is sent or read until
+    /// `start`.
+    pub fn new(me: usize, addrs: Vec<SocketAddr>) -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self {
+            me,
+            group: Group::new(me, addrs),
+            order: Mutex::new(CausalOrder::new(me)),
+            tx,
+            rx: Mutex::new(rx),
+        }
+    }
+
+    pub fn with_chaos(mut self, chaos: Arc<Chaos>) -> Self {
+        self.group.chaos = Some(chaos);
+        self
+    }
+
+    pub fn with_router(mut self, router: Router) -> Self {
+        self.group.router = Some(Arc::new(router));
+        self
+    }
+
+    /// Accepts the other members' connections on `rx`, then connects to each
+    /// of them, waiting until they are all up.
+    pub fn start(self: &Arc<Self>, rx: TcpListener) {
+        let me = self.clone();
+        self.group.start(
+            rx,
+            Arc::new(move |msg: Message| {
+                if msg.typ == MessageType::Broadcast {
+                    let from = msg.id.expect_right("") as usize;
+                    me.receive(from, VectorClock(msg.clock), msg.ts);
+                }
+            }),
+        );
+    }
+
+    fn receive(&self, from: usize, stamp: VectorClock, value: u128) {
+        // Handed over under the lock, so a broadcast of ours that depends on
+        // these cannot overtake them
+        let mut order = self.order.lock().unwrap();
+        let ready = order.receive(from, stamp, value);
+        // Nothing held was ready before, so only the first can be this one
+        for (i, (from, stamp, value)) in ready.into_iter().enumerate() {
+            let _ = self.tx.send(Delivery {
+                from,
+                stamp,
+                value,
+                held: i > 0,
+            });
+        }
+    }
+
+    /// Sends `value` to every member, and delivers it here at once. Returns
+    /// its stamp.
+    pub fn broadcast(&self, value: u128) -> VectorClock {
+        let stamp = {
+            let mut order = self.order.lock().unwrap();
+            let stamp = order.stamp();
+            let _ = self.tx.send(Delivery {
+                from: self.me,
+                stamp: stamp.clone(),
+                value,
+                held: false,
+            });
+            stamp
+        };
+        let msg = Message::new_rc(self.me as u128, MessageType::Broadcast, value)
+            .with_clock(stamp.0.clone());
+        self.group.send_all(msg);
+        stamp
+    }
+
+    /// The next delivery, waiting up to `timeout` for it
+    pub fn deliver(&self, timeout: Duration) -> Option<Delivery> {
+        self.rx.lock().unwrap().recv_timeout(timeout).ok()
+    }
+
+    /// Messages received but held back
+    pub fn held(&self) -> usize {
+        self.order.lock().unwrap().held()
+    }
+}
+
+/// Totally ordered broadcast of `u128` values among a fixed `Group`: every
+/// member delivers the same values in the same order, its own included.
+pub struct TotalBroadcast {
+    me: usize,
+    group: Group,
+    order: Mutex<TotalOrder<u128>>,
+    tx: Sender<Ordered>,
+    rx: Mutex<Receiver<Ordered>>,
+}
+
+impl TotalBroadcast {
+    /// Member `me` of the group at `addrs`. Nothing is sent or read until
+    /// `start`.
+    pub fn new(me: usize, addrs: Vec<SocketAddr>) -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self {
+            me,
+            order: Mutex::new(TotalOrder::new(me, addrs.len())),
+            group: Group::new(me, addrs),
+            tx,
+            rx: Mutex::new(rx),
+        }
+    }
+
+    pub fn with_chaos(mut self, chaos: Arc<Chaos>) -> Self {
+        self.group.chaos = Some(chaos);
+        self
+    }
+
+    pub fn with_router(mut self, router: Router) -> Self {
+        self.group.router = Some(Arc::new(router));
+        self
+    }
+
+    /// Accepts the other members' connections on `rx`, then connects to each
+    /// of them, waiting until they are all up.
+    pub fn start(self: &Arc<Self>, rx: TcpListener) {
+        let me = self.clone();
+        self.group
+            .start(rx, Arc::new(move |msg: Message| me.handle(msg)));
+    }
+
+    fn handle(&self, msg: Message) {
+        let id = msg.id.expect_right("") as usize;
+        let no = msg.clock.first().copied().unwrap_or_default();
+        match msg.typ {
+            MessageType::Multicast => {
+                let p = self.order.lock().unwrap().receive(id, no, msg.ts);
+                if let Some((seq, _)) = p {
+                    let reply = Message::new_rc(self.me as u128, MessageType::Propose, seq as u128)
+                        .with_clock(vec![no]);
+                    self.group.send(id, reply);
+                }
+            }
+            MessageType::Propose => self.propose(no, (msg.ts as u64, id)),
+            MessageType::Agree => {
+                let p = (msg.ts as u64, msg.clock[1] as usize);
+                self.agree(id, no, p);
+            }
+            _ => {}
+        }
+    }
+
+    fn propose(&self, no: u64, p: Priority) {
+        let Some(p) = self.order.lock().unwrap().propose(no, p.1, p) else {
+            return;
+        };
+        let msg = Message::new_rc(self.me as u128, MessageType::Agree, p.0 as u128)
+            .with_clock(vec![no, p.1 as u64]);
+        self.group.send_all(msg);
+        self.agree(self.me, no, p);
+    }
+
+    fn agree(&self, from: usize, no: u64, p: Priority) {
+        // Handed over under the lock, so deliveries stay in order
+        let mut order = self.order.lock().unwrap();
+        for (from, priority, value) in order.agree(from, no, p) {
+            let _ = self.tx.send(Ordered {
+                from,
+                priority,
+                value,
+            });
+        }
+    }
+
+    /// Sends `value` to every member. It is delivered here too, in its place
+    /// in the order. Returns its number among our broadcasts.
+    pub fn broadcast(&self, value: u128) -> u64 {
+        let (no, p) = self.order.lock().unwrap().send(value);
+        let msg =
+            Message::new_rc(self.me as u128, MessageType::Multicast, value).with_clock(vec![no]);
+        self.group.send_all(msg);
+        self.propose(no, p);
+        no
+    }
+
+    /// The next delivery, waiting up to `timeout` for it
+    pub fn deliver(&self, timeout: Duration) -> Option<Ordered> {
+        self.rx.lock().unwrap().recv_timeout(timeout).ok()
+    }
+
+    /// Messages received but not yet delivered
+    pub fn held(&self) -> usize {
+        self.order.lock().unwrap().held()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vc(x: &[u64]) -> VectorClock {
+        VectorClock(x.to_vec())
+    }
+
+    fn senders<T>(out: &[(usize, VectorClock, T)]) -> Vec<usize> {
+        out.iter().map(|x| x.0).collect()
+    }
+
+    #[test]
+    fn causal_holds_back_until_dependencies_arrive() {
+        let mut a = CausalOrder::<&str>::new(0);
+        let mut b = CausalOrder::new(1);
+        let mut c = CausalOrder::new(2);
+
+        // Node 1 delivers node 0's broadcast, then broadcasts its own
+        let m0 = a.stamp();
+        assert_eq!(senders(&b.receive(0, m0.clone(), "m0")), [0]);
+        let m1 = b.stamp();
+        assert_eq!(m1, vc(&[1, 1]));
+
+        // Node 2 hears from node 1 first, and must wait for node 0
+        assert!(c.receive(1, m1.clone(), "m1").is_empty());
+        assert_eq!(c.held(), 1);
+        let out = c.receive(0, m0.clone(), "m0");
+        assert_eq!(out.iter().map(|x| x.2).collect::<Vec<_>>(), ["m0", "m1"]);
+        assert_eq!(c.held(), 0);
+        assert_eq!(c.delivered(), &vc(&[1, 1]));
+
+        // Two from the same sender, in the wrong order
+        let (n1, n2) = (a.stamp(), a.stamp());
+        assert!(c.receive(0, n2, "n2").is_empty());
+        assert_eq!(senders(&c.receive(0, n1, "n1")), [0, 0]);
+    }
+
+    #[test]
+    fn causal_drops_duplicates() {
+        let mut a = CausalOrder::<()>::new(0);
+        let mut b = CausalOrder::new(1);
+        let mut c = CausalOrder::new(2);
+        let m0 = a.stamp();
+        b.receive(0, m0.clone(), ());
+        let m1 = b.stamp();
+
+        // A copy of a message still held back
+        assert!(c.receive(1, m1.clone(), ()).is_empty());
+        assert!(c.receive(1, m1.clone(), ()).is_empty());
+        assert_eq!(c.held(), 1);
+        assert_eq!(c.receive(0, m0.clone(), ()).len(), 2);
+
+        // Copies of delivered messages
+        assert!(c.receive(0, m0, ()).is_empty());
+        assert!(c.receive(1, m1, ()).is_empty());
+        assert_eq!(c.held(), 0);
+    }
+
+    #[test]
+    fn total_waits_for_agreement_on_the_head() {
+        let mut t = TotalOrder::new(2, 3);
+        assert_eq!(t.receive(0, 0, "a"), Some((1, 2)));
+        assert_eq!(t.receive(1, 0, "b"), Some((2, 2)));
+        assert_eq!(t.receive(1, 0, "b"), None);
+
+        // b is agreed on first, but a is still ahead of it and undecided
+        assert!(t.agree(1, 0, (3, 1)).is_empty());
+        assert_eq!(t.held(), 2);
+        // a ends up behind b after all
+        let out = t.agree(0, 0, (4, 0));
+        assert_eq!(out.iter().map(|x| x.2).collect::<Vec<_>>(), ["b", "a"]);
+        assert_eq!(t.held(), 0);
+
+        // Late copies change nothing
+        assert!(t.agree(0, 0, (4, 0)).is_empty());
+        assert_eq!(t.receive(0, 0, "a"), None);
+        // New proposals go above every agreed priority
+        assert_eq!(t.receive(0, 1, "c"), Some((5, 2)));
+    }
+
+    #[test]
+    fn total_agrees_on_the_highest_proposal() {
+        let mut t = TotalOrder::new(0, 3);
+        let (no, p) = t.send("a");
+        assert_eq!(t.propose(no, 0, p), None);
+        assert_eq!(t.propose(no, 2, (7, 2)), None);
+        assert_eq!(t.propose(no, 1, (3, 1)), Some((7, 2)));
+        // Already decided
+        assert_eq!(t.propose(no, 1, (9, 1)), None);
+        assert_eq!(t.agree(0, no, (7, 2)).len(), 1);
+    }
+
+    /// What is in flight between members of an ISIS group
+    #[derive(Debug, Clone, Copy)]
+    enum Isis {
+        Send(u32),
+        Multicast(usize, u64, u32),
+        Propose(usize, u64, Priority),
+        Agree(usize, u64, Priority),
+    }
+
+    /// Runs broadcasts of `n` members to completion, delivering messages in
+    /// a random order and some of them twice. Returns what each delivered.
+    fn isis(n: usize, sends: &[(usize, u32)], seed: u64) -> Vec<Vec<(usize, u32)>> {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut m: Vec<_> = (0..n).map(|i| TotalOrder::new(i, n)).collect();
+        let mut out = vec![vec![]; n];
+        let mut net: Vec<_> = sends.iter().map(|&(i, x)| (i, Isis::Send(x))).collect();
+        while !net.is_empty() {
+            let (i, msg) = net.swap_remove(rng.gen_range(0..net.len()));
+            if !matches!(msg, Isis::Send(_)) && rng.gen_bool(0.2) {
+                net.push((i, msg));
+            }
+            let (from, no, p) = match msg {
+                Isis::Send(x) => {
+                    let (no, p) = m[i].send(x);
+                    net.extend(
+                        (0..n)
+                            .filter(|&j| j != i)
+                            .map(|j| (j, Isis::Multicast(i, no, x))),
+                    );
+                    (i, no, p)
+                }
+                Isis::Multicast(from, no, x) => {
+                    if let Some(p) = m[i].receive(from, no, x) {
+                        net.push((from, Isis::Propose(i, no, p)));
+                    }
+                    continue;
+                }
+                Isis::Propose(by, no, p) => (by, no, p),
+                Isis::Agree(from, no, p) => {
+                    out[i].extend(m[i].agree(from, no, p).into_iter().map(|x| (x.0, x.2)));
+                    continue;
+                }
+            };
+            // A proposal for one of ours
+            if let Some(p) = m[i].propose(no, from, p) {
+                net.extend(
+                    (0..n)
+                        .filter(|&j| j != i)
+                        .map(|j| (j, Isis::Agree(i, no, p))),
+                );
+                out[i].extend(m[i].agree(i, no, p).into_iter().map(|x| (x.0, x.2)));
+            }
+        }
+        assert!(m.iter().all(|x| x.held() == 0));
+        out
+    }
+
+    #[test]
+    fn total_order_is_the_same_everywhere() {
+        let sends = [(0, 1), (1, 2), (2, 3), (0, 4), (1, 5), (3, 6)];
+        let mut orders = HashSet::new();
+        for seed in 0..200 {
+            let out = isis(4, &sends, seed);
+            assert_eq!(out[0].len(), sends.len());
+            assert!(out.iter().all(|x| *x == out[0]), "seed {}: {:?}", seed, out);
+            orders.insert(out[0].clone());
+        }
+        // The interleavings did differ
+        assert!(orders.len() > 1);
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use either::Either::Right;
+use polling::{Event, Events, Poller};
+use rand::thread_rng;
+
+use crate::{
+    chaos::Chaos,
+    conn::{Conn, ACCEPT},
+    protocol::Coordinator,
+    request::Request,
+    topology::Router,
+    utils::{epoch_micros, get_msgs, Action, LogEntry, Message, MessageType},
+    Params, Region,
+};
+
+/// How often a listener that is not the coordinator checks if it is done.
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Mutual exclusion through one coordinator, for comparison with the
+/// distributed algorithms and as a fallback.
+///
+/// Every node, the coordinator included, asks the coordinator for the CS and
+/// waits for its reply, then tells it when it leaves: three messages per CS.
+/// A request is stamped with the number of requests the node has made, and
+/// the queue goes by that stamp, so nodes that have asked less go first.
+/// Ties go to the higher id, as in Maekawa's queues. The reply and release
+/// carry the stamp of the request they answer, so copies and stragglers are
+/// told apart.
+pub struct CentralNode {
+    id: u128,
+    coordinator: u128,
+    ips: HashMap<u128, SocketAddr>,
+    rx: TcpListener, // Listener
+    pub init: Instant,
+    seq: AtomicU64,
+    pub mc: AtomicU64,
+    cs_mc: AtomicU64, // Sent and read by the requester since the last exit
+    done: AtomicBool, // the requester is done
+    chaos: Option<Arc<Chaos>>,
+    dir: String, // where logs go
+    router: Option<Arc<Router>>,
+}
+
+impl CentralNode {
+    /// A node listening on `rx`, which is already bound. Node 0 is the
+    /// coordinator unless told otherwise.
+    pub fn from_listener(id: u128, ips: HashMap<u128, SocketAddr>, rx: TcpListener) -> Self {
+        Self {
+            id,
+            coordinator: 0,
+            ips,
+            rx,
+            init: Instant::now(),
+            seq: 0.into(),
+            mc: 0.into(),
+            cs_mc: 0.into(),
+            done: false.into(),
+            chaos: None,
+            dir: "log/central".to_string(),
+            router: None,
+        }
+    }
+
+    pub fn with_coordinator(mut self, coordinator: u128) -> Self {
+        self.coordinator = coordinator;
+        self
+    }
+
+    /// Sends every message through a fault-injecting transport scripted by
+    /// `path`, and records CS intervals for the safety check. `ips` lists all
+    /// node addresses in order.
+    pub fn with_chaos(mut self, path: &str, ips: &[SocketAddr]) -> Self {
+        self.chaos = Some(Arc::new(Chaos::new(self.ips[&self.id], path, ips)));
+        self
+    }
+
+    pub fn with_router(mut self, router: Router) -> Self {
+        self.router = Some(Arc::new(router));
+        self
+    }
+
+    pub fn with_log_dir(mut self, dir: &str) -> Self {
+        self.dir = dir.to_string();
+        self
+    }
+
+    fn log(&self, out: &mut Vec<LogEntry>, act: Action) {
+        out.push(LogEntry {
+            pid: Right(self.id),
+            ts: self.init.elapsed().as_micros(),
+            act,
+            clock: None,
+        });
+    }
+
+    /// Answers on an incoming stream. A broken stream is left for the
+    /// poller to notice.
+    fn send(&self, mut stream: &TcpStream, to: u128, typ: MessageType, ts: u128) {
+        self.mc.fetch_add(1, Ordering::SeqCst);
+        let msg = Message::new_rc(self.id, typ, ts);
+        let _ = match &self.chaos {
+            Some(chaos) => chaos.write(stream, self.ips[&to], msg).map(|_| ()),
+            None => stream.write_all(&Vec::from(msg)),
+        };
+    }
+
+    fn send_at(&self, conn: &mut Conn, typ: MessageType, ts: u128) {
+        self.mc.fetch_add(1, Ordering::SeqCst);
+        self.cs_mc.fetch_add(1, Ordering::SeqCst);
+        conn.send(Message::new_rc(self.id, typ, ts));
+    }
+
+    /// Tells a requester it has the CS.
+    fn grant(&self, out: &mut Vec<LogEntry>, streams: &[TcpStream], x: Option<Request>) {
+        if let Some(x) = x {
+            let pid = x.pid.0 as u128;
+            self.log(out, Action::Grant(Right(pid)));
+            self.send(&streams[x.stream], pid, MessageType::Reply, x.ts);
+        }
+    }
+
+    /// The coordinator is done once all `n` requesters are, the others once
+    /// their own is.
+    fn finished(&self, terminated: usize) -> bool {
+        if self.id == self.coordinator {
+            terminated == self.ips.len()
+        } else {
+            self.done.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Accepts connections, relaying those for others. The coordinator
+    /// serves requests until every node has terminated; the others stop
+    /// once their requester is done.
+    fn listen(&self) -> Vec<LogEntry> {
+        let poller = Poller::new().unwrap();
+        unsafe { poller.add(&self.rx, Event::readable(ACCEPT)).unwrap() };
+        let mut streams = vec![];
+        let mut pending = vec![]; // part of a message, per stream
+        let mut queue = Coordinator::default();
+        let mut finished = HashSet::new(); // copies of a terminate count once
+        let mut out = vec![];
+        let mut events = Events::new();
+
+        while !self.finished(finished.len()) {
+            events.clear();
+            poller.wait(&mut events, Some(POLL_TIMEOUT)).unwrap();
+            for ev in events.iter() {
+                if ev.key == ACCEPT {
+                    let accepted = self.rx.accept().ok().and_then(|(x, _)| match &self.router {
+                        Some(router) => router.accept(x),
+                        None => Some(x),
+                    });
+                    if let Some(x) = accepted {
+                        unsafe { poller.add(&x, Event::readable(streams.len())).unwrap() };
+                        pending.push(vec![]);
+                        streams.push(x);
+                    }
+                    poller.modify(&self.rx, Event::readable(ACCEPT)).unwrap();
+                    continue;
+                }
+
+                let mut stream = &streams[ev.key];
+                let mut buf = [0; 128];
+                let msgs = match stream.read(&mut buf) {
+                    Ok(ref b) if *b > 0 => get_msgs(&mut pending[ev.key], &buf[..*b]),
+                    _ => {
+                        poller.delete(stream).unwrap();
+                        continue;
+                    }
+                };
+                poller.modify(stream, Event::readable(ev.key)).unwrap();
+
+                for mut msg in msgs {
+                    msg.flip();
+                    let pid = (msg.id.expect_right("") as u64, 0);
+                    match msg.typ {
+                        MessageType::Request => {
+                            self.log(&mut out, Action::Query(msg.id));
+                            let x = queue.on_request(Request::new(msg.ts, pid, ev.key));
+                            self.grant(&mut out, &streams, x);
+                        }
+                        MessageType::Release => {
+                            self.log(&mut out, Action::Release(msg.id));
+                            let x = queue.on_release(pid, msg.ts);
+                            self.grant(&mut out, &streams, x);
+                        }
+                        MessageType::Terminate => {
+                            self.log(&mut out, Action::Terminate);
+                            finished.insert(pid);
+                        }
+                        _ => {
+                            dbg!(msg);
+                            panic!("Unexpected message")
+                        }
+                    }
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Blocks until the coordinator grants request `ts`. Replies to other
+    /// requests are copies, and are dropped.
+    fn wait_reply(&self, conn: &mut Conn, ts: u128, out: &mut Vec<LogEntry>) {
+        loop {
+            let mut buf = [0; 128];
+            let b = conn.stream().unwrap().read(&mut buf).unwrap();
+            if b == 0 {
+                panic!("The coordinator closed the connection");
+            }
+            for mut msg in get_msgs(conn.inbox(), &buf[..b]) {
+                msg.flip();
+                self.cs_mc.fetch_add(1, Ordering::SeqCst);
+                match msg.typ {
+                    MessageType::Reply if msg.ts == ts => {
+                        self.log(out, Action::Reply(msg.id));
+                        return;
+                    }
+                    MessageType::Reply => {}
+                    _ => {
+                        dbg!(msg);
+                        panic!("Unexpected message")
+                    }
+                }
+            }
+        }
+    }
+
+    fn requester_thread(&self, params: Params) -> Vec<LogEntry> {
+        let mut rng = thread_rng();
+        let mut out = vec![];
+        let addr = self.ips[&self.coordinator];
+        let mut conn = Conn::new(addr, self.router.clone()).with_chaos(self.chaos.clone());
+        println!("Connections established.");
+
+        for i in 0..params.requests() {
+            self.log(&mut out, Action::Internal);
+            params.sleep(&mut rng, Region::Out, i, self.init);
+
+            let ts = self.seq.fetch_add(1, Ordering::SeqCst) as u128;
+            self.send_at(&mut conn, MessageType::Request, ts);
+            self.log(&mut out, Action::Ask(ts));
+            self.wait_reply(&mut conn, ts, &mut out);
+
+            self.log(&mut out, Action::Acquire);
+            if let Some(chaos) = &self.chaos {
+                chaos.enter();
+            }
+            params.sleep(&mut rng, Region::In, i, self.init);
+
+            if let Some(chaos) = &self.chaos {
+                chaos.exit();
+            }
+            // Before the coordinator hears of it, so the exit happens before the next entry
+            self.log(&mut out, Action::Exit);
+            self.send_at(&mut conn, MessageType::Release, ts);
+            self.log(
+                &mut out,
+                Action::Messages(self.cs_mc.swap(0, Ordering::SeqCst)),
+            );
+        }
+
+        self.send_at(&mut conn, MessageType::Terminate, 0);
+        self.done.store(true, Ordering::SeqCst);
+        out
+    }
+
+    fn listener_spawn(self: Arc<Self>) -> JoinHandle<Vec<LogEntry>> {
+        thread::spawn(move || self.listen())
+    }
+
+    fn requester_spawn(self: Arc<Self>, params: Params) -> JoinHandle<Vec<LogEntry>> {
+        thread::spawn(move || self.requester_thread(params))
+    }
+
+    pub fn spawn(self: Arc<Self>, params: Params) {
+        let mut file = File::create(format!("{}/node_{}.log", self.dir, self.id)).unwrap();
+
+        let listener = self.clone().listener_spawn();
+        let node = self.clone().requester_spawn(params);
+
+        let node_log = node.join().unwrap();
+        let listener_log = listener.join().unwrap();
+
+        // Lets logs of different nodes be put on one timeline
+        let start = LogEntry {
+            pid: Right(self.id),
+            ts: 0,
+            act: Action::Start(epoch_micros() - self.init.elapsed().as_micros()),
+            clock: None,
+        };
+
+        let mut log = [vec![start], listener_log, node_log].concat();
+        log.sort_by_key(|x| x.ts);
+        for entry in log.iter() {
+            writeln!(file, "{}", entry).unwrap();
+        }
+
+        if let Some(chaos) = &self.chaos {
+            chaos.dump(&format!("{}/cs_{}.log", self.dir, self.id));
+        }
+        if let Some(router) = &self.router {
+            router.linger(&self.rx);
+        }
+    }
+}
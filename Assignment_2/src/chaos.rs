@@ -0,0 +1,342 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{thread_rng, Rng};
+
+use crate::utils::{epoch_micros, Message, MessageType};
+
+/// What happens to a message matched by a rule.
+#[derive(Debug, Clone, Copy)]
+pub enum Fault {
+    /// Held back for a uniformly random time in the range. Messages on the same
+    /// channel overtake each other when the range is wide enough.
+    Delay(Duration, Duration),
+    /// Written twice.
+    Duplicate,
+    /// Lost with the given probability.
+    Drop(f64),
+    /// Lost, in both directions.
+    Partition,
+}
+
+/// One line of the schedule.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    start: Duration,
+    end: Duration,
+    from: Option<SocketAddr>, // None matches any node
+    to: Option<SocketAddr>,
+    types: Option<Vec<MessageType>>, // None matches any message
+    fault: Fault,
+}
+
+impl Rule {
+    fn matches(&self, at: Duration, from: SocketAddr, to: SocketAddr, typ: MessageType) -> bool {
+        let pair = |a: SocketAddr, b: SocketAddr| {
+            self.from.is_none_or(|x| x == a) && self.to.is_none_or(|x| x == b)
+        };
+        let link = match self.fault {
+            Fault::Partition => pair(from, to) || pair(to, from),
+            _ => pair(from, to),
+        };
+        (self.start..self.end).contains(&at)
+            && link
+            && self.types.as_ref().is_none_or(|x| x.contains(&typ))
+    }
+}
+
+type Key = (SocketAddr, SocketAddr); // local and peer address of a stream
+type Late = (Instant, Vec<u8>); // a delayed message and when it is due
+
+/// Writes on one stream. Whole messages are written under `lock`, so that
+/// delayed ones never land in the middle of another. Delayed messages go to
+/// a thread through `late`, which is there while that thread has any left.
+#[derive(Debug, Default)]
+struct Writer {
+    lock: Arc<Mutex<()>>,
+    late: Option<Sender<Late>>,
+}
+
+/// Writes each message from `rx` once it is due, earliest first. Gives up
+/// its copy of the stream once there is nothing left, or the stream breaks.
+fn write_late(
+    mut stream: TcpStream,
+    key: Key,
+    lock: Arc<Mutex<()>>,
+    writers: Arc<Mutex<HashMap<Key, Writer>>>,
+    rx: Receiver<Late>,
+) {
+    let mut due = BinaryHeap::new();
+    loop {
+        while let Ok(x) = rx.try_recv() {
+            due.push(Reverse(x));
+        }
+        let Some(Reverse((at, _))) = due.peek() else {
+            // Senders only send holding `writers`, so none is lost here
+            let mut writers = writers.lock().unwrap();
+            match rx.try_recv() {
+                Ok(x) => due.push(Reverse(x)),
+                Err(_) => {
+                    writers.get_mut(&key).unwrap().late = None;
+                    return;
+                }
+            }
+            continue;
+        };
+        match rx.recv_timeout(at.saturating_duration_since(Instant::now())) {
+            Ok(x) => due.push(Reverse(x)),
+            Err(_) => {
+                let Reverse((_, buf)) = due.pop().unwrap();
+                let _guard = lock.lock().unwrap();
+                // If the connection breaks in the meantime the message is lost.
+                if stream.write_all(&buf).and_then(|_| stream.flush()).is_err() {
+                    writers.lock().unwrap().get_mut(&key).unwrap().late = None;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Fault-injecting transport.
+///
+/// Every message a node writes goes through `write`, which applies whichever
+/// rules of the schedule are active for that link. Rules are read from the
+/// schedule file, `chaos.txt` by default, one per line:
+///
+/// ```text
+/// <start ms> <end ms> <from> <to> <types> <fault> [args]
+/// ```
+///
+/// `from` and `to` are node indices as in `ips.txt`, `types` is a comma separated
+/// list of message types such as `Inquire,Yield`, and `*` matches anything.
+/// Faults are `delay <min ms> <max ms>`, `reorder <max ms>`, `duplicate`,
+/// `drop [probability]` and `partition`. Lines starting with `#` are ignored.
+///
+/// CS entries and exits are also recorded in wall clock time, so that the
+/// `safety` binary can check mutual exclusion across processes afterwards.
+#[derive(Debug)]
+pub struct Chaos {
+    me: SocketAddr,
+    rules: Vec<Rule>,
+    init: Instant,
+    cs: Mutex<Vec<(u128, u128)>>,
+    writers: Arc<Mutex<HashMap<Key, Writer>>>,
+}
+
+impl Chaos {
+    /// `ips` lists the node addresses in order. The schedule should have been
+    /// checked with `read_rules` already.
+    pub fn new(me: SocketAddr, path: &str, ips: &[SocketAddr]) -> Self {
+        let rules = read_rules(path, ips).unwrap_or_else(|e| panic!("{}", e));
+        Self {
+            me,
+            rules,
+            init: Instant::now(),
+            cs: Mutex::new(vec![]),
+            writers: Arc::default(),
+        }
+    }
+
+    /// Writes a message to `to`, applying every active rule. Returns how many
+    /// copies went out, for termination detection to count.
+    ///
+    /// Dropped messages still report success: the sender cannot tell.
+    pub fn write(&self, mut stream: &TcpStream, to: SocketAddr, msg: Message) -> io::Result<usize> {
+        let typ = msg.typ;
+        let at = self.init.elapsed();
+        let mut copies = 1;
+        let mut delay = Duration::ZERO;
+        for rule in self
+            .rules
+            .iter()
+            .filter(|x| x.matches(at, self.me, to, typ))
+        {
+            match rule.fault {
+                Fault::Partition => return Ok(0),
+                Fault::Drop(p) if thread_rng().gen_bool(p) => return Ok(0),
+                Fault::Drop(_) => {}
+                Fault::Duplicate => copies += 1,
+                Fault::Delay(lo, hi) => delay += thread_rng().gen_range(lo..=hi),
+            }
+        }
+
+        let buf = Vec::from(msg).repeat(copies);
+        let key = (stream.local_addr()?, stream.peer_addr()?);
+        let mut writers = self.writers.lock().unwrap();
+        let writer = writers.entry(key).or_default();
+        if delay.is_zero() {
+            let lock = writer.lock.clone();
+            drop(writers);
+            let _guard = lock.lock().unwrap();
+            return stream
+                .write_all(&buf)
+                .and_then(|_| stream.flush())
+                .map(|_| copies);
+        }
+        let late = (Instant::now() + delay, buf);
+        if writer.late.is_none() {
+            let (tx, rx) = mpsc::channel();
+            let out = stream.try_clone()?;
+            let (lock, all) = (writer.lock.clone(), self.writers.clone());
+            thread::spawn(move || write_late(out, key, lock, all, rx));
+            writer.late = Some(tx);
+        }
+        writer.late.as_ref().unwrap().send(late).unwrap();
+        Ok(copies)
+    }
+
+    /// Whether a message to `to` is lost to a partition or drop rule. This is
+    /// all that applies to heartbeats: they go over UDP, where delays and
+    /// copies change nothing the detector looks at.
+    pub fn lost(&self, to: SocketAddr, typ: MessageType) -> bool {
+        let at = self.init.elapsed();
+        self.rules
+            .iter()
+            .filter(|x| x.matches(at, self.me, to, typ))
+            .any(|rule| match rule.fault {
+                Fault::Partition => true,
+                Fault::Drop(p) => thread_rng().gen_bool(p),
+                _ => false,
+            })
+    }
+
+    pub fn enter(&self) {
+        self.cs.lock().unwrap().push((epoch_micros(), 0));
+    }
+
+    pub fn exit(&self) {
+        self.cs.lock().unwrap().last_mut().unwrap().1 = epoch_micros();
+    }
+
+    /// Appends the recorded CS intervals to `path`, one `<enter> <exit>` pair
+    /// of microseconds since the epoch per line.
+    pub fn dump(&self, path: &str) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        for (a, b) in self.cs.lock().unwrap().iter() {
+            writeln!(file, "{} {}", a, b).unwrap();
+        }
+    }
+}
+
+/// Reads the schedule at `path`, with `ips` listing the node addresses in
+/// order. Errors name the line at fault.
+pub fn read_rules(path: &str, ips: &[SocketAddr]) -> Result<Vec<Rule>, String> {
+    let mut buf = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut buf))
+        .map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut rules = vec![];
+    for (i, l) in buf.lines().enumerate() {
+        let l = l.trim();
+        if l.is_empty() || l.starts_with('#') {
+            continue;
+        }
+        let bad = |what: String| format!("{}:{}: {}: {:?}", path, i + 1, what, l);
+        let mut it = l.split_whitespace();
+        let mut field = |what: &str| it.next().ok_or_else(|| bad(format!("missing {}", what)));
+        let ms = |x: &str| {
+            x.parse()
+                .map(Duration::from_millis)
+                .map_err(|_| bad(format!("bad time {:?}", x)))
+        };
+        let node = |x: &str| match x {
+            "*" => Ok(None),
+            x => x
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| ips.get(i))
+                .map(|x| Some(*x))
+                .ok_or_else(|| bad(format!("no node {:?}", x))),
+        };
+
+        let start = ms(field("start")?)?;
+        let end = ms(field("end")?)?;
+        let from = node(field("from")?)?;
+        let to = node(field("to")?)?;
+        let types = match field("types")? {
+            "*" => None,
+            x => Some(
+                x.split(',')
+                    .map(parse_type)
+                    .collect::<Result<_, _>>()
+                    .map_err(bad)?,
+            ),
+        };
+        let fault = match field("fault")? {
+            "delay" => {
+                let (lo, hi) = (ms(field("min delay")?)?, ms(field("max delay")?)?);
+                if lo > hi {
+                    return Err(bad("empty delay range".to_string()));
+                }
+                Fault::Delay(lo, hi)
+            }
+            "reorder" => Fault::Delay(Duration::ZERO, ms(field("max delay")?)?),
+            "duplicate" => Fault::Duplicate,
+            "drop" => {
+                let p = match it.next() {
+                    Some(x) => x
+                        .parse()
+                        .map_err(|_| bad(format!("bad probability {:?}", x)))?,
+                    None => 1.0,
+                };
+                if !(0.0..=1.0).contains(&p) {
+                    return Err(bad(format!("probability {} is not between 0 and 1", p)));
+                }
+                Fault::Drop(p)
+            }
+            "partition" => Fault::Partition,
+            x => return Err(bad(format!("unknown fault {:?}", x))),
+        };
+        rules.push(Rule {
+            start,
+            end,
+            from,
+            to,
+            types,
+            fault,
+        });
+    }
+    Ok(rules)
+}
+
+fn parse_type(x: &str) -> Result<MessageType, String> {
+    Ok(match x.to_lowercase().as_str() {
+        "request" => MessageType::Request,
+        "reply" => MessageType::Reply,
+        "release" => MessageType::Release,
+        "failed" => MessageType::Failed,
+        "inquire" => MessageType::Inquire,
+        "yield" => MessageType::Yield,
+        "terminate" => MessageType::Terminate,
+        "join" => MessageType::Join,
+        "leave" => MessageType::Leave,
+        "token" => MessageType::Token,
+        "marker" => MessageType::Marker,
+        "broadcast" => MessageType::Broadcast,
+        "multicast" => MessageType::Multicast,
+        "propose" => MessageType::Propose,
+        "agree" => MessageType::Agree,
+        "elect" => MessageType::Elect,
+        "alive" => MessageType::Alive,
+        "leader" => MessageType::Leader,
+        "heartbeat" => MessageType::Heartbeat,
+        x => return Err(format!("unknown message type {:?}", x)),
+    })
+}
@@ -0,0 +1,529 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    net::{SocketAddr, TcpStream},
+    str::FromStr,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Vector timestamp. Nodes are numbered as in the config, and a clock only
+/// grows as long as the highest node it has heard of, so nodes that join
+/// later just add entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct VectorClock(pub Vec<u64>);
+
+impl VectorClock {
+    pub fn get(&self, i: usize) -> u64 {
+        self.0.get(i).copied().unwrap_or(0)
+    }
+
+    /// A local event at node `i`
+    pub fn tick(&mut self, i: usize) {
+        if self.0.len() <= i {
+            self.0.resize(i + 1, 0);
+        }
+        self.0[i] += 1;
+    }
+
+    /// Entry-wise maximum
+    pub fn merge(&mut self, other: &[u64]) {
+        if self.0.len() < other.len() {
+            self.0.resize(other.len(), 0);
+        }
+        for (x, &y) in self.0.iter_mut().zip(other) {
+            *x = (*x).max(y);
+        }
+    }
+
+    /// Whether the event stamped `self` happened before the one stamped
+    /// `other`.
+    pub fn happened_before(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Less)
+    }
+
+    pub fn concurrent(&self, other: &Self) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let n = self.0.len().max(other.0.len());
+        let le = (0..n).all(|i| self.get(i) <= other.get(i));
+        let ge = (0..n).all(|i| self.get(i) >= other.get(i));
+        match (le, ge) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (false, false) => None,
+        }
+    }
+}
+
+impl Display for VectorClock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl FromStr for VectorClock {
+    type Err = String;
+
+    fn from_str(x: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("Bad vector clock: {}", x);
+        let inner = x
+            .strip_prefix('[')
+            .and_then(|x| x.strip_suffix(']'))
+            .ok_or_else(bad)?;
+        if inner.is_empty() {
+            return Ok(Self::default());
+        }
+        inner
+            .split(", ")
+            .map(|x| x.parse().map_err(|_| bad()))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Matrix timestamp: row `j` is what this node knows node `j` knows. Row
+/// `me` is the node's own vector clock.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MatrixClock {
+    me: usize,
+    rows: Vec<VectorClock>,
+}
+
+impl MatrixClock {
+    pub fn new(me: usize) -> Self {
+        Self {
+            me,
+            rows: vec![VectorClock::default(); me + 1],
+        }
+    }
+
+    fn row(&mut self, i: usize) -> &mut VectorClock {
+        if self.rows.len() <= i {
+            self.rows.resize(i + 1, VectorClock::default());
+        }
+        &mut self.rows[i]
+    }
+
+    pub fn tick(&mut self) {
+        let me = self.me;
+        self.row(me).tick(me);
+    }
+
+    pub fn vector(&self) -> &VectorClock {
+        &self.rows[self.me]
+    }
+
+    /// Events of every node that every node is known to have seen
+    pub fn stable(&self) -> VectorClock {
+        let n = self.rows.iter().map(|x| x.0.len()).max().unwrap_or(0);
+        VectorClock(
+            (0..n)
+                .map(|j| self.rows.iter().map(|x| x.get(j)).min().unwrap_or(0))
+                .collect(),
+        )
+    }
+
+    /// Rows padded to a square, flattened row by row
+    pub fn encode(&self) -> Vec<u64> {
+        let n = self
+            .rows
+            .iter()
+            .map(|x| x.0.len())
+            .max()
+            .unwrap_or(0)
+            .max(self.rows.len());
+        (0..n)
+            .flat_map(|i| {
+                let row = self.rows.get(i);
+                (0..n).map(move |j| row.map_or(0, |x| x.get(j)))
+            })
+            .collect()
+    }
+
+    /// Takes in a matrix sent by node `from`.
+    pub fn merge(&mut self, from: usize, flat: &[u64]) {
+        let n = (flat.len() as f64).sqrt() as usize;
+        let me = self.me;
+        for (i, row) in flat.chunks(n.max(1)).enumerate() {
+            self.row(i).merge(row);
+        }
+        if let Some(theirs) = flat.chunks(n.max(1)).nth(from) {
+            self.row(me).merge(theirs);
+        }
+    }
+}
+
+/// Both ends of a TCP connection, which is a FIFO channel
+pub type Channel = (SocketAddr, SocketAddr);
+
+/// Singhal–Kshemkalyani clock: a vector clock that only sends the entries
+/// that changed since the last message on the same channel, as
+/// `(index, value)` pairs.
+///
+/// `lu[j]` is our own entry when entry `j` last changed, and `ls[c]` our own
+/// entry when we last sent on channel `c`. The receiver only ever raises
+/// entries, so this relies on each channel delivering everything in order:
+/// a message lost or reordered by `Chaos` can leave it behind. A message on
+/// a channel we have not sent on carries the whole clock.
+#[derive(Debug, Clone, Default)]
+pub struct SkClock {
+    me: usize,
+    vc: VectorClock,
+    lu: Vec<u64>,
+    ls: HashMap<Channel, u64>,
+}
+
+impl SkClock {
+    pub fn new(me: usize) -> Self {
+        Self {
+            me,
+            ..Default::default()
+        }
+    }
+
+    fn set(&mut self, j: usize, x: u64) {
+        if self.lu.len() <= j {
+            self.lu.resize(j + 1, 0);
+        }
+        if self.vc.0.len() <= j {
+            self.vc.0.resize(j + 1, 0);
+        }
+        self.vc.0[j] = x;
+        self.lu[j] = self.vc.get(self.me);
+    }
+
+    pub fn tick(&mut self) {
+        let me = self.me;
+        self.set(me, self.vc.get(me) + 1);
+    }
+
+    pub fn vector(&self) -> &VectorClock {
+        &self.vc
+    }
+
+    /// Entries to send on `to`, after ticking for the send
+    pub fn send(&mut self, to: Option<Channel>) -> Vec<(usize, u64)> {
+        self.tick();
+        let last = to.and_then(|c| self.ls.get(&c).copied());
+        let out = (0..self.vc.0.len())
+            .filter(|&j| last.is_none_or(|x| self.lu.get(j).is_some_and(|&u| u > x)))
+            .map(|j| (j, self.vc.0[j]))
+            .collect();
+        if let Some(c) = to {
+            self.ls.insert(c, self.vc.get(self.me));
+        }
+        out
+    }
+
+    pub fn receive(&mut self, entries: &[(usize, u64)]) {
+        self.tick();
+        for &(j, x) in entries {
+            if self.vc.get(j) < x {
+                self.set(j, x);
+            }
+        }
+    }
+}
+
+/// Packs `(index, value)` pairs into a message's clock.
+pub fn encode_entries(entries: &[(usize, u64)]) -> Vec<u64> {
+    entries.iter().flat_map(|&(j, x)| [j as u64, x]).collect()
+}
+
+pub fn decode_entries(stamp: &[u64]) -> Vec<(usize, u64)> {
+    stamp.chunks(2).map(|x| (x[0] as usize, x[1])).collect()
+}
+
+/// Causal metadata a node sent, against what plain vector clocks would
+/// have cost.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ClockStats {
+    pub messages: u64,
+    pub bytes: u64,
+    pub vector_bytes: u64,
+}
+
+impl ClockStats {
+    pub fn add(&mut self, other: &Self) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+        self.vector_bytes += other.vector_bytes;
+    }
+
+    /// Fraction of the vector clock bytes saved, negative if more was sent
+    pub fn saved(&self) -> f64 {
+        if self.vector_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - self.bytes as f64 / self.vector_bytes as f64
+    }
+
+    pub fn write(&self, path: &str) {
+        fs::write(path, serde_json::to_string_pretty(self).unwrap()).unwrap();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockKind {
+    Vector,
+    Matrix,
+    Sk,
+}
+
+impl FromStr for ClockKind {
+    type Err = String;
+
+    fn from_str(x: &str) -> Result<Self, Self::Err> {
+        match x {
+            "vector" => Ok(ClockKind::Vector),
+            "matrix" => Ok(ClockKind::Matrix),
+            "sk" => Ok(ClockKind::Sk),
+            x => Err(format!("Unknown clock: {}", x)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Inner {
+    Vector(usize, VectorClock),
+    Matrix(MatrixClock),
+    Sk(SkClock),
+}
+
+/// Causal clock of a node, piggybacked on every message it sends. Sends,
+/// receipts and logged events each count as an event.
+#[derive(Debug, Clone)]
+pub struct Clock {
+    inner: Inner,
+    pub stats: ClockStats,
+}
+
+impl Clock {
+    /// Clock of node `me`, numbered as in the config
+    pub fn new(kind: ClockKind, me: usize) -> Self {
+        let inner = match kind {
+            ClockKind::Vector => Inner::Vector(me, VectorClock::default()),
+            ClockKind::Matrix => Inner::Matrix(MatrixClock::new(me)),
+            ClockKind::Sk => Inner::Sk(SkClock::new(me)),
+        };
+        Self {
+            inner,
+            stats: ClockStats::default(),
+        }
+    }
+
+    /// A local event. Returns the node's vector clock after it.
+    pub fn tick(&mut self) -> VectorClock {
+        match &mut self.inner {
+            Inner::Vector(me, vc) => vc.tick(*me),
+            Inner::Matrix(m) => m.tick(),
+            Inner::Sk(sk) => sk.tick(),
+        }
+        self.vector().clone()
+    }
+
+    pub fn vector(&self) -> &VectorClock {
+        match &self.inner {
+            Inner::Vector(_, vc) => vc,
+            Inner::Matrix(m) => m.vector(),
+            Inner::Sk(sk) => sk.vector(),
+        }
+    }
+
+    /// A send on channel `to`, or on a connection of its own if `None`.
+    /// Returns what goes in the message.
+    pub fn send(&mut self, to: Option<Channel>) -> Vec<u64> {
+        let out = match &mut self.inner {
+            Inner::Vector(me, vc) => {
+                vc.tick(*me);
+                vc.0.clone()
+            }
+            Inner::Matrix(m) => {
+                m.tick();
+                m.encode()
+            }
+            Inner::Sk(sk) => encode_entries(&sk.send(to)),
+        };
+        // Each list goes with a 2 byte length
+        self.stats.messages += 1;
+        self.stats.bytes += 2 + 8 * out.len() as u64;
+        self.stats.vector_bytes += 2 + 8 * self.vector().0.len() as u64;
+        out
+    }
+
+    /// Receipt of a message from node `from` carrying `stamp`
+    pub fn receive(&mut self, from: usize, stamp: &[u64]) {
+        match &mut self.inner {
+            Inner::Vector(me, vc) => {
+                vc.merge(stamp);
+                vc.tick(*me);
+            }
+            Inner::Matrix(m) => {
+                m.merge(from, stamp);
+                m.tick();
+            }
+            Inner::Sk(sk) => sk.receive(&decode_entries(stamp)),
+        }
+    }
+}
+
+/// The channel a stream is, if it is still connected
+pub fn channel(stream: &TcpStream) -> Option<Channel> {
+    Some((stream.local_addr().ok()?, stream.peer_addr().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chan(a: u16, b: u16) -> Channel {
+        (
+            SocketAddr::from(([127, 0, 0, 1], a)),
+            SocketAddr::from(([127, 0, 0, 1], b)),
+        )
+    }
+
+    #[test]
+    fn vector_clock_order_and_parse() {
+        let a = VectorClock(vec![1, 2]);
+        let b = VectorClock(vec![2, 2, 1]);
+        assert!(a.happened_before(&b));
+        assert!(!b.happened_before(&a));
+        assert!(a.concurrent(&VectorClock(vec![2, 1])));
+        // Missing entries are zero
+        assert_eq!(
+            VectorClock(vec![1]).partial_cmp(&VectorClock(vec![1, 0])),
+            Some(Ordering::Equal)
+        );
+
+        assert_eq!(b.to_string().parse::<VectorClock>(), Ok(b));
+        assert_eq!("[]".parse::<VectorClock>(), Ok(VectorClock::default()));
+        assert!("[1,2]".parse::<VectorClock>().is_err());
+        assert!("1, 2".parse::<VectorClock>().is_err());
+    }
+
+    #[test]
+    fn matrix_merge() {
+        let mut a = MatrixClock::new(0);
+        let mut b = MatrixClock::new(1);
+        a.tick();
+        b.tick();
+        b.tick();
+        // Square, padded with zeroes
+        assert_eq!(a.encode(), [1]);
+        assert_eq!(b.encode(), [0, 0, 0, 2]);
+
+        b.merge(0, &a.encode());
+        b.tick();
+        assert_eq!(b.vector(), &VectorClock(vec![1, 3]));
+        assert_eq!(b.stable(), VectorClock(vec![1, 0]));
+
+        a.merge(1, &b.encode());
+        assert_eq!(a.vector(), &VectorClock(vec![1, 3]));
+        // a knows b has seen everything a has
+        assert_eq!(a.stable(), VectorClock(vec![1, 3]));
+    }
+
+    /// Delivers a message from `from` to `to` in order, over the channel
+    /// between them, and checks the receiver ends up where a plain vector
+    /// clock would.
+    fn deliver(sk: &mut [SkClock], vc: &mut [VectorClock], from: usize, to: usize) -> usize {
+        let c = chan(from as u16, to as u16);
+        let stamp = encode_entries(&sk[from].send(Some(c)));
+        vc[from].tick(from);
+        let sent = vc[from].clone();
+
+        sk[to].receive(&decode_entries(&stamp));
+        vc[to].merge(&sent.0);
+        vc[to].tick(to);
+        assert_eq!(sk[from].vector(), &vc[from]);
+        assert_eq!(sk[to].vector(), &vc[to]);
+        stamp.len() / 2
+    }
+
+    #[test]
+    fn sk_rebuilds_vector_clocks() {
+        let mut sk: Vec<_> = (0..3).map(SkClock::new).collect();
+        let mut vc = vec![VectorClock::default(); 3];
+
+        // The first message on a channel carries every entry
+        sk[0].tick();
+        vc[0].tick(0);
+        deliver(&mut sk, &mut vc, 1, 0);
+        assert_eq!(deliver(&mut sk, &mut vc, 0, 2), 2);
+
+        // Then only what changed since: node 0's own entry
+        assert_eq!(deliver(&mut sk, &mut vc, 0, 2), 1);
+
+        // Node 0 learns of node 1 and 2, and passes on only those
+        deliver(&mut sk, &mut vc, 1, 2);
+        deliver(&mut sk, &mut vc, 2, 0);
+        assert_eq!(deliver(&mut sk, &mut vc, 0, 2), 3);
+        assert_eq!(deliver(&mut sk, &mut vc, 0, 1), 3);
+        assert_eq!(deliver(&mut sk, &mut vc, 0, 1), 1);
+        assert_eq!(deliver(&mut sk, &mut vc, 1, 2), 3);
+    }
+
+    #[test]
+    fn sk_sends_everything_on_new_channels() {
+        let mut sk = SkClock::new(1);
+        sk.receive(&[(0, 4), (2, 1)]);
+        sk.send(Some(chan(1, 0)));
+        assert_eq!(sk.send(Some(chan(1, 0))), [(1, 3)]);
+        assert_eq!(sk.send(Some(chan(1, 2))), [(0, 4), (1, 4), (2, 1)]);
+        // A connection of its own is always new
+        assert_eq!(sk.send(None).len(), 3);
+        assert_eq!(sk.send(None).len(), 3);
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let entries = [(0, 3), (5, 1), (2, 0)];
+        assert_eq!(encode_entries(&entries), [0, 3, 5, 1, 2, 0]);
+        assert_eq!(decode_entries(&encode_entries(&entries)), entries);
+        assert!(decode_entries(&[]).is_empty());
+    }
+
+    #[test]
+    fn clock_stats_saved() {
+        let mut s = ClockStats::default();
+        assert_eq!(s.saved(), 0.0);
+        s.add(&ClockStats {
+            messages: 2,
+            bytes: 30,
+            vector_bytes: 100,
+        });
+        s.add(&ClockStats {
+            messages: 1,
+            bytes: 20,
+            vector_bytes: 0,
+        });
+        assert_eq!(s.messages, 3);
+        assert_eq!(s.saved(), 0.5);
+        s.bytes = 150;
+        assert_eq!(s.saved(), -0.5);
+
+        // SK sends fewer bytes once channels are warm
+        let mut c = Clock::new(ClockKind::Sk, 0);
+        let mut v = Clock::new(ClockKind::Vector, 0);
+        for x in [&mut c, &mut v] {
+            x.receive(1, &encode_entries(&[(1, 1), (2, 1), (3, 1)]));
+        }
+        v.receive(1, &[0, 1, 1, 1]);
+        for _ in 0..4 {
+            c.send(Some(chan(0, 1)));
+            v.send(Some(chan(0, 1)));
+        }
+        assert_eq!(v.stats.saved(), 0.0);
+        assert!(c.stats.saved() > 0.0);
+    }
+}
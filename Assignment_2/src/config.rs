@@ -0,0 +1,693 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::{self, OpenOptions},
+    io::Write,
+    net::{IpAddr, SocketAddr, TcpListener},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    chaos::read_rules,
+    clocks::ClockKind,
+    dist::{read_trace, Distribution},
+    topology::{Router, Topology},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Rc,
+    Maekawa,
+    Central,
+    #[serde(rename = "naimi")]
+    NaimiTrehel,
+    #[serde(rename = "ring")]
+    TokenRing,
+}
+
+impl Algorithm {
+    /// Binary that runs one node
+    pub fn bin(&self) -> &'static str {
+        match self {
+            Algorithm::Rc => "q2",
+            Algorithm::Maekawa => "q1",
+            Algorithm::Central => "central",
+            Algorithm::NaimiTrehel => "naimi",
+            Algorithm::TokenRing => "ring",
+        }
+    }
+
+    /// Directory under `log` the nodes write to
+    pub fn dir(&self) -> &'static str {
+        match self {
+            Algorithm::Rc => "rc",
+            Algorithm::Maekawa => "maekawa",
+            Algorithm::Central => "central",
+            Algorithm::NaimiTrehel => "naimi",
+            Algorithm::TokenRing => "ring",
+        }
+    }
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.dir())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(x: &str) -> Result<Self, Self::Err> {
+        match x {
+            "rc" => Ok(Algorithm::Rc),
+            "maekawa" => Ok(Algorithm::Maekawa),
+            "central" => Ok(Algorithm::Central),
+            "naimi" => Ok(Algorithm::NaimiTrehel),
+            "ring" => Ok(Algorithm::TokenRing),
+            x => Err(format!("Unknown algorithm: {}", x)),
+        }
+    }
+}
+
+/// How long a node waits for the others to publish their ports
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Address of one node, and its place in the Maekawa grid. Port 0 means any
+/// free port, published through the rendezvous file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeAddr {
+    pub addr: SocketAddr,
+    /// Row-major position in the node list when absent
+    #[serde(default)]
+    pub grid: Option<(u64, u64)>,
+}
+
+/// Settings for some of the nodes. Distributions are written as in
+/// `inp-params.txt`, e.g. `"pareto 2 1.5"`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Override {
+    pub nodes: Option<Vec<usize>>, // None means every node
+    pub k: Option<usize>,
+    #[serde(rename = "out")]
+    pub out_d: Option<String>,
+    #[serde(rename = "in")]
+    pub in_d: Option<String>,
+    pub trace: Option<String>,
+}
+
+impl Override {
+    pub fn applies(&self, node: usize) -> bool {
+        self.nodes.as_ref().is_none_or(|x| x.contains(&node))
+    }
+}
+
+/// Everything a node needs to know about the cluster.
+///
+/// Read from a JSON file with `--config`, or from `inp-params.txt` and
+/// `ips.txt` otherwise, then checked by `validate`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub algorithm: Algorithm,
+    pub n: usize,
+    /// Nodes listed after the first `n`, which start outside the cluster and
+    /// join it with `--join`. Their grid cells are vacant until they do.
+    #[serde(default)]
+    pub spares: usize,
+    pub k: usize,
+    pub out_l: f64, // mean think time in ms
+    pub in_l: f64,  // mean CS time in ms
+    /// Heartbeat timeout in ms. Zero disables failure detection.
+    #[serde(default)]
+    pub timeout: u64,
+    pub nodes: Vec<NodeAddr>,
+    #[serde(default)]
+    pub overrides: Vec<Override>,
+    /// Where logs go, `log/<algorithm>` by default
+    #[serde(default)]
+    pub log_dir: Option<String>,
+    #[serde(default)]
+    pub wal: bool,
+    /// Fault schedule, as for `Chaos`
+    #[serde(default)]
+    pub chaos: Option<String>,
+    /// Causal clock stamped on every message and log entry, if any
+    #[serde(default)]
+    pub clock: Option<ClockKind>,
+    /// File nodes on port 0 publish their actual address to
+    #[serde(default)]
+    pub rendezvous: Option<String>,
+    /// Links nodes may connect over, as for `Topology::from_spec`. Everyone
+    /// connects to everyone when absent.
+    #[serde(default)]
+    pub topology: Option<String>,
+    /// Node that grants the CS, for `Central`
+    #[serde(default)]
+    pub coordinator: usize,
+}
+
+impl ClusterConfig {
+    /// Reads a JSON config. It still needs validating.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let buf = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        serde_json::from_str(&buf).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Reads `inp-params.txt` and `ips.txt`. It still needs validating.
+    pub fn legacy(algorithm: Algorithm) -> Result<Self, String> {
+        let params =
+            fs::read_to_string("inp-params.txt").map_err(|e| format!("inp-params.txt: {}", e))?;
+        let mut lines = params.lines().enumerate();
+
+        let first = lines.next().ok_or("inp-params.txt: empty file")?.1;
+        let q = first
+            .split_whitespace()
+            .map(|x| x.parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("inp-params.txt:1: expected numbers, got {:?}", first))?;
+        if q.len() < 4 {
+            return Err(format!(
+                "inp-params.txt:1: expected `n k out_l in_l [timeout]`, got {:?}",
+                first
+            ));
+        }
+        // n, k and the timeout are counts, so 2.5 is a mistake rather than 2
+        let whole = |x: f64, what: &str| {
+            if x.fract() == 0.0 && x >= 0.0 {
+                Ok(x as u64)
+            } else {
+                Err(format!(
+                    "inp-params.txt:1: {} must be a whole number, got {}",
+                    what, x
+                ))
+            }
+        };
+        let (n, k) = (whole(q[0], "n")?, whole(q[1], "k")?);
+        let timeout = whole(q.get(4).copied().unwrap_or(0.0), "timeout")?;
+
+        let mut overrides = vec![];
+        for (i, l) in lines
+            .map(|(i, l)| (i, l.trim()))
+            .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'))
+        {
+            let bad = |what: &str| format!("inp-params.txt:{}: {}: {:?}", i + 1, what, l);
+            let mut it = l.split_whitespace();
+            let who = it.next().unwrap();
+            let mut o = Override {
+                nodes: match who {
+                    "*" => None,
+                    x => Some(
+                        x.split(',')
+                            .map(|x| x.parse())
+                            .collect::<Result<_, _>>()
+                            .map_err(|_| bad("bad node list"))?,
+                    ),
+                },
+                ..Override::default()
+            };
+            let what = it.next().ok_or_else(|| bad("missing setting"))?;
+            let rest = it.collect::<Vec<_>>().join(" ");
+            match what {
+                "out" => o.out_d = Some(rest),
+                "in" => o.in_d = Some(rest),
+                "k" => o.k = Some(rest.parse().map_err(|_| bad("bad count"))?),
+                "trace" => o.trace = Some(rest),
+                _ => return Err(bad("unknown setting")),
+            }
+            overrides.push(o);
+        }
+
+        let ips = fs::read_to_string("ips.txt").map_err(|e| format!("ips.txt: {}", e))?;
+        let nodes = ips
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.trim().is_empty())
+            .map(|(i, l)| {
+                let bad = || format!("ips.txt:{}: expected `row col ip port`, got {:?}", i + 1, l);
+                let it = l.split_whitespace().collect::<Vec<_>>();
+                let [a, b, ip, port] = it[..] else {
+                    return Err(bad());
+                };
+                Ok(NodeAddr {
+                    addr: format!("{}:{}", ip, port).parse().map_err(|_| bad())?,
+                    grid: Some((a.parse().map_err(|_| bad())?, b.parse().map_err(|_| bad())?)),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            algorithm,
+            n: n as usize,
+            spares: 0,
+            k: k as usize,
+            out_l: q[2],
+            in_l: q[3],
+            timeout,
+            nodes,
+            overrides,
+            log_dir: None,
+            wal: false,
+            chaos: None,
+            clock: None,
+            rendezvous: None,
+            topology: None,
+            coordinator: 0,
+        })
+    }
+
+    /// A manifest for `n` nodes spread round-robin over `hosts`, with grid
+    /// positions for Maekawa. Node `i` listens on `port + i`, or on any free
+    /// port if `port` is `None`, in which case the ports are exchanged through
+    /// `rendezvous`.
+    pub fn generate(
+        algorithm: Algorithm,
+        n: usize,
+        hosts: &[IpAddr],
+        port: Option<u16>,
+        rendezvous: &str,
+    ) -> Self {
+        let mut cfg = Self {
+            algorithm,
+            n,
+            spares: 0,
+            k: 5,
+            out_l: 5.0,
+            in_l: 5.0,
+            timeout: 0,
+            nodes: (0..n)
+                .map(|i| NodeAddr {
+                    addr: SocketAddr::new(hosts[i % hosts.len()], port.map_or(0, |p| p + i as u16)),
+                    grid: None,
+                })
+                .collect(),
+            overrides: vec![],
+            log_dir: None,
+            wal: false,
+            chaos: None,
+            clock: None,
+            rendezvous: port.is_none().then(|| rendezvous.to_string()),
+            topology: None,
+            coordinator: 0,
+        };
+        if algorithm == Algorithm::Maekawa {
+            for i in 0..n {
+                cfg.nodes[i].grid = Some(cfg.grid(i));
+            }
+        }
+        cfg
+    }
+
+    /// Opens the listener of node `i`. A node on port 0 gets a free port,
+    /// publishes it in the rendezvous file and waits until every other such
+    /// node has done the same, then fills in their addresses.
+    ///
+    /// The file must be empty before the cluster starts. A restarted node
+    /// gets a new port, which the others won't see.
+    pub fn bind(&mut self, i: usize) -> Result<TcpListener, String> {
+        let addr = self.nodes[i].addr;
+        let rx =
+            TcpListener::bind(addr).map_err(|e| format!("Cannot listen on {}: {}", addr, e))?;
+        let Some(path) = self.rendezvous.clone() else {
+            return Ok(rx);
+        };
+
+        let me = SocketAddr::new(addr.ip(), rx.local_addr().unwrap().port());
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        // One write, so lines of different nodes don't interleave
+        file.write_all(format!("{} {}\n", i, me).as_bytes())
+            .map_err(|e| format!("{}: {}", path, e))?;
+        self.nodes[i].addr = me;
+
+        let start = Instant::now();
+        loop {
+            let buf = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+            let mut seen = HashMap::new();
+            for l in buf.lines() {
+                let bad = || format!("{}: bad line {:?}", path, l);
+                let (j, a) = l.split_once(' ').ok_or_else(bad)?;
+                let j: usize = j.parse().map_err(|_| bad())?;
+                let a: SocketAddr = a.parse().map_err(|_| bad())?;
+                seen.insert(j, a);
+            }
+            // Spares may join much later, and tell the others their port then
+            let missing: Vec<_> = (0..self.n)
+                .filter(|j| self.nodes[*j].addr.port() == 0 && !seen.contains_key(j))
+                .collect();
+            if missing.is_empty() {
+                let len = self.nodes.len();
+                for (j, a) in seen.into_iter().filter(|x| x.0 < len) {
+                    self.nodes[j].addr = a;
+                }
+                return Ok(rx);
+            }
+            if start.elapsed() > RENDEZVOUS_TIMEOUT {
+                return Err(format!(
+                    "Timed out waiting for nodes {:?} to publish their ports in {}",
+                    missing, path
+                ));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Loads the config a node binary was started with and applies the flags
+    /// that override it. Returns the remaining arguments, which name the node.
+    ///
+    /// Flags: `--config <file>`, `--k <count>`, `--out-l <ms>`, `--in-l <ms>`,
+    /// `--timeout <ms>`, `--log-dir <dir>`, `--wal`, `--chaos [file]`,
+    /// `--clock <vector|matrix|sk>`, `--topology <spec>` and
+    /// `--coordinator <node>`.
+    pub fn from_args(algorithm: Algorithm, args: &[String]) -> Result<(Self, Vec<String>), String> {
+        let mut cfg = match args.iter().position(|x| x == "--config") {
+            Some(i) => Self::load(args.get(i + 1).ok_or("--config needs a file")?)?,
+            None => Self::legacy(algorithm)?,
+        };
+        if cfg.algorithm != algorithm {
+            return Err(format!(
+                "The config is for {}, but this binary runs {}: use {}",
+                cfg.algorithm,
+                algorithm,
+                cfg.algorithm.bin()
+            ));
+        }
+
+        let mut rest = vec![];
+        let mut it = args.iter().peekable();
+        while let Some(flag) = it.next() {
+            let mut val = |what: &str| it.next().ok_or(format!("{} needs {}", flag, what)).cloned();
+            let num = |x: String| {
+                x.parse::<f64>()
+                    .map_err(|_| format!("{} needs a number, got {:?}", flag, x))
+            };
+            let count = |x: String| {
+                x.parse::<u64>()
+                    .map_err(|_| format!("{} needs a whole number, got {:?}", flag, x))
+            };
+            match flag.as_str() {
+                "--config" => {
+                    val("a file")?;
+                }
+                "--k" => cfg.k = count(val("a count")?)? as usize,
+                "--out-l" => cfg.out_l = num(val("a mean in ms")?)?,
+                "--in-l" => cfg.in_l = num(val("a mean in ms")?)?,
+                "--timeout" => cfg.timeout = count(val("a timeout in ms")?)?,
+                "--log-dir" => cfg.log_dir = Some(val("a directory")?),
+                "--wal" => cfg.wal = true,
+                "--chaos" => {
+                    let file = it.next_if(|x| !x.starts_with("--") && x.parse::<u64>().is_err());
+                    cfg.chaos = file
+                        .cloned()
+                        .or(cfg.chaos)
+                        .or(Some("chaos.txt".to_string()));
+                }
+                "--clock" => cfg.clock = Some(val("vector, matrix or sk")?.parse()?),
+                "--topology" => cfg.topology = Some(val("a file or a shape")?),
+                "--coordinator" => cfg.coordinator = count(val("a node")?)? as usize,
+                x if x.starts_with("--") => return Err(format!("Unknown flag: {}", x)),
+                x => rest.push(x.to_string()),
+            }
+        }
+
+        cfg.validate()?;
+        Ok((cfg, rest))
+    }
+
+    /// Side of the Maekawa grid, which has a cell for every spare too
+    pub fn side(&self) -> usize {
+        (self.nodes.len() as f64).sqrt() as usize
+    }
+
+    /// Grid position of node `i`
+    pub fn grid(&self, i: usize) -> (u64, u64) {
+        let q = self.side().max(1);
+        self.nodes[i]
+            .grid
+            .unwrap_or(((i / q) as u64, (i % q) as u64))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.n == 0 {
+            return Err("n must be at least 1".to_string());
+        }
+        let total = self.n + self.spares;
+        if self.nodes.len() < total {
+            return Err(format!(
+                "Node {} is missing: n is {} and spares {}, but only {} nodes are listed",
+                self.nodes.len(),
+                self.n,
+                self.spares,
+                self.nodes.len()
+            ));
+        }
+        if self.coordinator >= self.n {
+            return Err(format!(
+                "The coordinator is node {}, but n is {}",
+                self.coordinator, self.n
+            ));
+        }
+        if self.nodes.len() > total {
+            return Err(format!(
+                "{} nodes are listed but n is {} and spares {}",
+                self.nodes.len(),
+                self.n,
+                self.spares
+            ));
+        }
+        let q = self.side();
+        if self.algorithm == Algorithm::Maekawa && q * q != total {
+            return Err(format!(
+                "{} nodes is not a perfect square, but Maekawa arranges the nodes in a grid",
+                total
+            ));
+        }
+        for (what, x) in [("out_l", self.out_l), ("in_l", self.in_l)] {
+            if !x.is_finite() || x < 0.0 {
+                return Err(format!("{} must be a non-negative number, got {}", what, x));
+            }
+        }
+
+        let mut addrs = HashMap::new();
+        let mut cells = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.addr.port() == 0 {
+                if self.rendezvous.is_none() {
+                    return Err(format!(
+                        "Node {} has port 0, which needs a rendezvous file",
+                        i
+                    ));
+                }
+            } else if let Some(j) = addrs.insert(node.addr, i) {
+                return Err(format!("Nodes {} and {} both use {}", j, i, node.addr));
+            }
+            if self.algorithm == Algorithm::Maekawa {
+                let (a, b) = self.grid(i);
+                if a as usize >= q || b as usize >= q {
+                    return Err(format!(
+                        "Node {} is at {:?}, outside the {}x{} grid",
+                        i,
+                        (a, b),
+                        q,
+                        q
+                    ));
+                }
+                if let Some(j) = cells.insert((a, b), i) {
+                    return Err(format!("Nodes {} and {} are both at {:?}", j, i, (a, b)));
+                }
+            }
+        }
+        if self.algorithm == Algorithm::Maekawa {
+            // Some cross must be clear of vacant cells
+            let spare = self.spare_cells();
+            let row = (0..q as u64).any(|a| spare.iter().all(|x| x.0 != a));
+            let col = (0..q as u64).any(|b| spare.iter().all(|x| x.1 != b));
+            if !row || !col {
+                return Err(format!(
+                    "Spares at {:?} leave no row and column of the grid fully occupied",
+                    spare
+                ));
+            }
+        }
+
+        if let Some(spec) = &self.topology {
+            let t = Topology::from_spec(spec, total)?;
+            if !t.connected(self.n) {
+                return Err(format!(
+                    "Topology {} is not connected, or a spare has no neighbour among the first {} nodes",
+                    spec, self.n
+                ));
+            }
+        }
+
+        if let Some(path) = &self.chaos {
+            read_rules(path, &self.addrs())?;
+        }
+
+        for (i, o) in self.overrides.iter().enumerate() {
+            let bad = |e: String| format!("Override {}: {}", i, e);
+            if let Some(x) = o.nodes.iter().flatten().find(|&&x| x >= total) {
+                return Err(bad(format!(
+                    "node {} does not exist, only {} are listed",
+                    x, total
+                )));
+            }
+            for d in [&o.out_d, &o.in_d].into_iter().flatten() {
+                Distribution::parse(d.split_whitespace()).map_err(bad)?;
+            }
+            if let Some(t) = &o.trace {
+                read_trace(t).map_err(bad)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Index of the node at a grid position
+    pub fn find_grid(&self, pos: (u64, u64)) -> Result<usize, String> {
+        (0..self.nodes.len())
+            .find(|&i| self.grid(i) == pos)
+            .ok_or(format!("No node at {:?}", pos))
+    }
+
+    /// Addresses by grid position, spares included, for `MaekawaNode`
+    pub fn grid_ips(&self) -> HashMap<(u64, u64), SocketAddr> {
+        (0..self.nodes.len())
+            .map(|i| (self.grid(i), self.nodes[i].addr))
+            .collect()
+    }
+
+    /// Cells of the spares, vacant until they join
+    pub fn spare_cells(&self) -> Vec<(u64, u64)> {
+        (self.n..self.nodes.len()).map(|i| self.grid(i)).collect()
+    }
+
+    /// Addresses by node index, for `RCNode`
+    pub fn ips(&self) -> HashMap<u128, SocketAddr> {
+        (0..self.n)
+            .map(|i| (i as u128, self.nodes[i].addr))
+            .collect()
+    }
+
+    /// Addresses of every node that may be in the cluster, for a newcomer
+    pub fn all_ips(&self) -> HashMap<u128, SocketAddr> {
+        (0..self.nodes.len())
+            .map(|i| (i as u128, self.nodes[i].addr))
+            .collect()
+    }
+
+    /// Router of node `i`, if the config has a topology
+    pub fn router(&self, i: usize) -> Option<Router> {
+        let spec = self.topology.as_ref()?;
+        let t = Topology::from_spec(spec, self.nodes.len()).unwrap();
+        Some(Router::new(&t, i, self.addrs(), self.n))
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.nodes.iter().map(|x| x.addr).collect()
+    }
+
+    pub fn log_dir(&self) -> String {
+        self.log_dir
+            .clone()
+            .unwrap_or(format!("log/{}", self.algorithm.dir()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(algorithm: Algorithm, n: usize) -> ClusterConfig {
+        ClusterConfig::generate(
+            algorithm,
+            n,
+            &["127.0.0.1".parse().unwrap()],
+            Some(7000),
+            "",
+        )
+    }
+
+    fn err(cfg: &ClusterConfig) -> String {
+        cfg.validate().unwrap_err()
+    }
+
+    #[test]
+    fn accepts_generated_manifests() {
+        for alg in [Algorithm::Rc, Algorithm::Maekawa] {
+            assert_eq!(cfg(alg, 9).validate(), Ok(()));
+        }
+        let mut c = cfg(Algorithm::Rc, 3);
+        c.rendezvous = Some("ports.txt".to_string());
+        for x in c.nodes.iter_mut() {
+            x.addr.set_port(0);
+        }
+        assert_eq!(c.validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_bad_node_lists() {
+        assert_eq!(
+            err(&cfg(Algorithm::Maekawa, 5)),
+            "5 nodes is not a perfect square, but Maekawa arranges the nodes in a grid"
+        );
+
+        let mut c = cfg(Algorithm::Rc, 4);
+        c.nodes.pop();
+        assert_eq!(
+            err(&c),
+            "Node 3 is missing: n is 4 and spares 0, but only 3 nodes are listed"
+        );
+        let mut c = cfg(Algorithm::Rc, 4);
+        c.nodes.push(c.nodes[0].clone());
+        assert_eq!(err(&c), "5 nodes are listed but n is 4 and spares 0");
+
+        let mut c = cfg(Algorithm::Rc, 4);
+        c.nodes[2].addr = c.nodes[1].addr;
+        assert_eq!(err(&c), "Nodes 1 and 2 both use 127.0.0.1:7001");
+        c.nodes[2].addr.set_port(0);
+        assert_eq!(err(&c), "Node 2 has port 0, which needs a rendezvous file");
+    }
+
+    #[test]
+    fn rejects_bad_grids() {
+        let mut c = cfg(Algorithm::Maekawa, 4);
+        c.nodes[3].grid = Some((2, 0));
+        assert_eq!(err(&c), "Node 3 is at (2, 0), outside the 2x2 grid");
+        c.nodes[3].grid = Some((0, 0));
+        assert_eq!(err(&c), "Nodes 0 and 3 are both at (0, 0)");
+    }
+
+    #[test]
+    fn rejects_bad_overrides() {
+        let mut c = cfg(Algorithm::Rc, 4);
+        c.overrides = vec![
+            Override::default(),
+            Override {
+                nodes: Some(vec![1, 4]),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            err(&c),
+            "Override 1: node 4 does not exist, only 4 are listed"
+        );
+
+        c.overrides = vec![Override {
+            in_d: Some("uniform 3 1".to_string()),
+            ..Default::default()
+        }];
+        assert_eq!(err(&c), "Override 0: Empty range for uniform: 3 > 1");
+        c.overrides[0].in_d = Some("normal 1".to_string());
+        assert!(err(&c).starts_with("Override 0: "), "{}", err(&c));
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    io::{self, Write},
+    mem,
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{chaos::Chaos, topology::Router, utils::Message};
+
+/// First retry delay, also how often pollers wake up while a connection is down.
+pub const MIN_BACKOFF: Duration = Duration::from_millis(10);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(1);
+pub const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Poller key for a listener's own socket.
+pub const ACCEPT: usize = usize::MAX - 1;
+
+/// Connects to `addr`, backing off exponentially until the peer is up.
+pub fn connect(addr: &SocketAddr) -> TcpStream {
+    connect_via(addr, None)
+}
+
+/// Connects once, through `router` if there is one.
+fn dial(addr: &SocketAddr, router: Option<&Router>) -> io::Result<TcpStream> {
+    match router {
+        Some(r) => r.connect(addr, CONNECT_TIMEOUT),
+        None => TcpStream::connect_timeout(addr, CONNECT_TIMEOUT),
+    }
+}
+
+/// Like `connect`, through `router` if there is one.
+pub fn connect_via(addr: &SocketAddr, router: Option<&Router>) -> TcpStream {
+    let mut delay = MIN_BACKOFF;
+    loop {
+        match dial(addr, router) {
+            Ok(x) => break x,
+            Err(_) => {
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Outgoing connection to a peer that outlives the peer crashing.
+///
+/// Messages that cannot be written are kept in an outbox until the
+/// connection is re-established. The owner is responsible for noticing EOF
+/// on the stream (and removing it from its poller) before calling `down`.
+#[derive(Debug)]
+pub struct Conn {
+    addr: SocketAddr,
+    stream: Option<TcpStream>,
+    outbox: Vec<Message>,
+    delay: Duration,
+    retry: Instant,
+    chaos: Option<Arc<Chaos>>,
+    router: Option<Arc<Router>>,
+    closed: bool,   // the peer is out of the cluster
+    inbox: Vec<u8>, // start of a message still being read
+    /// Half-closed stream, kept open: closing it with unread data would
+    /// reset the connection and lose what we sent last.
+    linger: Option<TcpStream>,
+}
+
+impl Conn {
+    /// Connects to `addr`, through `router` if there is one, waiting until
+    /// the peer is up.
+    pub fn new(addr: SocketAddr, router: Option<Arc<Router>>) -> Self {
+        Self {
+            stream: Some(connect_via(&addr, router.as_deref())),
+            addr,
+            outbox: vec![],
+            delay: MIN_BACKOFF,
+            retry: Instant::now(),
+            chaos: None,
+            router,
+            closed: false,
+            inbox: vec![],
+            linger: None,
+        }
+    }
+
+    /// Connects once, for peers that may not be in the cluster.
+    pub fn try_new(addr: SocketAddr, router: Option<Arc<Router>>) -> Option<Self> {
+        let stream = dial(&addr, router.as_deref()).ok()?;
+        Some(Self {
+            stream: Some(stream),
+            router,
+            closed: false,
+            ..Self::closed(addr)
+        })
+    }
+
+    /// A connection to a peer that is not in the cluster, until it is
+    /// replaced by a new one.
+    pub fn closed(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            stream: None,
+            outbox: vec![],
+            delay: MIN_BACKOFF,
+            retry: Instant::now(),
+            chaos: None,
+            router: None,
+            closed: true,
+            inbox: vec![],
+            linger: None,
+        }
+    }
+
+    /// Routes writes through a fault-injecting transport.
+    pub fn with_chaos(mut self, chaos: Option<Arc<Chaos>>) -> Self {
+        self.chaos = chaos;
+        self
+    }
+
+    pub fn stream(&self) -> Option<&TcpStream> {
+        self.stream.as_ref()
+    }
+
+    /// Bytes read so far of a message that did not arrive whole
+    pub fn inbox(&mut self) -> &mut Vec<u8> {
+        &mut self.inbox
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Stops using a connection for good once the peer has left. The owner
+    /// removes the stream from its poller first.
+    pub fn close(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(Shutdown::Write);
+            self.linger = Some(stream);
+        }
+        self.outbox.clear();
+        self.closed = true;
+    }
+
+    /// Writes a message, or queues it if the connection is down or breaks.
+    /// Returns the number of copies sent, counting a queued message as one.
+    pub fn send(&mut self, msg: Message) -> usize {
+        if self.closed {
+            return 0;
+        }
+        if let Some(mut stream) = self.stream.as_ref() {
+            let res = match &self.chaos {
+                Some(chaos) => chaos.write(stream, self.addr, msg.clone()),
+                None => stream
+                    .write_all(&Vec::from(msg.clone()))
+                    .and_then(|_| stream.flush())
+                    .map(|_| 1),
+            };
+            if let Ok(copies) = res {
+                return copies;
+            }
+        }
+        self.outbox.push(msg);
+        1
+    }
+
+    /// Drops a connection the peer has closed.
+    pub fn down(&mut self) {
+        self.stream = None;
+        self.inbox.clear();
+        self.retry = Instant::now() + self.delay;
+    }
+
+    /// Tries to reconnect once the backoff has expired. True if reconnected.
+    pub fn reconnect(&mut self) -> bool {
+        if self.closed || self.stream.is_some() || Instant::now() < self.retry {
+            return false;
+        }
+        match dial(&self.addr, self.router.as_deref()) {
+            Ok(x) => {
+                self.stream = Some(x);
+                self.delay = MIN_BACKOFF;
+                true
+            }
+            Err(_) => {
+                self.delay = (self.delay * 2).min(MAX_BACKOFF);
+                self.retry = Instant::now() + self.delay;
+                false
+            }
+        }
+    }
+
+    /// Keeps only the queued messages that are still worth sending.
+    pub fn retain(&mut self, f: impl FnMut(&Message) -> bool) {
+        self.outbox.retain(f);
+    }
+
+    pub fn has_queued(&self) -> bool {
+        !self.outbox.is_empty()
+    }
+
+    /// Resends everything queued while the connection was down.
+    pub fn flush(&mut self) {
+        for msg in mem::take(&mut self.outbox) {
+            self.send(msg);
+        }
+    }
+}
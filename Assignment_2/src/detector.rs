@@ -0,0 +1,120 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    chaos::Chaos,
+    utils::{Message, MessageType, Pid},
+};
+
+/// How long peers get to start, on top of the timeout, before their silence
+/// counts against them.
+const GRACE: Duration = Duration::from_secs(5);
+
+/// Heartbeat failure detector.
+///
+/// Heartbeats go over UDP on the same address as the node's TCP listener, so
+/// they never show up on the algorithm's streams. A peer is suspected once it
+/// has been silent for `timeout`. Every peer counts as heard from `GRACE`
+/// after the detector is made, so a slow starter is not mistaken for a
+/// crashed node, but one that never starts is still suspected.
+///
+/// With a `Chaos` schedule, heartbeats are subject to its partitions and
+/// drops, so a scripted partition shows up as a suspicion.
+pub struct Detector {
+    id: Pid,
+    sock: UdpSocket,
+    peers: HashMap<Pid, SocketAddr>,
+    timeout: Duration,
+    period: Duration,
+    last: Mutex<HashMap<Pid, Instant>>,
+    suspects: Mutex<HashSet<Pid>>,
+    chaos: Option<Arc<Chaos>>,
+}
+
+impl Detector {
+    pub fn new(id: Pid, mut peers: HashMap<Pid, SocketAddr>, timeout: Duration) -> Self {
+        let sock = UdpSocket::bind(peers.remove(&id).unwrap()).unwrap();
+        let start = Instant::now() + GRACE;
+        let last = peers.keys().map(|&x| (x, start)).collect();
+        Self {
+            id,
+            sock,
+            peers,
+            timeout,
+            period: timeout / 4,
+            last: Mutex::new(last),
+            suspects: Mutex::new(HashSet::new()),
+            chaos: None,
+        }
+    }
+
+    /// Loses heartbeats the way `chaos` loses messages.
+    pub fn with_chaos(mut self, chaos: Arc<Chaos>) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// How often heartbeats are sent. Pollers use this as their wait timeout.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn suspected(&self, pid: &Pid) -> bool {
+        self.suspects.lock().unwrap().contains(pid)
+    }
+
+    /// One heartbeat round: beat to every peer, collect beats for a period,
+    /// then return the peers whose status changed. `true` means recovered.
+    pub fn tick(&self) -> Vec<(Pid, bool)> {
+        let beat: Vec<u8> = Message {
+            id: self.id,
+            typ: MessageType::Heartbeat,
+            ts: 0,
+            clock: vec![],
+        }
+        .into();
+        for addr in self.peers.values() {
+            if let Some(chaos) = &self.chaos {
+                if chaos.lost(*addr, MessageType::Heartbeat) {
+                    continue;
+                }
+            }
+            // Nobody listening is exactly what we are trying to detect.
+            let _ = self.sock.send_to(&beat, addr);
+        }
+
+        let until = Instant::now() + self.period;
+        let mut buf = [0; 64];
+        loop {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            self.sock.set_read_timeout(Some(left)).unwrap();
+            if let Ok(33) = self.sock.recv(&mut buf) {
+                let mut msg = Message::from(&buf[..33]);
+                if self.id.is_right() {
+                    msg.flip();
+                }
+                self.last.lock().unwrap().insert(msg.id, Instant::now());
+            }
+        }
+
+        let last = self.last.lock().unwrap();
+        let mut suspects = self.suspects.lock().unwrap();
+        let mut out = vec![];
+        for (pid, seen) in last.iter() {
+            let silent = seen.elapsed() > self.timeout;
+            if silent && suspects.insert(*pid) {
+                out.push((*pid, false));
+            } else if !silent && suspects.remove(pid) {
+                out.push((*pid, true));
+            }
+        }
+        out
+    }
+}
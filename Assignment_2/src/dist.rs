@@ -0,0 +1,128 @@
+use std::fs;
+
+use rand::Rng;
+
+/// How long a node stays in a region, in milliseconds.
+#[derive(Debug, Clone)]
+pub enum Distribution {
+    Constant(f64),
+    /// With the given mean, so requests form a Poisson process
+    Exponential(f64),
+    Uniform(f64, f64),
+    /// Heavy tailed, with the given minimum (scale) and shape. Shapes at or
+    /// below 2 have infinite variance, at or below 1 infinite mean.
+    Pareto(f64, f64),
+    /// Drawn from a list of samples
+    Empirical(Vec<f64>),
+}
+
+impl Distribution {
+    pub fn sample(&self, rng: &mut impl Rng) -> f64 {
+        match self {
+            Distribution::Constant(x) => *x,
+            Distribution::Exponential(mean) => -(1.0 - rng.gen::<f64>()).ln() * mean,
+            Distribution::Uniform(lo, hi) => rng.gen_range(*lo..=*hi),
+            Distribution::Pareto(scale, shape) => {
+                scale / (1.0 - rng.gen::<f64>()).powf(1.0 / shape)
+            }
+            Distribution::Empirical(xs) => xs[rng.gen_range(0..xs.len())],
+        }
+    }
+
+    /// Parses `constant <ms>`, `exponential <mean ms>`, `uniform <min ms> <max ms>`,
+    /// `pareto <scale ms> <shape>` or `empirical <file>`. The file holds one
+    /// sample in milliseconds per line.
+    pub fn parse<'a>(mut it: impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        let name = it.next().ok_or("Missing distribution")?;
+        let mut num = || -> Result<f64, String> {
+            let x = it.next().ok_or(format!("Missing argument for {}", name))?;
+            x.parse()
+                .ok()
+                .filter(|x: &f64| x.is_finite() && *x >= 0.0)
+                .ok_or(format!("Bad argument for {}: {}", name, x))
+        };
+        Ok(match name {
+            "constant" => Distribution::Constant(num()?),
+            "exponential" | "exp" => Distribution::Exponential(num()?),
+            "uniform" => {
+                let (lo, hi) = (num()?, num()?);
+                if lo > hi {
+                    return Err(format!("Empty range for uniform: {} > {}", lo, hi));
+                }
+                Distribution::Uniform(lo, hi)
+            }
+            "pareto" => {
+                let (scale, shape) = (num()?, num()?);
+                if scale == 0.0 || shape == 0.0 {
+                    return Err("Pareto scale and shape must be positive".to_string());
+                }
+                Distribution::Pareto(scale, shape)
+            }
+            "empirical" => {
+                let path = it.next().ok_or("Missing file for empirical")?;
+                let buf = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+                let xs = buf
+                    .split_whitespace()
+                    .map(|x| {
+                        x.parse()
+                            .ok()
+                            .filter(|x: &f64| x.is_finite() && *x >= 0.0)
+                            .ok_or(format!("Bad sample in {}: {}", path, x))
+                    })
+                    .collect::<Result<Vec<f64>, _>>()?;
+                if xs.is_empty() {
+                    return Err(format!("No samples in {}", path));
+                }
+                Distribution::Empirical(xs)
+            }
+            x => return Err(format!("Unknown distribution: {}", x)),
+        })
+    }
+}
+
+/// Reads a workload trace: one `<request ms> <hold ms>` pair per line, with
+/// request times counted from the start of the node. Lines starting with `#`
+/// are ignored.
+pub fn read_trace(path: &str) -> Result<Vec<(f64, f64)>, String> {
+    let buf = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut out = buf
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let mut it = l.split_whitespace().map(|x| x.parse::<f64>());
+            match (it.next(), it.next()) {
+                (Some(Ok(at)), Some(Ok(hold))) if at.is_finite() && hold.is_finite() => {
+                    Ok((at, hold))
+                }
+                _ => Err(format!("Bad line in {}: {}", path, l)),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    out.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(x: &str) -> Result<Distribution, String> {
+        Distribution::parse(x.split_whitespace())
+    }
+
+    #[test]
+    fn rejects_degenerate_arguments() {
+        assert!(parse("uniform 1 5").is_ok());
+        assert!(parse("uniform 5 5").is_ok());
+        assert!(parse("uniform 5 1").is_err());
+        assert!(parse("pareto 10 0").is_err());
+        assert!(parse("pareto 0 1.5").is_err());
+        assert!(parse("pareto 10 0.5").is_ok());
+        assert!(parse("constant -1").is_err());
+        assert!(parse("exponential NaN").is_err());
+        assert!(parse("exponential inf").is_err());
+        assert!(parse("exponential").is_err());
+        assert!(parse("gamma 1").is_err());
+    }
+}
@@ -0,0 +1,527 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::Write,
+    net::{SocketAddr, TcpListener},
+    str::FromStr,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use either::Either::Right;
+
+use crate::{
+    broadcast::Group,
+    chaos::Chaos,
+    topology::Router,
+    utils::{epoch_micros, Action, LogEntry, Message, MessageType},
+};
+
+/// How long a node waits for an answer, unless told otherwise
+pub const ELECTION_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Election {
+    /// Garcia-Molina's bully algorithm, over the full mesh
+    Bully,
+    /// Chang–Roberts, on a unidirectional ring
+    ChangRoberts,
+    /// Hirschberg–Sinclair, on a bidirectional ring
+    HirschbergSinclair,
+}
+
+impl FromStr for Election {
+    type Err = String;
+
+    fn from_str(x: &str) -> Result<Self, Self::Err> {
+        match x {
+            "bully" => Ok(Election::Bully),
+            "cr" => Ok(Election::ChangRoberts),
+            "hs" => Ok(Election::HirschbergSinclair),
+            _ => Err(format!("Unknown election: {} (bully, cr or hs)", x)),
+        }
+    }
+}
+
+impl Display for Election {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Election::Bully => write!(f, "bully"),
+            Election::ChangRoberts => write!(f, "cr"),
+            Election::HirschbergSinclair => write!(f, "hs"),
+        }
+    }
+}
+
+/// A node taking part in one leader election.
+///
+/// Nodes are numbered by their place in the config. The ring runs in that
+/// order, and the node of highest rank wins; by default a node's rank is its
+/// number. Messages carry the candidate's number in `ts`, and
+/// Hirschberg–Sinclair probes their phase and hop count in `clock`.
+///
+/// Once it knows the leader, a node stays until nothing has come in for a
+/// timeout, to pass on what is still in flight. In the bully algorithm it
+/// answers late candidates with the leader.
+pub struct ElectionNode {
+    pub id: usize,
+    alg: Election,
+    n: usize,
+    ranks: Vec<u64>,
+    group: Group,
+    timeout: Duration,
+    initiator: bool,
+    crashed: bool,
+    dir: String,
+    pub mc: u64,
+    pub init: Instant,
+    log: Vec<LogEntry>,
+    outbox: Vec<(usize, Message)>, // sent by the last step, to hand to `group`
+
+    leader: Option<usize>,
+    participant: bool,
+    deadline: Option<Instant>, // bully: when to give up waiting
+    answered: bool,            // bully: a higher node is alive
+    phase: u32,                // Hirschberg–Sinclair
+    replies: usize,            // to the probes of this phase
+}
+
+impl ElectionNode {
+    pub fn new(id: usize, alg: Election, addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            id,
+            alg,
+            n: addrs.len(),
+            ranks: (0..addrs.len() as u64).collect(),
+            group: Group::new(id, addrs),
+            timeout: ELECTION_TIMEOUT,
+            initiator: true,
+            crashed: false,
+            dir: "log/election".to_string(),
+            mc: 0,
+            init: Instant::now(),
+            log: vec![],
+            outbox: vec![],
+            leader: None,
+            participant: false,
+            deadline: None,
+            answered: false,
+            phase: 0,
+            replies: 0,
+        }
+    }
+
+    /// Ranks of every node, the same on all of them
+    pub fn with_ranks(mut self, ranks: Vec<u64>) -> Self {
+        self.ranks = ranks;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Waits for someone else to start the election.
+    pub fn passive(mut self) -> Self {
+        self.initiator = false;
+        self
+    }
+
+    /// Takes no part, as if crashed, until it hears who won. Only the bully
+    /// algorithm copes with this.
+    pub fn crashed(mut self) -> Self {
+        self.crashed = true;
+        self
+    }
+
+    pub fn with_chaos(mut self, path: &str, ips: &[SocketAddr]) -> Self {
+        self.group.chaos = Some(Arc::new(Chaos::new(ips[self.id], path, ips)));
+        self
+    }
+
+    pub fn with_router(mut self, router: Router) -> Self {
+        self.group.router = Some(Arc::new(router));
+        self
+    }
+
+    pub fn with_log_dir(mut self, dir: &str) -> Self {
+        self.dir = dir.to_string();
+        self
+    }
+
+    fn entry(&mut self, act: Action) {
+        self.log.push(LogEntry {
+            pid: Right(self.id as u128),
+            ts: self.init.elapsed().as_micros(),
+            act,
+            clock: None,
+        });
+    }
+
+    fn send(&mut self, to: usize, typ: MessageType, candidate: usize, clock: Vec<u64>) {
+        self.mc += 1;
+        let msg = Message::new_rc(self.id as u128, typ, candidate as u128).with_clock(clock);
+        self.outbox.push((to, msg));
+    }
+
+    /// Hands what the last steps sent to the connections
+    fn flush(&mut self) {
+        for (to, msg) in self.outbox.drain(..) {
+            self.group.send(to, msg);
+        }
+    }
+
+    fn next(&self) -> usize {
+        (self.id + 1) % self.n
+    }
+
+    fn prev(&self) -> usize {
+        (self.id + self.n - 1) % self.n
+    }
+
+    /// The ring neighbour other than `from`
+    fn onward(&self, from: usize) -> usize {
+        if from == self.next() {
+            self.prev()
+        } else {
+            self.next()
+        }
+    }
+
+    fn beats(&self, a: usize, b: usize) -> bool {
+        self.ranks[a] > self.ranks[b]
+    }
+
+    /// Runs the election on the listener `rx`, and writes the log to
+    /// `node_<id>.log`. Returns the leader, or `None` if crashed.
+    pub fn run(&mut self, rx: TcpListener) -> Option<usize> {
+        let (tx, inbox) = mpsc::channel();
+        self.group.start(
+            rx,
+            Arc::new(move |msg| {
+                let _ = tx.send(msg);
+            }),
+        );
+        println!("Connections established.");
+        self.init = Instant::now();
+        self.entry(Action::Start(epoch_micros()));
+
+        if self.crashed {
+            while let Ok(msg) = inbox.recv() {
+                if msg.typ == MessageType::Leader {
+                    break;
+                }
+            }
+            self.write_log();
+            return None;
+        }
+        if self.n == 1 {
+            self.leader = Some(self.id);
+            self.entry(Action::Leader(Right(self.id as u128)));
+        } else if self.initiator {
+            self.stand();
+        }
+
+        loop {
+            self.flush();
+            let wait = match (self.leader, self.deadline) {
+                (None, Some(t)) => t.saturating_duration_since(Instant::now()),
+                _ => self.timeout,
+            };
+            match inbox.recv_timeout(wait) {
+                Ok(msg) => self.handle(msg),
+                Err(RecvTimeoutError::Timeout) if self.leader.is_some() => break,
+                Err(RecvTimeoutError::Timeout) if self.deadline.is_some() => self.expire(),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        self.write_log();
+        self.leader
+    }
+
+    fn write_log(&self) {
+        let mut file = File::create(format!("{}/node_{}.log", self.dir, self.id)).unwrap();
+        for x in self.log.iter() {
+            writeln!(file, "{}", x).unwrap();
+        }
+    }
+
+    /// Stands for leader
+    fn stand(&mut self) {
+        self.participant = true;
+        self.entry(Action::Elect);
+        match self.alg {
+            Election::Bully => {
+                let higher: Vec<_> = (0..self.n).filter(|&j| self.beats(j, self.id)).collect();
+                if higher.is_empty() {
+                    return self.win();
+                }
+                for j in higher {
+                    self.send(j, MessageType::Elect, self.id, vec![]);
+                }
+                self.answered = false;
+                self.deadline = Some(Instant::now() + self.timeout);
+            }
+            Election::ChangRoberts => {
+                self.send(self.next(), MessageType::Elect, self.id, vec![]);
+            }
+            Election::HirschbergSinclair => {
+                self.phase = 0;
+                self.probe();
+            }
+        }
+    }
+
+    /// Sends the probes of the current phase both ways round the ring
+    fn probe(&mut self) {
+        self.replies = 0;
+        let clock = vec![self.phase as u64, 1];
+        self.send(self.next(), MessageType::Elect, self.id, clock.clone());
+        self.send(self.prev(), MessageType::Elect, self.id, clock);
+    }
+
+    fn win(&mut self) {
+        self.leader = Some(self.id);
+        self.deadline = None;
+        self.entry(Action::Leader(Right(self.id as u128)));
+        match self.alg {
+            Election::Bully => {
+                for j in 0..self.n {
+                    if j != self.id {
+                        self.send(j, MessageType::Leader, self.id, vec![]);
+                    }
+                }
+            }
+            _ => self.send(self.next(), MessageType::Leader, self.id, vec![]),
+        }
+    }
+
+    /// Bully: nobody higher answered in time, or the one that did never
+    /// announced itself
+    fn expire(&mut self) {
+        self.deadline = None;
+        if self.answered {
+            self.stand();
+        } else {
+            self.win();
+        }
+    }
+
+    fn handle(&mut self, msg: Message) {
+        let from = msg.id.expect_right("") as usize;
+        let c = msg.ts as usize;
+        match (msg.typ, self.alg) {
+            (MessageType::Leader, alg) => {
+                if self.leader.is_some() {
+                    return;
+                }
+                self.leader = Some(c);
+                self.deadline = None;
+                self.entry(Action::Leader(Right(c as u128)));
+                if alg != Election::Bully && c != self.id {
+                    self.send(self.next(), MessageType::Leader, c, vec![]);
+                }
+            }
+
+            (MessageType::Elect, Election::Bully) => {
+                if let Some(l) = self.leader {
+                    self.send(from, MessageType::Leader, l, vec![]);
+                    return;
+                }
+                self.send(from, MessageType::Alive, self.id, vec![]);
+                if self.deadline.is_none() {
+                    self.stand();
+                }
+            }
+            (MessageType::Alive, Election::Bully) if self.leader.is_none() => {
+                // Give the higher node time to win its own election
+                self.answered = true;
+                self.deadline = Some(Instant::now() + self.timeout * 2);
+            }
+
+            _ if self.leader.is_some() => {}
+            (MessageType::Elect, Election::ChangRoberts) => {
+                if c == self.id {
+                    self.win();
+                } else if self.beats(c, self.id) {
+                    self.participant = true;
+                    self.send(self.next(), MessageType::Elect, c, vec![]);
+                } else if !self.participant {
+                    self.stand();
+                }
+            }
+            (MessageType::Elect, Election::HirschbergSinclair) => {
+                let (phase, hops) = (msg.clock[0], msg.clock[1]);
+                if c == self.id {
+                    self.win();
+                } else if self.beats(c, self.id) {
+                    if hops < 1 << phase {
+                        let to = self.onward(from);
+                        self.send(to, MessageType::Elect, c, vec![phase, hops + 1]);
+                    } else {
+                        self.send(from, MessageType::Alive, c, vec![phase]);
+                    }
+                } else if !self.participant {
+                    self.stand();
+                }
+            }
+            (MessageType::Alive, Election::HirschbergSinclair) => {
+                if c != self.id {
+                    let to = self.onward(from);
+                    self.send(to, MessageType::Alive, c, msg.clock);
+                } else if msg.clock[0] == self.phase as u64 {
+                    self.replies += 1;
+                    if self.replies == 2 {
+                        self.phase += 1;
+                        self.probe();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(alg: Election, ranks: &[u64]) -> Vec<ElectionNode> {
+        let addrs: Vec<SocketAddr> = (0..ranks.len())
+            .map(|i| format!("127.0.0.1:{}", 9000 + i).parse().unwrap())
+            .collect();
+        (0..ranks.len())
+            .map(|i| ElectionNode::new(i, alg, addrs.clone()).with_ranks(ranks.to_vec()))
+            .collect()
+    }
+
+    fn msg(from: usize, typ: MessageType, c: usize, clock: Vec<u64>) -> Message {
+        Message::new_rc(from as u128, typ, c as u128).with_clock(clock)
+    }
+
+    /// What the node sent since last asked: to whom, what, and for whom
+    fn sent(node: &mut ElectionNode) -> Vec<(usize, MessageType, u128)> {
+        node.outbox
+            .drain(..)
+            .map(|(to, m)| (to, m.typ, m.ts))
+            .collect()
+    }
+
+    /// Runs an election in which everyone stands, delivering messages in
+    /// the order they are sent. Bully deadlines expire once nothing is in
+    /// flight.
+    fn elect(alg: Election, ranks: &[u64]) -> Vec<Option<usize>> {
+        let mut nodes = nodes(alg, ranks);
+        let mut queue = std::collections::VecDeque::new();
+        for x in nodes.iter_mut() {
+            x.stand();
+        }
+        loop {
+            for x in nodes.iter_mut() {
+                let from = x.id;
+                queue.extend(x.outbox.drain(..).map(|(to, m)| (from, to, m)));
+            }
+            if let Some((_, to, m)) = queue.pop_front() {
+                nodes[to].handle(m);
+            } else if let Some(x) = nodes
+                .iter_mut()
+                .find(|x| x.leader.is_none() && x.deadline.is_some())
+            {
+                x.expire();
+            } else {
+                break;
+            }
+        }
+        nodes.iter().map(|x| x.leader).collect()
+    }
+
+    #[test]
+    fn highest_rank_wins() {
+        let ranks = [2, 0, 4, 1, 3];
+        for alg in [
+            Election::Bully,
+            Election::ChangRoberts,
+            Election::HirschbergSinclair,
+        ] {
+            assert_eq!(elect(alg, &ranks), [Some(2); 5], "{}", alg);
+        }
+    }
+
+    #[test]
+    fn chang_roberts_swallows_lower_candidates() {
+        let mut x = nodes(Election::ChangRoberts, &[0, 1, 2]).remove(1);
+        x.stand();
+        assert_eq!(sent(&mut x), [(2, MessageType::Elect, 1)]);
+
+        // Already standing, so a lower candidate goes no further
+        x.handle(msg(0, MessageType::Elect, 0, vec![]));
+        assert!(sent(&mut x).is_empty());
+
+        // A higher one is passed on
+        x.handle(msg(0, MessageType::Elect, 2, vec![]));
+        assert_eq!(sent(&mut x), [(2, MessageType::Elect, 2)]);
+
+        // Its own number coming round means it won
+        x.handle(msg(0, MessageType::Elect, 1, vec![]));
+        assert_eq!(x.leader, Some(1));
+        assert_eq!(sent(&mut x), [(2, MessageType::Leader, 1)]);
+    }
+
+    #[test]
+    fn hirschberg_sinclair_needs_both_replies() {
+        let mut x = nodes(Election::HirschbergSinclair, &[3, 0, 1, 2]).remove(0);
+        x.stand();
+        assert_eq!(
+            sent(&mut x),
+            [(1, MessageType::Elect, 0), (3, MessageType::Elect, 0)]
+        );
+
+        // One reply is not enough to go on to phase 1
+        x.handle(msg(1, MessageType::Alive, 0, vec![0]));
+        assert!(sent(&mut x).is_empty());
+        assert_eq!(x.phase, 0);
+
+        x.handle(msg(3, MessageType::Alive, 0, vec![0]));
+        assert_eq!(x.phase, 1);
+        let probes: Vec<_> = x
+            .outbox
+            .iter()
+            .map(|(to, m)| (*to, m.clock.clone()))
+            .collect();
+        assert_eq!(probes, [(1, vec![1, 1]), (3, vec![1, 1])]);
+        sent(&mut x);
+
+        // A late reply to phase 0 does not count towards phase 1
+        x.handle(msg(1, MessageType::Alive, 0, vec![0]));
+        x.handle(msg(1, MessageType::Alive, 0, vec![1]));
+        assert_eq!(x.phase, 1);
+        assert!(sent(&mut x).is_empty());
+    }
+
+    #[test]
+    fn bully_takes_over_after_silence() {
+        let mut x = nodes(Election::Bully, &[0, 1, 2]).remove(1);
+        x.stand();
+        assert_eq!(sent(&mut x), [(2, MessageType::Elect, 1)]);
+
+        // Node 2 answers, then never announces itself, so node 1 stands again
+        x.handle(msg(2, MessageType::Alive, 2, vec![]));
+        assert!(x.answered && x.deadline.is_some());
+        x.expire();
+        assert_eq!(sent(&mut x), [(2, MessageType::Elect, 1)]);
+        assert!(!x.answered);
+
+        // This time nobody answers
+        x.expire();
+        assert_eq!(x.leader, Some(1));
+        assert_eq!(
+            sent(&mut x),
+            [(0, MessageType::Leader, 1), (2, MessageType::Leader, 1)]
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    fs::{self, File},
+    path::Path,
+    process::{Child, Command},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    analysis::RunStats,
+    config::{Algorithm, ClusterConfig},
+};
+
+/// One point of a parameter sweep
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub alg: Algorithm,
+    pub n: usize,
+    pub k: usize,
+    pub out_l: f64,
+    pub in_l: f64,
+}
+
+impl Config {
+    /// Runs every node as its own process in `dir`, with the node binaries
+    /// taken from `bins`. Nodes pick free ports, so runs can go on in
+    /// parallel. Returns `None` if a node fails or they are not all done by
+    /// `deadline`. Each node's output goes to `stdout_<i>.txt`.
+    pub fn run(&self, dir: &Path, bins: &Path, deadline: Duration) -> Option<RunStats> {
+        let log = dir.join("log").join(self.alg.dir());
+        fs::create_dir_all(&log).unwrap();
+        let mut cfg = ClusterConfig::generate(
+            self.alg,
+            self.n,
+            &["127.0.0.1".parse().unwrap()],
+            None,
+            "rendezvous.txt",
+        );
+        cfg.k = self.k;
+        cfg.out_l = self.out_l;
+        cfg.in_l = self.in_l;
+        fs::write(
+            dir.join("cluster.json"),
+            serde_json::to_string_pretty(&cfg).unwrap(),
+        )
+        .unwrap();
+
+        let mut nodes: Vec<Child> = (0..self.n)
+            .map(|i| {
+                let mut cmd = Command::new(bins.join(self.alg.bin()));
+                match self.alg {
+                    Algorithm::Rc
+                    | Algorithm::Central
+                    | Algorithm::NaimiTrehel
+                    | Algorithm::TokenRing => cmd.arg(i.to_string()),
+                    Algorithm::Maekawa => {
+                        let (a, b) = cfg.grid(i);
+                        cmd.arg(a.to_string()).arg(b.to_string())
+                    }
+                };
+                cmd.arg("--config").arg("cluster.json");
+                let out = File::create(dir.join(format!("stdout_{}.txt", i))).unwrap();
+                cmd.current_dir(dir)
+                    .stdout(out.try_clone().unwrap())
+                    .stderr(out)
+                    .spawn()
+                    .unwrap()
+            })
+            .collect();
+
+        let start = Instant::now();
+        let mut ok = true;
+        let mut left = nodes.len();
+        while left > 0 {
+            if start.elapsed() > deadline {
+                ok = false;
+                break;
+            }
+            left = 0;
+            for node in nodes.iter_mut() {
+                match node.try_wait().unwrap() {
+                    Some(status) => ok &= status.success(),
+                    None => left += 1,
+                }
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        for node in nodes.iter_mut() {
+            let _ = node.kill();
+            let _ = node.wait();
+        }
+
+        if ok {
+            Some(RunStats::new(log))
+        } else {
+            None
+        }
+    }
+}
+
+/// Mean, sample standard deviation and the half-width of the 95% confidence
+/// interval of a set of repetitions. What cannot be estimated from the
+/// repetitions there are is NaN: everything with none, the spread with one.
+#[derive(Debug, Clone, Copy)]
+pub struct Summary {
+    pub mean: f64,
+    pub stddev: f64,
+    pub ci95: f64,
+}
+
+impl Summary {
+    pub fn new(xs: &[f64]) -> Self {
+        let n = xs.len();
+        let mean = xs.iter().sum::<f64>() / n as f64;
+        if n < 2 {
+            return Self {
+                mean,
+                stddev: f64::NAN,
+                ci95: f64::NAN,
+            };
+        }
+        let var = xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+        let stddev = var.sqrt();
+        Self {
+            mean,
+            stddev,
+            ci95: t95(n - 1) * stddev / (n as f64).sqrt(),
+        }
+    }
+}
+
+/// Two-sided 95% quantile of Student's t distribution
+fn t95(df: usize) -> f64 {
+    const T: [f64; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+        2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+        2.052, 2.048, 2.045, 2.042,
+    ];
+    T.get(df - 1).copied().unwrap_or(1.960)
+}
@@ -1,46 +1,72 @@
 #![allow(dead_code)]
 
-use rand::{
-    distributions::{Distribution, Uniform},
-    rngs::ThreadRng,
+use rand::rngs::ThreadRng;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
 };
 
-use std::{fs::File, io::Read, thread, time::Duration};
+use config::ClusterConfig;
+use dist::{read_trace, Distribution};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct Params {
     n: usize,
     k: usize,
-    out_l: f64,
-    in_l: f64,
+    out_d: Distribution, // time between leaving the CS and the next request
+    in_d: Distribution,  // time spent in the CS
+    pub timeout: Option<Duration>, // heartbeat timeout, if any
+    trace: Option<Vec<(f64, f64)>>, // replayed instead of the distributions
 }
 
 impl Params {
-    pub fn new() -> Self {
-        let mut file = File::open("inp-params.txt").unwrap();
-        let mut buf = String::new();
-        file.read_to_string(&mut buf).unwrap();
-
-        let q = buf
-            .split_whitespace()
-            .map(|x| x.parse::<f64>().unwrap())
-            .collect::<Vec<f64>>();
-
-        Self {
-            n: q[0] as usize,
-            k: q[1] as usize,
-            out_l: q[2],
-            in_l: q[3],
+    /// Parameters of node `i` of a validated config. Overrides are applied in
+    /// order, so later ones win. Without them both regions are exponential
+    /// with means `out_l` and `in_l`.
+    pub fn from_config(cfg: &ClusterConfig, i: usize) -> Self {
+        let mut params = Self {
+            n: cfg.n,
+            k: cfg.k,
+            out_d: Distribution::Exponential(cfg.out_l),
+            in_d: Distribution::Exponential(cfg.in_l),
+            timeout: Some(Duration::from_millis(cfg.timeout)).filter(|t| !t.is_zero()),
+            trace: None,
+        };
+        let dist = |x: &String| Distribution::parse(x.split_whitespace()).unwrap();
+        for o in cfg.overrides.iter().filter(|o| o.applies(i)) {
+            if let Some(k) = o.k {
+                params.k = k;
+            }
+            if let Some(d) = &o.out_d {
+                params.out_d = dist(d);
+            }
+            if let Some(d) = &o.in_d {
+                params.in_d = dist(d);
+            }
+            if let Some(t) = &o.trace {
+                params.trace = Some(read_trace(t).unwrap());
+            }
         }
+        params
+    }
+
+    /// Number of CS requests to make
+    fn requests(&self) -> usize {
+        self.trace.as_ref().map_or(self.k, |x| x.len())
     }
 
-    fn sleep(&self, u: Uniform<f64>, rng: &mut ThreadRng, which: Region) {
-        let ts = -(u.sample(rng) as f64).ln()
-            * match which {
-                Region::Out => self.out_l,
-                Region::In => self.in_l,
-            } as f64;
-        thread::sleep(Duration::from_millis(ts as u64));
+    /// Sleeps before (`Out`) or during (`In`) the `i`th CS. A trace request is
+    /// sent at its recorded time after `init`, or at once if that has passed.
+    fn sleep(&self, rng: &mut ThreadRng, which: Region, i: usize, init: Instant) {
+        let ts = match (&self.trace, which) {
+            (Some(t), Region::Out) => t[i].0 - init.elapsed().as_secs_f64() * 1000.0,
+            (Some(t), Region::In) => t[i].1,
+            (None, Region::Out) => self.out_d.sample(rng),
+            (None, Region::In) => self.in_d.sample(rng),
+        };
+        // Heavy tails can draw more seconds than a `Duration` holds
+        thread::sleep(Duration::try_from_secs_f64(ts.max(0.0) / 1000.0).unwrap_or(Duration::MAX));
     }
 }
 
@@ -49,7 +75,27 @@ pub enum Region {
     In,
 }
 
+pub mod analysis;
+pub mod broadcast;
+pub mod central;
+pub mod chaos;
+pub mod clocks;
+pub mod config;
+pub mod conn;
+pub mod detector;
+pub mod dist;
+pub mod election;
+pub mod experiment;
 pub mod maekawa;
+pub mod membership;
+pub mod model;
+pub mod naimi;
+pub mod protocol;
 pub mod rc;
 pub mod request;
+pub mod snapshot;
+pub mod termination;
+pub mod token_ring;
+pub mod topology;
 pub mod utils;
+pub mod wal;
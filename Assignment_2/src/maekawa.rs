@@ -1,11 +1,11 @@
 use std::{
-    collections::{BinaryHeap, HashMap},
+    collections::{HashMap, HashSet},
     fs::File,
     io::{Read, Write},
     net::{SocketAddr, TcpListener, TcpStream},
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
     },
     thread::{self, JoinHandle},
     time::{Duration, Instant},
@@ -13,167 +13,467 @@ use std::{
 
 use either::Either::Left;
 use polling::{Event, Events, Poller};
-use rand::{distributions::Uniform, thread_rng};
+use rand::thread_rng;
 
 use crate::{
+    chaos::Chaos,
+    clocks::{channel, Channel, Clock, ClockKind},
+    conn::{Conn, ACCEPT, MIN_BACKOFF},
+    detector::Detector,
+    membership::{joiner_addr, Changes},
+    protocol::{cross, Arbiter, Grants, RequestStatus, Requester},
     request::Request,
-    utils::{get_a_stream, get_msgs, Action, LogEntry, Message, MessageType},
+    snapshot::{InFlight, Local, Recorder, Side},
+    termination::{counts, detection, Safra, Step, ROUND_TIMEOUT},
+    topology::Router,
+    utils::{epoch_micros, get_msgs, Action, LogEntry, Message, MessageType},
+    wal::{Record, Wal},
     Params, Region,
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum RequestStatus {
-    Pending,
-    Granted,
-    Inquiring,
-    Failed,
-}
-
 pub struct MaekawaNode {
-    id: (u64, u64), // grid coordinates
-    ips: HashMap<(u64, u64), SocketAddr>,
-    rx: TcpListener, // Quorum listener
+    id: (u64, u64),                              // grid coordinates
+    ips: Mutex<HashMap<(u64, u64), SocketAddr>>, // every cell of the grid
+    rx: TcpListener,                             // Quorum listener
     pub init: Instant,
     seq: AtomicU64, // lamport clock
     pub mc: AtomicU64,
+    cs_mc: AtomicU64,       // Sent and read by the requester since the last exit
+    pub term_mc: AtomicU64, // Safra tokens and terminate notices, kept out of `mc`
+    fd: Option<Detector>,
+    done: AtomicBool,
+    wal: Option<Wal>,
+    chaos: Option<Arc<Chaos>>,
+    dir: String,                         // where logs go
+    members: Mutex<HashSet<(u64, u64)>>, // cells with a node in the cluster
+    changes: Mutex<Changes<(u64, u64)>>,
+    join: bool,                     // started outside the cluster
+    leave: bool,                    // leaves once done with its requests
+    term: Mutex<Safra<(u64, u64)>>, // termination detection
+    snap: Mutex<Recorder<(u64, u64)>>,
+    snapshots: Option<Duration>, // how often we start one, if at all
+    clock: Option<Mutex<Clock>>,
+    router: Option<Arc<Router>>, // sparse topology, if any
 }
 
 impl MaekawaNode {
     pub fn new(id: (u64, u64), ips: HashMap<(u64, u64), SocketAddr>) -> Self {
+        let rx = TcpListener::bind(ips.get(&id).unwrap()).unwrap();
+        Self::from_listener(id, ips, rx)
+    }
+
+    /// A node listening on `rx`, which is already bound
+    pub fn from_listener(
+        id: (u64, u64),
+        ips: HashMap<(u64, u64), SocketAddr>,
+        rx: TcpListener,
+    ) -> Self {
         Self {
             id,
-            rx: TcpListener::bind(ips.get(&id).unwrap()).unwrap(),
-            ips,
+            rx,
+            members: Mutex::new(ips.keys().copied().collect()),
+            ips: Mutex::new(ips),
             init: Instant::now(),
             seq: 0.into(),
             mc: 0.into(),
+            cs_mc: 0.into(),
+            term_mc: 0.into(),
+            fd: None,
+            done: false.into(),
+            wal: None,
+            chaos: None,
+            dir: "log/maekawa".to_string(),
+            changes: Mutex::default(),
+            join: false,
+            leave: false,
+            term: Mutex::default(),
+            snap: Mutex::default(),
+            snapshots: None,
+            clock: None,
+            router: None,
+        }
+    }
+
+    /// Sends every message through a fault-injecting transport scripted by
+    /// `path`, and records CS intervals for the safety check. `ips` lists all
+    /// node addresses in order.
+    pub fn with_chaos(mut self, path: &str, ips: &[SocketAddr]) -> Self {
+        let me = self.ips.get_mut().unwrap()[&self.id];
+        let chaos = Arc::new(Chaos::new(me, path, ips));
+        self.fd = self.fd.map(|fd| fd.with_chaos(chaos.clone()));
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Cells kept free for nodes that join later. Quorums steer clear of
+    /// them until they do.
+    pub fn with_spares(mut self, cells: &[(u64, u64)]) -> Self {
+        let members = self.members.get_mut().unwrap();
+        for x in cells.iter().filter(|&&x| x != self.id) {
+            members.remove(x);
+        }
+        self
+    }
+
+    /// Starts outside the cluster and joins it in its own cell. Cells whose
+    /// node cannot be reached are taken to be vacant.
+    pub fn with_join(mut self) -> Self {
+        self.join = true;
+        self
+    }
+
+    /// Leaves the cluster once done with its requests, instead of serving
+    /// as an arbiter until everyone is done. Its cell becomes vacant.
+    pub fn with_leave(mut self) -> Self {
+        self.leave = true;
+        self
+    }
+
+    /// Starts a global snapshot every `period`, written to the log
+    /// directory as `snapshot_<number>_<row>_<col>.json` by every node.
+    pub fn with_snapshots(mut self, period: Duration) -> Self {
+        self.snapshots = Some(period);
+        self
+    }
+
+    /// Stamps every message and log entry with a causal clock. Nodes are
+    /// numbered by cell, row-major.
+    pub fn with_clock(mut self, kind: ClockKind) -> Self {
+        let me = self.index(self.id);
+        self.clock = Some(Mutex::new(Clock::new(kind, me)));
+        self
+    }
+
+    /// Row-major cell number, the node's place in a causal clock
+    fn index(&self, (a, b): (u64, u64)) -> usize {
+        let q = (self.ips.lock().unwrap().len() as f64).sqrt() as usize;
+        a as usize * q + b as usize
+    }
+
+    /// Only connects to neighbours in a topology, and relays for the others.
+    pub fn with_router(mut self, router: Router) -> Self {
+        self.router = Some(Arc::new(router));
+        self
+    }
+
+    pub fn with_log_dir(mut self, dir: &str) -> Self {
+        self.dir = dir.to_string();
+        self
+    }
+
+    /// Persists the clock and arbiter lock to `path`, restoring them if the
+    /// node is restarting after a crash.
+    pub fn with_wal(mut self, path: &str) -> Self {
+        let wal = Wal::open(path);
+        self.seq = wal.state.seq.into();
+        println!("Recovered: {:?}", wal.state);
+        self.wal = Some(wal);
+        self
+    }
+
+    fn persist(&self, rec: Record) {
+        if let Some(wal) = &self.wal {
+            wal.append(rec);
+        }
+    }
+
+    /// Runs a heartbeat failure detector alongside the listener.
+    pub fn with_detector(mut self, timeout: Duration) -> Self {
+        let peers = self
+            .ips
+            .get_mut()
+            .unwrap()
+            .iter()
+            .map(|(&x, &a)| (Left(x), a))
+            .collect();
+        let fd = Detector::new(Left(self.id), peers, timeout);
+        self.fd = Some(match &self.chaos {
+            Some(chaos) => fd.with_chaos(chaos.clone()),
+            None => fd,
+        });
+        self
+    }
+
+    fn suspected(&self, pid: (u64, u64)) -> bool {
+        self.fd.as_ref().is_some_and(|fd| fd.suspected(&Left(pid)))
+    }
+
+    /// Pollers wake up every heartbeat period to notice suspicions.
+    fn poll_timeout(&self) -> Option<Duration> {
+        self.fd.as_ref().map(|fd| fd.period())
+    }
+
+    /// A message from us, stamped with our clock if we keep one. `to` is the
+    /// channel it goes on, if it has one.
+    fn message(&self, typ: MessageType, ts: u128, to: Option<Channel>) -> Message {
+        let msg = Message::new_maekawa(self.id, typ, ts);
+        match &self.clock {
+            Some(clock) => msg.with_clock(clock.lock().unwrap().send(to)),
+            None => msg,
+        }
+    }
+
+    /// Takes in the clock of a message.
+    fn receive(&self, msg: &Message) {
+        if let Some(clock) = self.clock.as_ref().filter(|_| !msg.clock.is_empty()) {
+            let from = self.index(msg.id.expect_left(""));
+            clock.lock().unwrap().receive(from, &msg.clock);
         }
     }
 
-    /// Sends messages
-    fn send(&self, mut stream: &TcpStream, typ: MessageType) {
+    /// Sends messages on an incoming stream. A broken stream is left for the
+    /// poller to notice; the requester on the other end will resend.
+    fn send(&self, mut stream: &TcpStream, to: (u64, u64), typ: MessageType) {
+        self.mc.fetch_add(1, Ordering::SeqCst);
+        let seq = self.seq.load(Ordering::SeqCst) as u128;
+        let msg = self.message(typ, seq, channel(stream));
+        let _ = match &self.chaos {
+            Some(chaos) => chaos
+                .write(stream, self.ips.lock().unwrap()[&to], msg)
+                .map(|_| ()),
+            None => stream
+                .write_all(&Vec::from(msg))
+                .and_then(|_| stream.flush()),
+        };
+    }
+
+    /// Sends messages to a quorum member with a given timestamp
+    fn send_at(&self, (to, conn): &mut ((u64, u64), Conn), typ: MessageType, ts: u128) {
         self.mc.fetch_add(1, Ordering::SeqCst);
-        stream
-            .write_all(&*<Message as Into<Vec<u8>>>::into(Message::new_maekawa(
-                self.id,
-                typ,
-                self.seq.load(Ordering::SeqCst) as u128,
-            )))
-            .unwrap();
-        stream.flush().unwrap();
+        self.cs_mc.fetch_add(1, Ordering::SeqCst);
+        let msg = self.message(typ, ts, conn.stream().and_then(channel));
+        let copies = conn.send(msg);
+        if counts(typ) {
+            self.term.lock().unwrap().sent(*to, copies);
+        }
+    }
+
+    /// Sends messages to a quorum member
+    fn send_to(&self, stream: &mut ((u64, u64), Conn), typ: MessageType) {
+        self.send_at(stream, typ, self.seq.load(Ordering::SeqCst) as u128);
     }
 
     /// Generates log entries
     fn log(&self, out: &mut Vec<LogEntry>, act: Action) {
         out.push(LogEntry {
             pid: Left(self.id),
-            ts: self.init.elapsed().as_millis(),
+            ts: self.init.elapsed().as_micros(),
             act,
+            clock: self.clock.as_ref().map(|x| x.lock().unwrap().tick()),
         });
     }
 
-    fn get_requester_poller(&self, streams: &Vec<TcpStream>) -> Poller {
-        let poller = Poller::new().unwrap();
-        streams.iter().enumerate().for_each(|(i, x)| unsafe {
-            poller.add(x, Event::readable(i)).unwrap();
+    /// The listener wakes it up when the membership changes.
+    fn get_requester_poller(&self, streams: &[((u64, u64), Conn)]) -> Arc<Poller> {
+        let poller = Arc::new(Poller::new().unwrap());
+        streams.iter().enumerate().for_each(|(i, (_, x))| unsafe {
+            if let Some(x) = x.stream() {
+                poller.add(x, Event::readable(i)).unwrap();
+            }
         });
+        self.changes.lock().unwrap().set_waker(poller.clone());
         poller
     }
 
-    /// Send request to all endpoints in the quorum
-    fn request_cs(&self, streams: &Vec<TcpStream>) {
-        for stream in streams.iter() {
-            self.send(stream, MessageType::Request);
+    /// Reads whatever a quorum member sent. On EOF the connection is dropped
+    /// from the poller and marked down.
+    fn read_from(&self, conn: &mut Conn, poller: &Poller, key: usize) -> Vec<Message> {
+        let Some(mut stream) = conn.stream() else {
+            return vec![];
+        };
+        let mut buf = [0; 1024];
+        match stream.read(&mut buf) {
+            Ok(ref b) if *b > 0 => {
+                poller.modify(stream, Event::readable(key)).unwrap();
+                let msgs = get_msgs(conn.inbox(), &buf[..*b]);
+                self.cs_mc.fetch_add(msgs.len() as u64, Ordering::SeqCst);
+                for msg in msgs.iter() {
+                    self.receive(msg);
+                }
+                msgs
+            }
+            _ => {
+                println!("Lost connection to quorum member {}", key);
+                poller.delete(stream).unwrap();
+                conn.down();
+                self.snap.lock().unwrap().closed(Side::Requester, key);
+                vec![]
+            }
+        }
+    }
+
+    /// Retry dropped connections. Returns the members we got back.
+    fn reconnect(&self, streams: &mut [((u64, u64), Conn)], poller: &Poller) -> Vec<usize> {
+        let mut out = vec![];
+        for (i, x) in streams.iter_mut().enumerate() {
+            let (pid, conn) = (x.0, &mut x.1);
+            if !self.suspected(pid) && conn.reconnect() {
+                println!("Reconnected to {:?}", pid);
+                unsafe {
+                    poller
+                        .add(conn.stream().unwrap(), Event::readable(i))
+                        .unwrap()
+                };
+                // The member may have restarted, so anything it knew about us is
+                // stale. Release whatever it thinks we hold; requests are resent
+                // by the caller.
+                conn.retain(|x| x.typ == MessageType::Leave);
+                self.term.lock().unwrap().reset(pid);
+                self.send_to(x, MessageType::Release);
+                x.1.flush();
+                out.push(i);
+            }
+        }
+        out
+    }
+
+    /// Wakes up often enough to retry dropped connections.
+    fn requester_timeout(&self, streams: &[((u64, u64), Conn)]) -> Option<Duration> {
+        if streams.iter().any(|x| !x.1.is_up() && !x.1.is_closed()) {
+            Some(MIN_BACKOFF)
+        } else {
+            self.poll_timeout()
+        }
+    }
+
+    /// Pick a quorum: the row and column through some centre node.
+    /// Any two such crosses intersect, so we may centre on any node whose
+    /// cross has no suspected or vacant members, preferring ourselves.
+    ///
+    /// Changing crosses as nodes come and go stays safe: two crosses meet in
+    /// cells that are in both, so cells that were occupied when the older
+    /// one was picked.
+    fn get_quorum(&self, streams: &[((u64, u64), Conn)], q: usize) -> Vec<usize> {
+        let cross = |c| cross(c, q);
+        let me = self.id.0 as usize * q + self.id.1 as usize;
+        (0..q * q)
+            .map(|d| cross((me + d) % (q * q)))
+            .find(|c| c.iter().all(|&i| !self.unusable(streams, i)))
+            .unwrap_or_else(|| cross(me))
+    }
+
+    /// Crashed, or not in the cluster as far as the requester knows
+    fn unusable(&self, streams: &[((u64, u64), Conn)], i: usize) -> bool {
+        self.suspected(streams[i].0) || streams[i].1.is_closed()
+    }
+
+    /// Send request to all endpoints in the quorum, returns the request timestamp
+    fn request_cs(&self, streams: &mut [((u64, u64), Conn)], quorum: &[usize]) -> u128 {
+        let ts = self.seq.load(Ordering::SeqCst) as u128;
+        self.persist(Record::Clock(ts as u64 + 1));
+        for &i in quorum.iter() {
+            self.send_at(&mut streams[i], MessageType::Request, ts);
         }
         self.seq.fetch_add(1, Ordering::SeqCst);
+        ts
+    }
+
+    /// Replace a quorum with suspected or vacant members by one without.
+    /// Live nodes that are dropped get a release, new ones get our request.
+    fn reroute(
+        &self,
+        streams: &mut [((u64, u64), Conn)],
+        q: usize,
+        ts: u128,
+        quorum: &mut Vec<usize>,
+        req: &mut Requester,
+    ) {
+        let next = self.get_quorum(streams, q);
+        for &i in quorum.iter().filter(|i| !next.contains(i)) {
+            req.status.remove(&i);
+            if !self.suspected(streams[i].0) {
+                self.send_to(&mut streams[i], MessageType::Release);
+                req.released.insert(i);
+            }
+        }
+        for &i in next.iter().filter(|i| !quorum.contains(i)) {
+            req.status.insert(i, RequestStatus::Pending);
+            req.released.remove(&i);
+            self.send_at(&mut streams[i], MessageType::Request, ts);
+        }
+        println!("Rerouted quorum: {:?}", next);
+        *quorum = next;
     }
 
     /// Wait for quorum replies
-    fn enter_cs(&self, streams: &mut Vec<TcpStream>, poller: &Poller, q: usize) -> Vec<LogEntry> {
+    fn enter_cs(
+        &self,
+        streams: &mut [((u64, u64), Conn)],
+        poller: &Poller,
+        q: usize,
+        quorum: &mut Vec<usize>,
+    ) -> Vec<LogEntry> {
         // Send a request to all the nodes in the quorum
-        self.request_cs(streams);
+        self.catch_up(streams, poller, false);
+        *quorum = self.get_quorum(streams, q);
+        let ts = self.request_cs(streams, quorum);
         println!("Request sent");
 
         let mut out = vec![];
-        let mut status = vec![RequestStatus::Pending; 2 * q - 1];
-        
+        self.log(&mut out, Action::Ask(ts));
+        let mut req = Requester::new(quorum);
+
         // wait for the quorum to reply
         let mut events = Events::new();
-        while status.contains(&RequestStatus::Pending) || status.contains(&RequestStatus::Failed) {
+        while req.waiting() {
             events.clear();
-            poller.wait(&mut events, None).unwrap();
+            poller
+                .wait(&mut events, self.requester_timeout(streams))
+                .unwrap();
+
+            // Release and forget nodes that left, then route around them
+            self.catch_up(streams, poller, false);
+            if quorum.iter().any(|&i| self.unusable(streams, i)) {
+                self.reroute(streams, q, ts, quorum, &mut req);
+            }
+
+            // Start over with members we reconnected to
+            for i in self.reconnect(streams, poller) {
+                req.released.remove(&i);
+                if let Some(stat) = req.status.get_mut(&i) {
+                    *stat = RequestStatus::Pending;
+                    self.send_at(&mut streams[i], MessageType::Request, ts);
+                }
+            }
 
             for ev in events.iter() {
-                let mut stream = streams.get(ev.key).unwrap();
-                let mut buf = [0; 1024];
-                match stream.read(&mut buf) {
-                    Ok(ref b) if *b > 0 => {
-                        // let msg = Message::from(&buf[..*b]);
-                        let msgs = get_msgs(&mut buf, b);
-                        for msg in msgs {
-                            match msg.typ {
-                                MessageType::Reply => {
-                                    println!("Reply: {:#?}", msg);
-                                    if status[ev.key] == RequestStatus::Granted {
-                                        panic!("Duplicate reply");
-                                        // continue;
-                                    }
-                                    status[ev.key] = RequestStatus::Granted;
-                                    self.log(&mut out, Action::Reply(msg.id));
-                                }
-                                MessageType::Failed => {
-                                    println!("Failed: {:#?}", msg);
-                                    if status[ev.key] == RequestStatus::Failed {
-                                        panic!("Duplicate fail");
-                                        // continue;
-                                    }
-                                    status[ev.key] = RequestStatus::Failed;
-                                    for (ss, stat) in streams
-                                        .iter()
-                                        .zip(status.iter_mut())
-                                        .filter(|x| x.1 == &RequestStatus::Inquiring)
-                                    {
-                                        self.send(ss, MessageType::Yield);
-                                        *stat = RequestStatus::Pending;
-                                    }
-                                }
-                                MessageType::Inquire => {
-                                    println!("Inquire received: {:#?}.", msg);
-                                    if status[ev.key] == RequestStatus::Failed {
-                                        // panic!("Inquire from failed.");
-                                        continue;
-                                    }
-                                    if status[ev.key] == RequestStatus::Pending {
-                                        // panic!("Orphan inquire.");
-                                        continue;
-                                    }
-                                    if status[ev.key] == RequestStatus::Inquiring {
-                                        panic!("Duplicate inquire.");
-                                        // continue;
-                                    }
-                                    if status.contains(&RequestStatus::Failed)
-                                        && status[ev.key] != RequestStatus::Inquiring
-                                    {
-                                        self.send(stream, MessageType::Yield);
-                                        println!("Yield sent {:#?}", msg);
-                                        status[ev.key] = RequestStatus::Pending;
-                                    } else {
-                                        status[ev.key] = RequestStatus::Inquiring;
-                                    }
-                                }
-                                _ => {
-                                    panic!("Unexpected message")
-                                }
-                            }
-                        }
+                let msgs = self.read_from(&mut streams[ev.key].1, poller, ev.key);
+                for msg in msgs {
+                    println!("{:?}: {:#?}", msg.typ, msg);
+                    if msg.typ == MessageType::Marker {
+                        let from = req
+                            .status
+                            .iter()
+                            .filter(|x| {
+                                matches!(x.1, RequestStatus::Pending | RequestStatus::Failed)
+                            })
+                            .map(|x| streams[*x.0].0)
+                            .collect();
+                        let state = Local::Waiting { ts, from };
+                        self.snapshot_requester(streams, ev.key, msg.ts as u64, state);
+                        continue;
                     }
-                    _ => {
-                        thread::sleep(Duration::from_micros(10));
+                    self.snap.lock().unwrap().on_message(
+                        Side::Requester,
+                        ev.key,
+                        InFlight {
+                            from: streams[ev.key].0,
+                            typ: msg.typ,
+                            ts: msg.ts,
+                        },
+                    );
+                    if msg.typ == MessageType::Reply && req.status.contains_key(&ev.key) {
+                        self.log(&mut out, Action::Reply(msg.id));
+                    }
+                    let sends = req
+                        .on_message(ev.key, msg.typ)
+                        .unwrap_or_else(|e| panic!("{}", e));
+                    for (i, typ) in sends {
+                        self.send_to(&mut streams[i], typ);
                     }
                 }
-                buf.fill(0);
-                poller.modify(stream, Event::readable(ev.key)).unwrap();
             }
+            self.save_snapshot();
         }
 
         println!("CS acquired");
@@ -182,79 +482,212 @@ impl MaekawaNode {
     }
 
     /// Send release to all endpoints in the quorum
-    fn exit_cs(&self, streams: &Vec<TcpStream>) {
-        for stream in streams.iter() {
-            self.send(stream, MessageType::Release);
+    fn exit_cs(&self, streams: &mut [((u64, u64), Conn)], quorum: &[usize]) {
+        for &i in quorum.iter() {
+            self.send_to(&mut streams[i], MessageType::Release);
         }
         println!("CS released");
     }
 
-    /// Get the streams for the quorum
-    fn get_streams(&self, q: usize) -> Vec<TcpStream> {
-        vec![
-            vec![get_a_stream(self.ips.get(&(self.id.0, self.id.1)).unwrap())],
-            (0..q)
-                .filter(|&i| i != self.id.0 as usize)
-                .map(|i| {
-                    get_a_stream(
-                        self.ips
-                            .get(&(i as u64, self.id.1)).unwrap()
-                            // .expect(&format!("{:#?}", (i as u64, self.id.1))),
-                    )
-                })
-                .collect::<Vec<TcpStream>>(),
-            (0..q)
-                .filter(|&i| i != self.id.1 as usize)
-                .map(|i| {
-                    get_a_stream(
-                        self.ips
-                            .get(&(self.id.0, i as u64)).unwrap()
-                            // .expect(&format!("{:#?}", (self.id.0, i as u64))),
-                    )
-                })
-                .collect::<Vec<TcpStream>>(),
-        ]
-        .into_iter()
-        .flatten()
-        .collect::<Vec<TcpStream>>()
-    }
-
-    /// Indicates algorithm termination
-    fn terminate(&self, streams: &mut Vec<TcpStream>) {
-        for stream in streams.iter_mut() {
-            self.send(stream, MessageType::Terminate);
-            stream.flush().unwrap();
+    /// Get the streams for the whole grid, indexed row-major, so that any
+    /// quorum can be reached. Vacant cells get a closed connection. A
+    /// newcomer connects to whoever is up and announces itself first.
+    fn get_streams(&self, q: usize) -> Vec<((u64, u64), Conn)> {
+        let ips = self.ips.lock().unwrap().clone();
+        let mut members = self.members.lock().unwrap();
+        let mut out: Vec<_> = (0..q as u64)
+            .flat_map(|a| (0..q as u64).map(move |b| (a, b)))
+            .map(|id| {
+                let addr = ips[&id];
+                let conn = if self.join {
+                    Conn::try_new(addr, self.router.clone())
+                } else if members.contains(&id) {
+                    Some(Conn::new(addr, self.router.clone()))
+                } else {
+                    None
+                };
+                if conn.is_some() {
+                    members.insert(id);
+                } else {
+                    members.remove(&id);
+                }
+                let conn = conn.unwrap_or_else(|| Conn::closed(addr));
+                (id, conn.with_chaos(self.chaos.clone()))
+            })
+            .collect();
+        if self.join {
+            let port = ips[&self.id].port() as u128;
+            for x in out.iter_mut() {
+                self.send_at(x, MessageType::Join, port);
+            }
+        }
+        out
+    }
+
+    /// Acts on the joins and leaves the listener has seen: newcomers get a
+    /// connection, and departed nodes a release of anything they granted us
+    /// and a `Leave` after our last message to them.
+    fn catch_up(&self, streams: &mut [((u64, u64), Conn)], poller: &Poller, done: bool) {
+        let (joined, left) = self.changes.lock().unwrap().take(done);
+        let q = (streams.len() as f64).sqrt() as usize;
+        let cell = |(a, b): (u64, u64)| a as usize * q + b as usize;
+        for (pid, addr) in joined {
+            let i = cell(pid);
+            if !streams[i].1.is_closed() {
+                continue;
+            }
+            // Gone again already
+            let Some(conn) = Conn::try_new(addr, self.router.clone()) else {
+                continue;
+            };
+            unsafe {
+                poller
+                    .add(conn.stream().unwrap(), Event::readable(i))
+                    .unwrap()
+            };
+            streams[i].1 = conn.with_chaos(self.chaos.clone());
+            self.term.lock().unwrap().reset(pid);
+        }
+        for pid in left {
+            let i = cell(pid);
+            self.send_to(&mut streams[i], MessageType::Release);
+            self.send_to(&mut streams[i], MessageType::Leave);
+            self.snap.lock().unwrap().closed(Side::Requester, i);
+            let conn = &mut streams[i].1;
+            if let Some(stream) = conn.stream() {
+                poller.delete(stream).unwrap();
+            }
+            conn.close();
+        }
+    }
+
+    /// Done requesting: tells everyone if we are leaving, and lets the
+    /// termination token through. From here on the listener answers
+    /// newcomers.
+    fn terminate(&self, streams: &mut [((u64, u64), Conn)], poller: &Poller) {
+        self.catch_up(streams, poller, true);
+        if self.leave {
+            for x in streams.iter_mut() {
+                if !self.suspected(x.0) {
+                    self.send_to(x, MessageType::Leave);
+                }
+            }
+        }
+        self.term.lock().unwrap().passive();
+        self.pass_token();
+    }
+
+    /// Live members in row-major order, the ring the termination token goes
+    /// round. A leaving node is not on it.
+    fn ring(&self) -> Vec<(u64, u64)> {
+        let mut out: Vec<_> = self
+            .members
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .filter(|&x| !self.suspected(x))
+            .filter(|&x| !self.leave || x != self.id)
+            .collect();
+        out.sort();
+        out
+    }
+
+    /// Passes the termination token on if we hold it, or tells everyone the
+    /// run is over.
+    fn pass_token(&self) {
+        let ring = self.ring();
+        let step = self.term.lock().unwrap().step(self.id, &ring);
+        match step {
+            Step::Pass(to, token) => self.tell(to, MessageType::Token, token.into()),
+            Step::Announce => {
+                let ts = self.seq.load(Ordering::SeqCst) as u128;
+                for &pid in ring.iter() {
+                    self.tell(pid, MessageType::Terminate, ts);
+                }
+            }
+            Step::Idle => {}
+        }
+    }
+
+    /// A node that left is done once every member has answered its `Leave`,
+    /// the others once termination is detected.
+    fn finished(&self) -> bool {
+        let members = self.members.lock().unwrap();
+        if self.leave && !members.contains(&self.id) {
+            members.iter().all(|&x| self.suspected(x))
+        } else {
+            self.term.lock().unwrap().is_done()
+        }
+    }
+
+    /// Wakes up often enough to resend a lost token.
+    fn listener_timeout(&self) -> Option<Duration> {
+        Some(
+            self.poll_timeout()
+                .map_or(ROUND_TIMEOUT, |x| x.min(ROUND_TIMEOUT)),
+        )
+    }
+
+    /// Deliver whatever is still queued for members that are down.
+    fn drain(&self, streams: &mut [((u64, u64), Conn)], poller: &Poller) {
+        let mut events = Events::new();
+        while streams
+            .iter()
+            .any(|(pid, conn)| conn.has_queued() && !self.suspected(*pid))
+        {
+            events.clear();
+            poller.wait(&mut events, Some(MIN_BACKOFF)).unwrap();
+            for ev in events.iter() {
+                // Nothing left to act on, but EOF still needs noticing
+                self.read_from(&mut streams[ev.key].1, poller, ev.key);
+            }
+            self.reconnect(streams, poller);
         }
     }
 
     /// Simulate CS requests
     fn requester_thread(&self, params: &Params, q: usize) -> Vec<LogEntry> {
         let mut rng = thread_rng();
-        let u = Uniform::new(0.0, 1.0);
         let mut out = vec![];
 
-        // All the nodes in the quorum
+        // All the nodes in the grid
         let mut streams = self.get_streams(q);
         let poller = self.get_requester_poller(&streams);
+        let mut quorum = vec![];
         // dbg!(&streams);
         println!("Streams: {:#?}", streams);
 
         // Send a request to all the nodes in the quorum
-        for _i in 0..params.k {
+        for i in 0..params.requests() {
             self.log(&mut out, Action::Internal);
-            params.sleep(u, &mut rng, Region::Out);
+            params.sleep(&mut rng, Region::Out, i, self.init);
 
-            let replies = self.enter_cs(&mut streams, &poller, q);
+            let replies = self.enter_cs(&mut streams, &poller, q, &mut quorum);
             out.extend(replies);
 
             self.log(&mut out, Action::Acquire);
-            params.sleep(u, &mut rng, Region::In);
+            if let Some(chaos) = &self.chaos {
+                chaos.enter();
+            }
+            params.sleep(&mut rng, Region::In, i, self.init);
 
-            self.exit_cs(&mut streams);
+            if let Some(chaos) = &self.chaos {
+                chaos.exit();
+            }
+            // Before anyone hears of it, so the exit happens before the next entry
             self.log(&mut out, Action::Exit);
+            self.exit_cs(&mut streams, &quorum);
+            self.log(
+                &mut out,
+                Action::Messages(self.cs_mc.swap(0, Ordering::SeqCst)),
+            );
         }
 
-        self.terminate(&mut streams);
+        self.terminate(&mut streams, &poller);
+        self.drain(&mut streams, &poller);
+        self.snap.lock().unwrap().requester_done();
+        self.save_snapshot();
         println!("Node {:?} sent terminate.", self.id);
 
         out
@@ -264,206 +697,358 @@ impl MaekawaNode {
         thread::spawn(move || self.requester_thread(&params, q))
     }
 
-    fn get_listener_poller(&self, q: usize) -> (Poller, Vec<TcpStream>) {
-        let mut streams = vec![];
-
+    /// Connections are accepted as they come, so restarted peers can rejoin.
+    fn get_listener_poller(&self) -> Poller {
         let poller = Poller::new().unwrap();
-        for stream in self.rx.incoming() {
-            println!("Incoming : {:#?}", stream);
-            match stream {
-                Ok(x) => {
-                    unsafe { poller.add(&x, Event::readable(streams.len())).unwrap() };
-                    streams.push(x);
-                }
-                Err(_) => {
-                    // thread::sleep(Duration::from_micros(10));
-                }
-            }
+        unsafe { poller.add(&self.rx, Event::readable(ACCEPT)).unwrap() };
+        poller
+    }
 
-            // All connections acquired
-            if streams.len() == 2 * q - 1 {
-                break;
+    /// Persist the lock if a protocol step moved it, then send what the step
+    /// asked for.
+    fn answer(
+        &self,
+        arb: &Arbiter,
+        before: Option<((u64, u64), u128)>,
+        grants: Grants,
+        streams: &[TcpStream],
+        out: &mut Vec<LogEntry>,
+    ) {
+        if arb.holder() != before {
+            self.persist(Record::Lock(arb.holder()));
+        }
+        for (to, typ) in grants {
+            match typ {
+                MessageType::Reply => {
+                    println!("Grant sent: {:#?}", to.pid);
+                    self.log(out, Action::Grant(Left(to.pid)));
+                }
+                MessageType::Inquire => println!("Inquire sent: {:#?}", to.pid),
+                _ => {}
             }
+            self.send(&streams[to.stream], to.pid, typ);
         }
-
-        (poller, streams)
     }
 
     /// Listen for incoming messages
-    fn listen(&self, poller: Poller, streams: &Vec<TcpStream>, q: usize) -> Vec<LogEntry> {
-        let mut req = BinaryHeap::new();
-        let mut locked: Option<Request> = None;
-        let mut inq = false; // Have we sent an inquire message already?
+    fn listen(&self, poller: Poller) -> Vec<LogEntry> {
+        let mut streams = vec![];
+        let mut open = HashSet::new(); // streams not at EOF yet
+        let mut pending = vec![]; // part of a message, per stream
+        let mut arb = Arbiter {
+            phantom: self.wal.as_ref().and_then(|w| w.state.locked),
+            ..Default::default()
+        };
         let mut out = vec![];
-        let mut term = 0;
         let mut events = Events::new();
+        let suspected = |x| self.suspected(x);
 
-        while term < 2 * q - 1 {
+        while !self.finished() {
             events.clear();
-            poller.wait(&mut events, None).unwrap();
+            poller.wait(&mut events, self.listener_timeout()).unwrap();
+
+            let before = arb.holder();
+            if let Some(t) = before.filter(|t| self.suspected(t.0)) {
+                println!("Reclaiming lock from {:?}", t.0);
+            }
+            let grants = arb.reclaim(suspected);
+            self.answer(&arb, before, grants, &streams, &mut out);
+
             for ev in events.iter() {
-                let mut stream = streams.get(ev.key).unwrap();
-                let mut buf = [0; 128];
-                match stream.read(&mut buf) {
-                    Ok(ref b) if *b > 0 => {
-                        let msgs = get_msgs(&mut buf, b);
-                        buf.fill(0);
-                        for msg in msgs {
-                            match msg.typ {
-                                MessageType::Request => {
-                                    // dbg!(&msg);
-                                    let new_req = Request::new(
-                                        msg.ts,
-                                        msg.id.expect_left(""),
-                                        stream,
-                                    );
-                                    // if req.iter().any(|x| *x == new_req) {
-                                    //     panic!("Duplicate request: {:#?}, {:#?}.", *x, msg);
-                                    //     // continue;
-                                    // }
-                                    req.iter().for_each(|x| {
-                                        if *x == new_req {
-                                            panic!("Duplicate request: {:#?}, {:#?}.", *x, new_req);
-                                            // continue;
-                                        }
-                                    });
-                                    self.log(&mut out, Action::Query(msg.id));
-                                    // Lamport clock
-                                    if msg.ts > self.seq.load(Ordering::SeqCst) as u128 {
-                                        self.seq.store((msg.ts + 1) as u64, Ordering::SeqCst);
-                                    }
-                                    if let Some(ref t) = locked {
-                                        if *t == new_req {
-                                            panic!("Duplicate request: {:#?} {:#?}.", *t, new_req);
-                                            // continue;
-                                        }
-                                        if msg.ts == t.ts && msg.id.expect_left("") < t.pid {
-                                            self.send(stream, MessageType::Failed);
-                                        } else if msg.ts > t.ts
-                                            || req.iter().fold(false, |acc, x: &Request| {
-                                                acc || x.ts < msg.ts
-                                            })
-                                        {
-                                            // If the request is younger than the locked request
-                                            self.send(stream, MessageType::Failed);
-                                        } else if !inq {
-                                            self.send(t.stream, MessageType::Inquire);
-                                            inq = true;
-                                        }
-
-                                        // Need to put in queue anyway.
-                                        req.push(new_req);
-                                        dbg!(&req);
-                                        println!("Request queued: {:#?}", msg.id);
-                                    } else {
-                                        locked = Some(new_req);
-                                        self.log(&mut out, Action::Grant(msg.id));
-                                        inq = false;
-                                        self.send(stream, MessageType::Reply);
-                                        println!("Grant sent {:#?}", msg.id);
-                                    }
-                                }
-                                MessageType::Release => {
-                                    self.log(&mut out, Action::Release(msg.id));
-                                    if locked.is_none() {
-                                        // dbg!(msg);
-                                        panic!("Tf?");
-                                    }
-                                    inq = false;
-                                    if req.is_empty() {
-                                        locked = None;
-                                    } else {
-                                        let next = req.pop().unwrap();
-                                        println!("Grant sent: {:#?}", next.pid);
-                                        self.log(&mut out, Action::Grant(msg.id));
-                                        self.send(next.stream, MessageType::Reply);
-                                        locked = Some(next);
-                                    }
-                                }
-                                MessageType::Yield => {
-                                    dbg!(&req);
-                                    dbg!(&locked);
-                                    dbg!(&msg);
-                                    inq = false;
-                                    match locked {
-                                        Some(ref t) if t.pid == msg.id.expect_left("") => {
-                                            
-                                            if req.is_empty() {
-                                                if msg.ts < self.seq.load(Ordering::SeqCst) as u128
-                                                {
-                                                    dbg!(msg);
-                                                    panic!("Bad yield to {:#?}.", self.id);
-                                                }
-                                            } else {
-                                                println!("Yield received: {:#?}", msg.id);
-                                                let next = req.pop().unwrap();
-                                                req.push(locked.take().unwrap());
-                                                println!("Grant sent: {:#?}", next.pid);
-                                                self.log(&mut out, Action::Grant(msg.id));
-                                                self.send(next.stream, MessageType::Reply);
-                                                locked = Some(next);
-                                            }
-                                        }
-                                        None => {
-                                            panic!("Yielding when locked empty.");
-                                        }
-                                        _ => {
-                                            if msg.ts < locked.unwrap().ts {
-                                                dbg!(msg);
-                                                dbg!(&locked);
-                                                panic!("Weird yield to {:#?}.", self.id);
-                                            }
-                                        } // Old yield?
-                                    }
-                                }
-                                MessageType::Terminate => {
-                                    term += 1;
-                                    println!(
-                                        "Node {:?} received terminate from {:?}.",
-                                        self.id,
-                                        msg.id.expect_left("")
-                                    );
-                                }
-                                _ => {
-                                    panic!("Unexpected message")
-                                }
-                            }
-                        }
+                if ev.key == ACCEPT {
+                    let accepted = self.rx.accept().ok().and_then(|(x, _)| match &self.router {
+                        Some(router) => router.accept(x),
+                        None => Some(x),
+                    });
+                    if let Some(x) = accepted {
+                        println!("Incoming : {:#?}", x);
+                        unsafe { poller.add(&x, Event::readable(streams.len())).unwrap() };
+                        open.insert(streams.len());
+                        pending.push(vec![]);
+                        streams.push(x);
                     }
+                    poller.modify(&self.rx, Event::readable(ACCEPT)).unwrap();
+                    continue;
+                }
+
+                let mut stream = &streams[ev.key];
+                let mut buf = [0; 128];
+                let msgs = match stream.read(&mut buf) {
+                    Ok(ref b) if *b > 0 => get_msgs(&mut pending[ev.key], &buf[..*b]),
                     _ => {
-                        // thread::sleep(Duration::from_micros(10))
+                        // The requester will reconnect and resend if it still cares.
+                        poller.delete(stream).unwrap();
+                        arb.on_disconnect(ev.key);
+                        open.remove(&ev.key);
+                        self.snap.lock().unwrap().closed(Side::Listener, ev.key);
+                        continue;
                     }
-                }
-                // Clear buffer
-                buf.fill(0);
+                };
                 // Reset poller
                 poller.modify(stream, Event::readable(ev.key)).unwrap();
+
+                for msg in msgs {
+                    let pid = msg.id.expect_left("");
+                    self.receive(&msg);
+                    if counts(msg.typ) {
+                        self.term.lock().unwrap().received(pid, ev.key);
+                    }
+                    if msg.typ != MessageType::Marker {
+                        self.snap.lock().unwrap().on_message(
+                            Side::Listener,
+                            ev.key,
+                            InFlight {
+                                from: pid,
+                                typ: msg.typ,
+                                ts: msg.ts,
+                            },
+                        );
+                    }
+                    let before = arb.holder();
+                    let grants = match msg.typ {
+                        MessageType::Request => {
+                            self.log(&mut out, Action::Query(msg.id));
+                            // Lamport clock
+                            if msg.ts > self.seq.load(Ordering::SeqCst) as u128 {
+                                self.persist(Record::Clock((msg.ts + 1) as u64));
+                                self.seq.store((msg.ts + 1) as u64, Ordering::SeqCst);
+                            }
+                            let grants = arb
+                                .on_request(Request::new(msg.ts, pid, ev.key))
+                                .unwrap_or_else(|e| panic!("{}", e));
+                            if arb.req.iter().any(|x| x.pid == pid) {
+                                println!("Request queued: {:#?}", msg.id);
+                            }
+                            grants
+                        }
+                        MessageType::Release => {
+                            self.log(&mut out, Action::Release(msg.id));
+                            arb.on_release(pid, suspected)
+                        }
+                        MessageType::Yield => {
+                            dbg!(&arb);
+                            dbg!(&msg);
+                            let seq = self.seq.load(Ordering::SeqCst) as u128;
+                            arb.on_yield(pid, msg.ts, seq, suspected)
+                                .unwrap_or_else(|e| panic!("{} to {:#?}.", e, self.id))
+                        }
+                        MessageType::Terminate => {
+                            println!("Node {:?} received terminate from {:?}.", self.id, pid);
+                            self.log(&mut out, Action::Terminate);
+                            self.term.lock().unwrap().finish();
+                            vec![]
+                        }
+                        MessageType::Token => {
+                            self.term.lock().unwrap().on_token(msg.ts.into());
+                            vec![]
+                        }
+                        MessageType::Marker => {
+                            let n = msg.ts as u64;
+                            self.snapshot_listener(&arb, &streams, &open, n, ev.key);
+                            vec![]
+                        }
+                        MessageType::Join => {
+                            self.log(&mut out, Action::Join(msg.id));
+                            let addr = joiner_addr(stream, &msg);
+                            self.ips.lock().unwrap().insert(pid, addr);
+                            self.members.lock().unwrap().insert(pid);
+                            self.term.lock().unwrap().changed();
+                            // A node that left said so to everyone but the newcomer
+                            if !self.changes.lock().unwrap().join(pid, addr) && self.leave {
+                                let ts = self.seq.load(Ordering::SeqCst) as u128;
+                                self.tell(pid, MessageType::Leave, ts);
+                            }
+                            vec![]
+                        }
+                        MessageType::Leave => {
+                            // Whatever it held was released just before
+                            self.log(&mut out, Action::Leave(msg.id));
+                            let member = self.members.lock().unwrap().remove(&pid);
+                            self.term.lock().unwrap().changed();
+                            // Answer for a requester that is done. A leaving
+                            // node has already said `Leave` to everyone.
+                            if member && !self.changes.lock().unwrap().leave(pid) && !self.leave {
+                                let ts = self.seq.load(Ordering::SeqCst) as u128;
+                                self.tell(pid, MessageType::Leave, ts);
+                            }
+                            vec![]
+                        }
+                        _ => {
+                            panic!("Unexpected message")
+                        }
+                    };
+                    self.answer(&arb, before, grants, &streams, &mut out);
+                }
             }
+            self.pass_token();
+            self.save_snapshot();
         }
 
         out
     }
 
-    fn listener_thread(&self, q: usize) -> Vec<LogEntry> {
-        let (poller, streams) = self.get_listener_poller(q);
+    /// Records the arbiter's part of snapshot `n`, whose marker came on
+    /// stream `key`, and passes the marker on to every requester.
+    fn snapshot_listener(
+        &self,
+        arb: &Arbiter,
+        streams: &[TcpStream],
+        open: &HashSet<usize>,
+        n: u64,
+        key: usize,
+    ) {
+        if !self.snap.lock().unwrap().on_marker(Side::Listener, n, key) {
+            return;
+        }
+        let state = Local::Maekawa {
+            seq: self.seq.load(Ordering::SeqCst),
+            locked: arb.locked.map(|x| (x.pid, x.ts)),
+            // Highest priority first
+            queue: arb
+                .req
+                .clone()
+                .into_sorted_vec()
+                .iter()
+                .rev()
+                .map(|x| (x.pid, x.ts))
+                .collect(),
+            phantom: arb.phantom,
+            inquired: arb.inq,
+        };
+        for &i in open.iter() {
+            let marker = self.message(MessageType::Marker, n as u128, channel(&streams[i]));
+            let _ = (&streams[i]).write_all(&Vec::from(marker));
+        }
+        let open = open.iter().copied().filter(|&i| i != key);
+        self.snap
+            .lock()
+            .unwrap()
+            .record(Side::Listener, state, open);
+    }
 
-        println!("Quorum ready: {}", streams.len());
+    /// Records the requester's part of snapshot `n`, whose marker came on
+    /// stream `key`, and passes the marker on to every member.
+    fn snapshot_requester(
+        &self,
+        streams: &mut [((u64, u64), Conn)],
+        key: usize,
+        n: u64,
+        state: Local<(u64, u64)>,
+    ) {
+        if !self.snap.lock().unwrap().on_marker(Side::Requester, n, key) {
+            return;
+        }
+        for (_, conn) in streams.iter_mut().filter(|x| x.1.is_up()) {
+            let msg = self.message(
+                MessageType::Marker,
+                n as u128,
+                conn.stream().and_then(channel),
+            );
+            conn.send(msg);
+        }
+        let open = streams
+            .iter()
+            .enumerate()
+            .filter(|(i, x)| *i != key && x.1.is_up())
+            .map(|(i, _)| i);
+        self.snap
+            .lock()
+            .unwrap()
+            .record(Side::Requester, state, open);
+    }
 
-        self.listen(poller, &streams, q)
+    /// Writes our share of a snapshot once both processes have recorded it.
+    fn save_snapshot(&self) {
+        let snap = self.snap.lock().unwrap().take(self.id);
+        if let Some(snap) = snap {
+            let (a, b) = snap.id;
+            snap.write(&format!(
+                "{}/snapshot_{}_{}_{}.json",
+                self.dir, snap.number, a, b
+            ));
+        }
     }
 
-    fn listener_spawn(self: Arc<Self>, q: usize) -> JoinHandle<Vec<LogEntry>> {
-        thread::spawn(move || self.listener_thread(q))
+    /// Starts a snapshot every period, by sending ourselves a marker, until
+    /// the node is done.
+    fn snapshot_thread(&self) {
+        let Some(period) = self.snapshots else {
+            return;
+        };
+        while !self.done.load(Ordering::SeqCst) {
+            thread::sleep(period);
+            let n = self.snap.lock().unwrap().next();
+            self.tell(self.id, MessageType::Marker, n as u128);
+        }
+    }
+
+    /// Sends one message on a connection of its own, to a node the
+    /// requester has no connection to or is done with.
+    fn tell(&self, pid: (u64, u64), typ: MessageType, ts: u128) {
+        let addr = self.ips.lock().unwrap()[&pid];
+        if let Some(mut conn) = Conn::try_new(addr, self.router.clone()) {
+            if detection(typ) {
+                self.term_mc.fetch_add(1, Ordering::SeqCst);
+            } else {
+                self.mc.fetch_add(1, Ordering::SeqCst);
+            }
+            conn.send(self.message(typ, ts, None));
+        } else {
+            println!("Could not reach node {:?}", pid);
+        }
+    }
+
+    fn listener_thread(&self) -> Vec<LogEntry> {
+        let poller = self.get_listener_poller();
+
+        self.listen(poller)
+    }
+
+    fn listener_spawn(self: Arc<Self>) -> JoinHandle<Vec<LogEntry>> {
+        thread::spawn(move || self.listener_thread())
+    }
+
+    /// Runs heartbeat rounds until the node is done, logging suspicions.
+    fn detector_thread(&self) -> Vec<LogEntry> {
+        let mut out = vec![];
+        let Some(fd) = &self.fd else {
+            return out;
+        };
+        while !self.done.load(Ordering::SeqCst) {
+            for (pid, up) in fd.tick() {
+                self.term.lock().unwrap().changed();
+                self.log(
+                    &mut out,
+                    if up {
+                        Action::Recover(pid)
+                    } else {
+                        Action::Suspect(pid)
+                    },
+                );
+            }
+        }
+        out
+    }
+
+    fn detector_spawn(self: Arc<Self>) -> JoinHandle<Vec<LogEntry>> {
+        thread::spawn(move || self.detector_thread())
     }
 
     /// Initiates node execution
     pub fn spawn(self: Arc<Self>, params: Params) {
         let mut file =
-            File::create(format!("log/maekawa/node_{}_{}.log", self.id.0, self.id.1)).unwrap();
-        let q = (params.n as f64).sqrt() as usize;
+            File::create(format!("{}/node_{}_{}.log", self.dir, self.id.0, self.id.1)).unwrap();
+        let q = (self.ips.lock().unwrap().len() as f64).sqrt() as usize;
+
+        // Spawn a new thread to watch for crashed peers
+        let detector = self.clone().detector_spawn();
 
         // Spawn a new thread to listen for incoming messages
-        let listener = self.clone().listener_spawn(q);
+        let listener = self.clone().listener_spawn();
+
+        // Not joined: it may be asleep until the next snapshot
+        let snap = self.clone();
+        thread::spawn(move || snap.snapshot_thread());
 
         // Spawn a new thread to request CS.
         let node = self.clone().requester_spawn(params, q);
@@ -471,13 +1056,34 @@ impl MaekawaNode {
         // Wait for the threads to finish
         let listener_log = listener.join().unwrap();
         let node_log = node.join().unwrap();
+        self.done.store(true, Ordering::SeqCst);
+        let detector_log = detector.join().unwrap();
 
-        let mut log = [listener_log, node_log].concat();
+        // Lets logs of different nodes be put on one timeline
+        let start = LogEntry {
+            pid: Left(self.id),
+            ts: 0,
+            act: Action::Start(epoch_micros() - self.init.elapsed().as_micros()),
+            clock: None,
+        };
+
+        let mut log = [vec![start], listener_log, node_log, detector_log].concat();
         log.sort_by_key(|x| x.ts);
 
         // TODO: Make a new display function for LogEntry
         for entry in log.iter() {
             writeln!(file, "{}", entry).unwrap();
         }
+
+        if let Some(chaos) = &self.chaos {
+            chaos.dump(&format!("{}/cs_{}_{}.log", self.dir, self.id.0, self.id.1));
+        }
+        if let Some(clock) = &self.clock {
+            let path = format!("{}/clock_{}_{}.json", self.dir, self.id.0, self.id.1);
+            clock.lock().unwrap().stats.write(&path);
+        }
+        if let Some(router) = &self.router {
+            router.linger(&self.rx);
+        }
     }
 }
@@ -0,0 +1,70 @@
+use std::{
+    mem,
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+};
+
+use polling::Poller;
+
+use crate::utils::Message;
+
+/// Joins and leaves the listener has seen and the requester has yet to act on.
+///
+/// The requester opens connections to newcomers, and answers a departure
+/// with a `Leave` of its own once it has stopped using the node, so the
+/// departing node knows no more requests are coming. Once the requester is
+/// `done` the listener answers departures itself.
+#[derive(Default)]
+pub struct Changes<P> {
+    joined: Vec<(P, SocketAddr)>,
+    left: Vec<P>,
+    done: bool,
+    wake: Option<Arc<Poller>>, // the requester's poller
+}
+
+impl<P> Changes<P> {
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn set_waker(&mut self, poller: Arc<Poller>) {
+        self.wake = Some(poller);
+    }
+
+    /// Returns false if the requester is done, and will never see it.
+    pub fn join(&mut self, pid: P, addr: SocketAddr) -> bool {
+        if !self.done {
+            self.joined.push((pid, addr));
+            self.notify();
+        }
+        !self.done
+    }
+
+    /// Returns false if the requester is done, and will never see it.
+    pub fn leave(&mut self, pid: P) -> bool {
+        if !self.done {
+            self.left.push(pid);
+            self.notify();
+        }
+        !self.done
+    }
+
+    /// Everything since the last call. With `done`, later changes are left
+    /// to the listener.
+    pub fn take(&mut self, done: bool) -> (Vec<(P, SocketAddr)>, Vec<P>) {
+        self.done |= done;
+        (mem::take(&mut self.joined), mem::take(&mut self.left))
+    }
+
+    fn notify(&self) {
+        if let Some(poller) = &self.wake {
+            let _ = poller.notify();
+        }
+    }
+}
+
+/// Where a node that sent `Join` on `stream` listens: the port is in the
+/// message, since the stream's own port is an ephemeral one.
+pub fn joiner_addr(stream: &TcpStream, msg: &Message) -> SocketAddr {
+    SocketAddr::new(stream.peer_addr().unwrap().ip(), msg.ts as u16)
+}
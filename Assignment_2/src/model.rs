@@ -0,0 +1,762 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt::{Debug, Display},
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use crate::{
+    protocol::{cross, rc_request, Arbiter, Requester, Verdict},
+    request::Request,
+    utils::MessageType,
+};
+
+/// Where a modelled node is in its run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Idle,
+    Waiting,
+    InCs,
+    Done,
+}
+
+/// A message, with a timestamp only if its receiver reads it. The real nodes
+/// stamp every message, but states that only differ in stamps nobody reads
+/// are the same state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Msg {
+    pub typ: MessageType,
+    pub ts: Option<u128>,
+}
+
+/// A message to send: destination, whether it goes to the destination's
+/// listener (rather than its requester), and the message.
+pub type Send = (usize, bool, Msg);
+
+/// A node of the protocol, as seen by the checker.
+///
+/// Each node has a requester and a listener half, like the real ones. The
+/// requester only reads its channels while it waits for the CS, so replies
+/// that arrive at other times sit there until the next request, as they do
+/// on a socket. Once a node is done its requester would read and ignore
+/// anything, so messages to it are dropped instead.
+pub trait Node: Clone + Eq + Hash + Debug {
+    fn new(id: usize, n: usize, k: usize) -> Self;
+    fn phase(&self) -> Phase;
+    fn request(&mut self) -> Vec<Send>;
+    fn exit(&mut self) -> Vec<Send>;
+    fn deliver(&mut self, from: usize, listener: bool, msg: Msg) -> Result<Vec<Send>, String>;
+
+    /// Whether a message for this half would be read now.
+    fn reads(&self, listener: bool) -> bool {
+        listener || self.phase() == Phase::Waiting
+    }
+}
+
+/// One transition of the system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Request(usize),
+    Exit(usize),
+    Deliver {
+        from: usize,
+        to: usize,
+        listener: bool,
+        msg: Msg,
+    },
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Request(i) => write!(f, "node {} requests the CS", i),
+            Step::Exit(i) => write!(f, "node {} exits the CS", i),
+            Step::Deliver {
+                from,
+                to,
+                listener,
+                msg,
+            } => write!(
+                f,
+                "node {} {} gets {:?}{} from node {}",
+                to,
+                if *listener { "listener" } else { "requester" },
+                msg.typ,
+                msg.ts.map_or(String::new(), |x| format!(" (ts {})", x)),
+                from
+            ),
+        }
+    }
+}
+
+/// Nodes and the FIFO channels between them, keyed by (from, to, listener).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct State<N> {
+    nodes: Vec<N>,
+    chans: BTreeMap<(usize, usize, bool), VecDeque<Msg>>,
+}
+
+impl<N: Node> State<N> {
+    fn post(&mut self, from: usize, sends: Vec<Send>) {
+        if self.nodes[from].phase() == Phase::Done {
+            self.chans
+                .retain(|&(_, to, listener), _| to != from || listener);
+        }
+        for (to, listener, msg) in sends {
+            if !listener && self.nodes[to].phase() == Phase::Done {
+                continue;
+            }
+            self.chans
+                .entry((from, to, listener))
+                .or_default()
+                .push_back(msg);
+        }
+    }
+
+    /// Every transition enabled here, and where it leads.
+    fn next(&self) -> Vec<(Step, Result<Self, String>)> {
+        let mut out = vec![];
+        for (i, node) in self.nodes.iter().enumerate() {
+            let step = match node.phase() {
+                Phase::Idle => Step::Request(i),
+                Phase::InCs => Step::Exit(i),
+                _ => continue,
+            };
+            let mut s = self.clone();
+            let sends = match step {
+                Step::Request(_) => s.nodes[i].request(),
+                _ => s.nodes[i].exit(),
+            };
+            s.post(i, sends);
+            out.push((step, Ok(s)));
+        }
+        for (&(from, to, listener), chan) in self.chans.iter() {
+            if !self.nodes[to].reads(listener) {
+                continue;
+            }
+            let mut s = self.clone();
+            let msg = s.chans.get_mut(&(from, to, listener)).unwrap().pop_front();
+            let msg = msg.unwrap();
+            if chan.len() == 1 {
+                s.chans.remove(&(from, to, listener));
+            }
+            let step = Step::Deliver {
+                from,
+                to,
+                listener,
+                msg,
+            };
+            let res = s.nodes[to].deliver(from, listener, msg).map(|sends| {
+                s.post(to, sends);
+                s
+            });
+            out.push((step, res));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Violation {
+    /// These nodes were in the CS together.
+    Exclusion(Vec<usize>),
+    /// Nothing can happen, but these nodes are not done.
+    Deadlock(Vec<usize>),
+    /// This node can wait forever while the others keep going.
+    Starvation(usize),
+    /// The protocol logic gave up, as the real node would panic.
+    Error(String),
+}
+
+/// A shortest run that ends in a violation. For starvation, `cycle` is
+/// repeated forever after `trace`.
+#[derive(Debug, Clone)]
+pub struct Counterexample {
+    pub violation: Violation,
+    pub trace: Vec<Step>,
+    pub cycle: Vec<Step>,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub states: usize,
+    pub transitions: usize,
+    /// False if exploration stopped at the state limit. True does not rule
+    /// out a state lost to a hash collision, see `check`.
+    pub complete: bool,
+    pub result: Result<(), Counterexample>,
+}
+
+/// Explores every interleaving of requests, exits and message deliveries for
+/// `n` nodes entering the CS `k` times each.
+///
+/// Mutual exclusion, deadlock freedom and protocol errors are checked on every
+/// state reached; starvation freedom once the whole graph is known, by looking
+/// for a cycle along which some node keeps waiting. The counterexample
+/// reported is a shortest one.
+///
+/// Visited states are only remembered by a 64 bit hash (hash compaction) and
+/// their successors, so that graphs of tens of millions of states fit in
+/// memory. Traces are rebuilt by replaying transitions from the start.
+/// Two states with the same hash are taken for one, and the successors of
+/// the second are never explored, so a clean result is probabilistic: with
+/// `m` states the chance of a collision is about `m^2 / 2^65`, around 1 in
+/// 100,000 for 20 million states.
+pub fn check<N: Node>(n: usize, k: usize, max_states: usize) -> Report {
+    let init = State {
+        nodes: (0..n).map(|i| N::new(i, n, k)).collect(),
+        chans: BTreeMap::new(),
+    };
+    let mut g = Graph::default();
+    g.visit(digest(&init));
+    let mut bad: Vec<(u32, Option<usize>, Violation)> = vec![];
+    let mut stack = vec![(0, init.clone())];
+    let mut transitions = 0;
+    let mut cut = false; // some state was left unexplored
+
+    while let Some((i, s)) = stack.pop() {
+        let cs = (0..n)
+            .filter(|&x| s.nodes[x].phase() == Phase::InCs)
+            .collect::<Vec<_>>();
+        if cs.len() > 1 {
+            bad.push((i, None, Violation::Exclusion(cs)));
+        }
+        g.waiting[i as usize] = (0..n)
+            .filter(|&x| s.nodes[x].phase() == Phase::Waiting)
+            .fold(0, |m, x| m | 1 << x);
+
+        let next = s.next();
+        if next.is_empty() {
+            let stuck = (0..n)
+                .filter(|&x| s.nodes[x].phase() != Phase::Done)
+                .collect::<Vec<_>>();
+            if !stuck.is_empty() {
+                bad.push((i, None, Violation::Deadlock(stuck)));
+            }
+        }
+
+        let start = g.edges.len();
+        for (t, (_, res)) in next.into_iter().enumerate() {
+            transitions += 1;
+            let t = match res {
+                Ok(x) => x,
+                Err(e) => {
+                    bad.push((i, Some(t), Violation::Error(e)));
+                    g.edges.push(NONE);
+                    continue;
+                }
+            };
+            let h = digest(&t);
+            let j = match g.seen.get(&h) {
+                Some(&j) => j,
+                None if g.seen.len() >= max_states => {
+                    cut = true;
+                    NONE
+                }
+                None => {
+                    let j = g.visit(h);
+                    stack.push((j, t));
+                    j
+                }
+            };
+            g.edges.push(j);
+        }
+        g.span[i as usize] = (start, g.edges.len());
+    }
+
+    let complete = !cut;
+    let depth = g.bfs();
+    // An error happens on the transition out of the state
+    let worst = bad
+        .into_iter()
+        .filter_map(|x| depth[x.0 as usize].map(|(_, d)| (d + x.1.is_some() as u32, x)))
+        .min_by_key(|x| x.0);
+    let result = match worst {
+        Some((_, (x, t, violation))) => {
+            let path = g.path(&depth, x);
+            let mut trace = g.replay(&init, &path);
+            if let Some(t) = t {
+                trace.push(g.state(&init, &path).next().swap_remove(t).0);
+            }
+            Err(Counterexample {
+                violation,
+                trace,
+                cycle: vec![],
+            })
+        }
+        None if complete => match g.starvation(n) {
+            Some((node, x, cycle)) => {
+                let mut path = g.path(&depth, x);
+                let trace = g.replay(&init, &path);
+                path.extend(cycle);
+                let cycle = g.replay(&init, &path).split_off(trace.len());
+                Err(Counterexample {
+                    violation: Violation::Starvation(node),
+                    trace,
+                    cycle,
+                })
+            }
+            None => Ok(()),
+        },
+        None => Ok(()),
+    };
+    Report {
+        states: g.seen.len(),
+        transitions,
+        complete,
+        result,
+    }
+}
+
+/// Successor that was cut off by the state limit, or failed.
+const NONE: u32 = u32::MAX;
+
+fn digest<N: Hash>(s: &N) -> u64 {
+    let mut h = DefaultHasher::new();
+    s.hash(&mut h);
+    h.finish()
+}
+
+/// The explored state graph, by state id. Successors of state `i` are
+/// `edges[span[i].0..span[i].1]`, in the order `State::next` lists the
+/// transitions.
+#[derive(Default)]
+struct Graph {
+    seen: HashMap<u64, u32>,
+    span: Vec<(usize, usize)>,
+    edges: Vec<u32>,
+    waiting: Vec<u32>, // Bitmask of waiting nodes
+}
+
+impl Graph {
+    fn visit(&mut self, h: u64) -> u32 {
+        let i = self.span.len() as u32;
+        self.seen.insert(h, i);
+        self.span.push((0, 0));
+        self.waiting.push(0);
+        i
+    }
+
+    fn succ(&self, i: usize) -> &[u32] {
+        &self.edges[self.span[i].0..self.span[i].1]
+    }
+
+    /// Parent and depth of each state on a shortest path from the start.
+    fn bfs(&self) -> Vec<Option<(u32, u32)>> {
+        let mut out = vec![None; self.span.len()];
+        out[0] = Some((0, 0));
+        let mut queue = VecDeque::from([0]);
+        while let Some(i) = queue.pop_front() {
+            let d = out[i as usize].unwrap().1;
+            for &j in self.succ(i as usize) {
+                if j != NONE && out[j as usize].is_none() {
+                    out[j as usize] = Some((i, d + 1));
+                    queue.push_back(j);
+                }
+            }
+        }
+        out
+    }
+
+    /// States on a shortest path from the start to `x`, inclusive.
+    fn path(&self, depth: &[Option<(u32, u32)>], x: u32) -> Vec<u32> {
+        let mut out = vec![x];
+        let mut y = x;
+        while y != 0 {
+            y = depth[y as usize].unwrap().0;
+            out.push(y);
+        }
+        out.reverse();
+        out
+    }
+
+    /// Follows a path of states, returning the state reached and the steps.
+    fn walk<N: Node>(&self, init: &State<N>, path: &[u32]) -> (State<N>, Vec<Step>) {
+        let mut s = init.clone();
+        let mut out = vec![];
+        for w in path.windows(2) {
+            let t = self
+                .succ(w[0] as usize)
+                .iter()
+                .position(|&x| x == w[1])
+                .unwrap();
+            let (step, res) = s.next().swap_remove(t);
+            out.push(step);
+            s = res.unwrap();
+        }
+        (s, out)
+    }
+
+    fn replay<N: Node>(&self, init: &State<N>, path: &[u32]) -> Vec<Step> {
+        self.walk(init, path).1
+    }
+
+    fn state<N: Node>(&self, init: &State<N>, path: &[u32]) -> State<N> {
+        self.walk(init, path).0
+    }
+
+    /// Finds a node that can wait forever: a cycle of states in which it is
+    /// always waiting. Returns the node, a state on such a cycle and the
+    /// states along the shortest cycle through it, ending back at it.
+    fn starvation(&self, n: usize) -> Option<(usize, u32, Vec<u32>)> {
+        for node in 0..n {
+            let comp = self.sccs(&|x| self.waiting[x] & 1 << node != 0);
+            // Every state of a cyclic component lies on a cycle within it.
+            let Some(x) = (0..comp.len()).find(|&x| comp[x] != NONE) else {
+                continue;
+            };
+
+            // Shortest way back to `x` without leaving its component
+            let c = comp[x];
+            let mut prev: HashMap<usize, usize> = HashMap::new();
+            let mut queue = VecDeque::from([x]);
+            'bfs: while let Some(y) = queue.pop_front() {
+                for z in self.succ(y).iter().map(|&z| z as usize) {
+                    if z >= comp.len() || comp[z] != c {
+                        continue;
+                    }
+                    if z == x {
+                        prev.insert(x, y);
+                        break 'bfs;
+                    }
+                    if let Entry::Vacant(e) = prev.entry(z) {
+                        e.insert(y);
+                        queue.push_back(z);
+                    }
+                }
+            }
+            let mut path = vec![x as u32];
+            let mut y = prev[&x];
+            while y != x {
+                path.push(y as u32);
+                y = prev[&y];
+            }
+            path.reverse();
+            return Some((node, x as u32, path));
+        }
+        None
+    }
+
+    /// Strongly connected components of the subgraph induced by `keep`, by
+    /// iterative Tarjan. Only components with a cycle in them are numbered;
+    /// every other state gets `NONE`.
+    fn sccs(&self, keep: &impl Fn(usize) -> bool) -> Vec<u32> {
+        let len = self.span.len();
+        let mut index = vec![NONE; len];
+        let mut low = vec![0; len];
+        let mut on_stack = vec![false; len];
+        let mut stack = vec![];
+        let mut comp = vec![NONE; len];
+        let mut next = 0;
+        let mut count = 0;
+        let succ = |v: usize| {
+            self.succ(v)
+                .iter()
+                .map(|&x| x as usize)
+                .filter(|&w| w < len && keep(w))
+        };
+
+        for root in (0..len).filter(|&x| keep(x)) {
+            if index[root] != NONE {
+                continue;
+            }
+            let mut work = vec![(root, 0)];
+            while let Some((v, e)) = work.pop() {
+                if e == 0 {
+                    index[v] = next;
+                    low[v] = next;
+                    next += 1;
+                    stack.push(v);
+                    on_stack[v] = true;
+                }
+                if let Some(w) = succ(v).nth(e) {
+                    work.push((v, e + 1));
+                    if index[w] == NONE {
+                        work.push((w, 0));
+                    } else if on_stack[w] {
+                        low[v] = low[v].min(index[w]);
+                    }
+                    continue;
+                }
+                if low[v] == index[v] {
+                    let at = stack.iter().rposition(|&x| x == v).unwrap();
+                    let members = stack.split_off(at);
+                    let cyclic = members.len() > 1 || succ(v).any(|w| w == v);
+                    for &w in members.iter() {
+                        on_stack[w] = false;
+                        if cyclic {
+                            comp[w] = count;
+                        }
+                    }
+                    count += 1;
+                }
+                if let Some(&(u, _)) = work.last() {
+                    low[u] = low[u].min(low[v]);
+                }
+            }
+        }
+        comp
+    }
+}
+
+/// `MaekawaNode`: requester and arbiter sharing a Lamport clock. Nodes are
+/// numbered row-major and always use their own cross as quorum.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Maekawa {
+    id: usize,
+    q: usize,
+    phase: Phase,
+    left: usize, // CS entries still to make
+    seq: u64,
+    quorum: Vec<usize>,
+    req: Requester,
+    arb: Arbiter,
+}
+
+impl Maekawa {
+    fn pid(&self, x: usize) -> (u64, u64) {
+        ((x / self.q) as u64, (x % self.q) as u64)
+    }
+
+    /// Arbiters read the stamps of requests and yields, and nothing else.
+    fn msg(&self, typ: MessageType) -> Msg {
+        let stamped = matches!(typ, MessageType::Request | MessageType::Yield);
+        Msg {
+            typ,
+            ts: stamped.then_some(self.seq as u128),
+        }
+    }
+}
+
+impl Node for Maekawa {
+    fn new(id: usize, n: usize, k: usize) -> Self {
+        let q = (n as f64).sqrt() as usize;
+        Self {
+            id,
+            q,
+            phase: if k > 0 { Phase::Idle } else { Phase::Done },
+            left: k,
+            seq: 0,
+            quorum: cross(id, q),
+            req: Requester::default(),
+            arb: Arbiter::default(),
+        }
+    }
+
+    fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    fn request(&mut self) -> Vec<Send> {
+        let msg = self.msg(MessageType::Request);
+        self.seq += 1;
+        self.req = Requester::new(&self.quorum);
+        self.phase = Phase::Waiting;
+        self.quorum.iter().map(|&i| (i, true, msg)).collect()
+    }
+
+    fn exit(&mut self) -> Vec<Send> {
+        self.left -= 1;
+        self.phase = if self.left > 0 {
+            Phase::Idle
+        } else {
+            Phase::Done
+        };
+        let msg = self.msg(MessageType::Release);
+        self.quorum.iter().map(|&i| (i, true, msg)).collect()
+    }
+
+    fn deliver(&mut self, from: usize, listener: bool, msg: Msg) -> Result<Vec<Send>, String> {
+        if !listener {
+            let sends = self.req.on_message(from, msg.typ)?;
+            if !self.req.waiting() {
+                self.phase = Phase::InCs;
+            }
+            return Ok(sends
+                .into_iter()
+                .map(|(i, typ)| (i, true, self.msg(typ)))
+                .collect());
+        }
+
+        let pid = self.pid(from);
+        let ts = msg.ts.unwrap_or_default();
+        let grants = match msg.typ {
+            MessageType::Request => {
+                if ts > self.seq as u128 {
+                    self.seq = (ts + 1) as u64;
+                }
+                self.arb.on_request(Request::new(ts, pid, from))?
+            }
+            MessageType::Release => self.arb.on_release(pid, |_| false),
+            MessageType::Yield => self.arb.on_yield(pid, ts, self.seq as u128, |_| false)?,
+            _ => return Err("Unexpected message".to_string()),
+        };
+        Ok(grants
+            .into_iter()
+            .map(|(to, typ)| (to.stream, false, self.msg(typ)))
+            .collect())
+    }
+}
+
+/// `RCNode`: requester and listener sharing a Lamport clock and the
+/// permission table.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Rc {
+    phase: Phase,
+    left: usize,
+    seq: u64,
+    requesting: bool,
+    /// Per node: must we ask it for permission, and is its request deferred
+    quorum: Vec<(bool, bool)>,
+    waiting: BTreeSet<usize>,
+}
+
+impl Rc {
+    /// Only requests are read for their stamp.
+    fn msg(&self, typ: MessageType) -> Msg {
+        Msg {
+            typ,
+            ts: (typ == MessageType::Request).then_some(self.seq as u128),
+        }
+    }
+
+    /// We are in CS now. Set everything to false.
+    fn enter(&mut self) {
+        for x in self.quorum.iter_mut() {
+            x.0 = false;
+        }
+        self.phase = Phase::InCs;
+    }
+}
+
+impl Node for Rc {
+    fn new(id: usize, n: usize, k: usize) -> Self {
+        Self {
+            phase: if k > 0 { Phase::Idle } else { Phase::Done },
+            left: k,
+            seq: 0,
+            requesting: false,
+            quorum: (0..n).map(|x| (x < id, false)).collect(),
+            waiting: BTreeSet::new(),
+        }
+    }
+
+    fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    fn request(&mut self) -> Vec<Send> {
+        self.requesting = true;
+        let msg = self.msg(MessageType::Request);
+        self.waiting = (0..self.quorum.len())
+            .filter(|&x| self.quorum[x].0)
+            .collect();
+        self.seq += 1;
+        self.phase = Phase::Waiting;
+        if self.waiting.is_empty() {
+            self.enter();
+        }
+        self.waiting.iter().map(|&x| (x, true, msg)).collect()
+    }
+
+    fn exit(&mut self) -> Vec<Send> {
+        self.requesting = false;
+        self.left -= 1;
+        self.phase = if self.left > 0 {
+            Phase::Idle
+        } else {
+            Phase::Done
+        };
+        let mut out = vec![];
+        for x in 0..self.quorum.len() {
+            if self.quorum[x].1 {
+                self.quorum[x].1 = false;
+                out.push((x, false, self.msg(MessageType::Reply)));
+            }
+        }
+        out
+    }
+
+    fn deliver(&mut self, from: usize, listener: bool, msg: Msg) -> Result<Vec<Send>, String> {
+        match (listener, msg.typ) {
+            (true, MessageType::Request) => match rc_request(msg.ts.unwrap(), &mut self.seq) {
+                Verdict::Defer => {
+                    self.quorum[from].1 = true;
+                    Ok(vec![])
+                }
+                Verdict::Reply => {
+                    self.quorum[from].0 = true;
+                    Ok(vec![(from, false, self.msg(MessageType::Reply))])
+                }
+            },
+            (false, MessageType::Reply) => {
+                if self.phase == Phase::Waiting {
+                    self.waiting.remove(&from);
+                    if self.waiting.is_empty() {
+                        self.enter();
+                    }
+                }
+                Ok(vec![])
+            }
+            _ => Err("Unexpected message".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rc_exclusion_counterexample() {
+        let report = check::<Rc>(3, 1, 100_000);
+        assert!(report.complete);
+        let cex = report.result.unwrap_err();
+        assert!(matches!(cex.violation, Violation::Exclusion(ref x) if x == &[0, 1]));
+
+        // Node 0 asks nobody, and node 1's request is no newer than node 0's
+        // clock, so it gets a reply
+        let request = Msg {
+            typ: MessageType::Request,
+            ts: Some(0),
+        };
+        let reply = Msg {
+            typ: MessageType::Reply,
+            ts: None,
+        };
+        assert_eq!(
+            cex.trace,
+            [
+                Step::Request(0),
+                Step::Request(1),
+                Step::Deliver {
+                    from: 1,
+                    to: 0,
+                    listener: true,
+                    msg: request
+                },
+                Step::Deliver {
+                    from: 0,
+                    to: 1,
+                    listener: false,
+                    msg: reply
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn maekawa_single_node() {
+        let report = check::<Maekawa>(1, 2, 100_000);
+        assert!(report.complete);
+        assert!(report.result.is_ok());
+    }
+
+    #[test]
+    fn state_limit_only_counts_when_hit() {
+        let states = check::<Maekawa>(1, 2, 100_000).states;
+        let report = check::<Maekawa>(1, 2, states);
+        assert_eq!(report.states, states);
+        assert!(report.complete);
+        assert!(!check::<Maekawa>(1, 2, states - 1).complete);
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::Write,
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use either::Either::Right;
+use rand::thread_rng;
+
+use crate::{
+    broadcast::Group,
+    chaos::Chaos,
+    topology::Router,
+    utils::{epoch_micros, Action, LogEntry, Message, MessageType},
+    Params, Region,
+};
+
+/// Where a node stands in the tree of `last` pointers.
+#[derive(Debug, Default)]
+struct Tree {
+    last: Option<usize>,         // probable owner of the token, `None` at the root
+    next: Option<(usize, u64)>,  // who gets the token after us, and its request's hops
+    token: bool,                 // held, in the CS or not
+    requesting: bool,            // from asking until leaving the CS
+    seen: HashSet<(usize, u64)>, // requests already handled, so copies are dropped
+    pass: u128,                  // number of the last token we held
+    done: HashSet<usize>,        // nodes that have sent `Terminate`
+}
+
+/// What a node does with a request, as `Tree::request` decides
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    Forward(usize), // on towards the root, through this node
+    Grant,          // the token goes to the requester
+    Queue,          // it goes there once we leave the CS
+    Copy,           // already handled
+}
+
+impl Tree {
+    /// Node 0 starts with the token and is everyone's `last`.
+    fn new(id: usize) -> Self {
+        Self {
+            last: (id != 0).then_some(0),
+            token: id == 0,
+            ..Default::default()
+        }
+    }
+
+    /// Takes in request `no` of node `j`, `hops` messages in, and points
+    /// `last` at the requester.
+    fn request(&mut self, j: usize, no: u64, hops: u64) -> Route {
+        if !self.seen.insert((j, no)) {
+            return Route::Copy;
+        }
+        let route = match self.last {
+            Some(l) => Route::Forward(l),
+            None if self.requesting => {
+                self.next = Some((j, hops));
+                Route::Queue
+            }
+            None => Route::Grant,
+        };
+        self.last = Some(j);
+        route
+    }
+
+    /// Gives the token up. Returns the number it goes out with.
+    fn give(&mut self) -> u128 {
+        self.token = false;
+        self.pass += 1;
+        self.pass
+    }
+
+    /// Takes in a token passed `pass` times, unless it is a copy.
+    fn take(&mut self, pass: u128) -> bool {
+        if pass <= self.pass {
+            return false;
+        }
+        self.token = true;
+        self.pass = pass;
+        true
+    }
+
+    /// Starts a request. Returns the node to send it to, or `None` if we
+    /// are the root, with the token.
+    fn ask(&mut self) -> Option<usize> {
+        self.requesting = true;
+        self.last.take()
+    }
+
+    /// Leaves the CS. Returns who the token goes to, and their hops.
+    fn release(&mut self) -> Option<(usize, u64)> {
+        self.requesting = false;
+        self.next.take()
+    }
+}
+
+/// Naimi–Trehel mutual exclusion: a token passed along a dynamic tree.
+///
+/// Every node keeps `last`, the node it thinks will hold the token, and
+/// `next`, the node to hand it to after its own CS. A request goes along
+/// `last` pointers to the root of the tree, and every node on the way
+/// points `last` at the requester, so paths get shorter as they are used.
+/// This takes O(log n) messages per CS on average. Node 0 starts with the
+/// token and is everyone's `last`.
+///
+/// A `Request` carries its requester in `ts` and `[request number, hops]` in
+/// `clock`; a `Token` carries the number of times it has been passed, and
+/// the hops of the request it answers. Copies of either are dropped.
+pub struct NaimiTrehelNode {
+    pub id: usize,
+    n: usize,
+    group: Group,
+    dir: String,
+    pub mc: AtomicU64,
+    pub init: Instant,
+    tree: Mutex<Tree>,
+    log: Mutex<Vec<LogEntry>>,
+    granted: Sender<u64>, // hops of our request, once the token comes
+    grants: Mutex<Receiver<u64>>,
+    finished: Sender<()>, // everyone has terminated
+    done: Mutex<Receiver<()>>,
+}
+
+impl NaimiTrehelNode {
+    pub fn new(id: usize, addrs: Vec<SocketAddr>) -> Self {
+        let (granted, grants) = mpsc::channel();
+        let (finished, done) = mpsc::channel();
+        Self {
+            id,
+            n: addrs.len(),
+            group: Group::new(id, addrs),
+            dir: "log/naimi".to_string(),
+            mc: 0.into(),
+            init: Instant::now(),
+            tree: Mutex::new(Tree::new(id)),
+            log: Mutex::default(),
+            granted,
+            grants: Mutex::new(grants),
+            finished,
+            done: Mutex::new(done),
+        }
+    }
+
+    pub fn with_chaos(mut self, path: &str, ips: &[SocketAddr]) -> Self {
+        self.group.chaos = Some(Arc::new(Chaos::new(ips[self.id], path, ips)));
+        self
+    }
+
+    pub fn with_router(mut self, router: Router) -> Self {
+        self.group.router = Some(Arc::new(router));
+        self
+    }
+
+    pub fn with_log_dir(mut self, dir: &str) -> Self {
+        self.dir = dir.to_string();
+        self
+    }
+
+    fn entry(&self, act: Action) {
+        self.log.lock().unwrap().push(LogEntry {
+            pid: Right(self.id as u128),
+            ts: self.init.elapsed().as_micros(),
+            act,
+            clock: None,
+        });
+    }
+
+    fn send(&self, to: usize, typ: MessageType, ts: u128, clock: Vec<u64>) {
+        self.mc.fetch_add(1, Ordering::SeqCst);
+        let msg = Message::new_rc(self.id as u128, typ, ts).with_clock(clock);
+        self.group.send(to, msg);
+    }
+
+    /// Hands the token on, to a request that took `hops` messages to get here
+    fn pass(&self, tree: &mut Tree, to: usize, hops: u64) {
+        let pass = tree.give();
+        self.entry(Action::Grant(Right(to as u128)));
+        self.send(to, MessageType::Token, pass, vec![hops]);
+    }
+
+    fn handle(&self, msg: Message) {
+        let from = msg.id.expect_right("") as usize;
+        let mut tree = self.tree.lock().unwrap();
+        match msg.typ {
+            MessageType::Request => {
+                let (j, no, hops) = (msg.ts as usize, msg.clock[0], msg.clock[1]);
+                let route = tree.request(j, no, hops);
+                if route == Route::Copy {
+                    return;
+                }
+                self.entry(Action::Query(Right(from as u128)));
+                match route {
+                    Route::Forward(l) => {
+                        self.send(l, MessageType::Request, j as u128, vec![no, hops + 1])
+                    }
+                    Route::Grant => self.pass(&mut tree, j, hops),
+                    Route::Queue | Route::Copy => {}
+                }
+            }
+            MessageType::Token => {
+                if tree.take(msg.ts) {
+                    self.entry(Action::Reply(Right(from as u128)));
+                    let _ = self.granted.send(msg.clock[0]);
+                }
+            }
+            MessageType::Terminate => {
+                tree.done.insert(from);
+                if tree.done.len() == self.n {
+                    let _ = self.finished.send(());
+                }
+            }
+            _ => {
+                dbg!(msg);
+                panic!("Unexpected message")
+            }
+        }
+    }
+
+    /// Asks the root for the token, and waits for it. Returns the messages
+    /// the request took.
+    fn enter_cs(&self, no: u64) -> u64 {
+        let mut tree = self.tree.lock().unwrap();
+        self.entry(Action::Ask(no as u128));
+        let Some(l) = tree.ask() else {
+            // The root is idle with the token
+            return 0;
+        };
+        self.entry(Action::Request(Right(l as u128)));
+        self.send(l, MessageType::Request, self.id as u128, vec![no, 1]);
+        drop(tree);
+        let hops = self.grants.lock().unwrap().recv().unwrap();
+        hops + 1
+    }
+
+    fn exit_cs(&self) {
+        let mut tree = self.tree.lock().unwrap();
+        if let Some((j, hops)) = tree.release() {
+            self.pass(&mut tree, j, hops);
+        }
+    }
+
+    /// Connects to the others on `rx`, enters the CS as `params` says, and
+    /// serves the others until they are all done. The log goes to
+    /// `node_<id>.log`.
+    pub fn run(self: &Arc<Self>, rx: TcpListener, params: Params) {
+        let me = self.clone();
+        self.group
+            .start(rx, Arc::new(move |msg: Message| me.handle(msg)));
+        println!("Connections established.");
+        let mut rng = thread_rng();
+
+        for i in 0..params.requests() {
+            self.entry(Action::Internal);
+            params.sleep(&mut rng, Region::Out, i, self.init);
+
+            let msgs = self.enter_cs(i as u64);
+            self.entry(Action::Acquire);
+            if let Some(chaos) = &self.group.chaos {
+                chaos.enter();
+            }
+            params.sleep(&mut rng, Region::In, i, self.init);
+
+            if let Some(chaos) = &self.group.chaos {
+                chaos.exit();
+            }
+            self.entry(Action::Exit);
+            self.exit_cs();
+            self.entry(Action::Messages(msgs));
+        }
+
+        // Whoever holds the token keeps serving until nobody needs it
+        self.entry(Action::Terminate);
+        self.mc.fetch_add(self.n as u64 - 1, Ordering::SeqCst);
+        self.group
+            .send_all(Message::new_rc(self.id as u128, MessageType::Terminate, 0));
+        self.handle(Message::new_rc(self.id as u128, MessageType::Terminate, 0));
+        self.done.lock().unwrap().recv().unwrap();
+
+        let start = LogEntry {
+            pid: Right(self.id as u128),
+            ts: 0,
+            act: Action::Start(epoch_micros() - self.init.elapsed().as_micros()),
+            clock: None,
+        };
+        let mut log = self.log.lock().unwrap();
+        log.push(start);
+        log.sort_by_key(|x| x.ts);
+        let mut file = File::create(format!("{}/node_{}.log", self.dir, self.id)).unwrap();
+        for x in log.iter() {
+            writeln!(file, "{}", x).unwrap();
+        }
+        if let Some(chaos) = &self.group.chaos {
+            chaos.dump(&format!("{}/cs_{}.log", self.dir, self.id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_reverse_the_path() {
+        let mut t: Vec<_> = (0..3).map(Tree::new).collect();
+
+        // Node 0 is the root and may enter at once
+        assert_eq!(t[0].ask(), None);
+        assert_eq!(t[0].release(), None);
+        assert!(t[0].token);
+
+        // Node 1 asks the idle root, which hands the token over
+        assert_eq!(t[1].ask(), Some(0));
+        assert_eq!(t[0].request(1, 0, 1), Route::Grant);
+        let pass = t[0].give();
+        assert!(t[1].take(pass));
+        assert!(!t[1].take(pass));
+        assert!(!t[0].token && t[1].token);
+
+        // Node 2 asks 0, which now points at 1, still in its CS
+        assert_eq!(t[2].ask(), Some(0));
+        assert_eq!(t[0].request(2, 0, 1), Route::Forward(1));
+        assert_eq!(t[1].request(2, 0, 2), Route::Queue);
+        assert_eq!(t[1].request(2, 0, 2), Route::Copy);
+        assert_eq!(t[1].release(), Some((2, 2)));
+        let pass = t[1].give();
+        assert!(t[2].take(pass));
+
+        // Everyone points at the last requester, which is the root
+        assert_eq!(
+            t.iter().map(|x| x.last).collect::<Vec<_>>(),
+            [Some(2), Some(2), None]
+        );
+        assert_eq!(t[2].pass, 2);
+    }
+
+    #[test]
+    fn holder_queues_while_asking() {
+        let mut t: Vec<_> = (0..2).map(Tree::new).collect();
+        assert_eq!(t[1].ask(), Some(0));
+        // The root asks again before node 1's request gets there
+        assert_eq!(t[0].ask(), None);
+        assert_eq!(t[0].request(1, 0, 1), Route::Queue);
+        assert_eq!(t[0].release(), Some((1, 1)));
+        let pass = t[0].give();
+        assert!(t[1].take(pass));
+
+        // Node 0's next request goes to the new root; an older copy of the
+        // token is dropped
+        assert_eq!(t[0].ask(), Some(1));
+        assert_eq!(t[1].request(0, 1, 1), Route::Queue);
+        assert_eq!(t[1].release(), Some((0, 1)));
+        assert!(!t[0].take(1));
+        let pass = t[1].give();
+        assert!(t[0].take(pass));
+    }
+}
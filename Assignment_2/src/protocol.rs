@@ -0,0 +1,625 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use crate::{request::Request, utils::MessageType};
+
+/// Row and column through node `c` of a `q` by `q` grid, indexed row-major.
+pub fn cross(c: usize, q: usize) -> Vec<usize> {
+    let (a, b) = (c / q, c % q);
+    (0..q)
+        .map(|x| x * q + b)
+        .chain((0..q).filter(|&y| y != b).map(|y| a * q + y))
+        .collect()
+}
+
+/// Messages a Maekawa arbiter wants sent, addressed by the request they answer.
+pub type Grants = Vec<(Request, MessageType)>;
+
+/// Lock state of a Maekawa arbiter, without the sockets.
+///
+/// `MaekawaNode::listen` feeds it the messages it reads and writes out
+/// whatever it returns; the model checker does the same over simulated
+/// channels. Protocol violations are returned as errors, which the node
+/// turns into panics.
+#[derive(Debug, Clone, Default)]
+pub struct Arbiter {
+    pub locked: Option<Request>,
+    pub req: BinaryHeap<Request>,
+    /// Lock whose holder we lost the connection to, until it turns up again
+    pub phantom: Option<((u64, u64), u128)>,
+    pub inq: bool, // Have we sent an inquire message already?
+    /// Queued requests we have sent `Failed`, by pid
+    pub failed: BTreeSet<(u64, u64)>,
+}
+
+impl Arbiter {
+    /// Whoever the WAL should record as holding the lock
+    pub fn holder(&self) -> Option<((u64, u64), u128)> {
+        self.locked.map(|t| (t.pid, t.ts)).or(self.phantom)
+    }
+
+    /// Hand the lock to the oldest queued request from a live node, if any
+    pub fn grant_next(&mut self, suspected: impl Fn((u64, u64)) -> bool) -> Grants {
+        let mut skipped = vec![];
+        let next = loop {
+            match self.req.pop() {
+                Some(x) if suspected(x.pid) => skipped.push(x),
+                x => break x,
+            }
+        };
+        self.req.extend(skipped);
+
+        self.locked = next;
+        self.forget();
+        let mut out: Grants = next.map(|x| (x, MessageType::Reply)).into_iter().collect();
+        out.extend(self.fail_below());
+        out
+    }
+
+    /// Fails queued requests below the lock that have not been failed yet.
+    /// A request that came in while a lower one held the lock was only
+    /// inquired about, so once a higher one gets the lock it must be told,
+    /// or it would keep the grants it has without yielding them.
+    fn fail_below(&mut self) -> Grants {
+        let Some(t) = self.locked else {
+            return vec![];
+        };
+        let mut below: Vec<_> = self
+            .req
+            .iter()
+            .filter(|x| **x < t && !self.failed.contains(&x.pid))
+            .copied()
+            .collect();
+        below.sort_by(|a, b| b.cmp(a));
+        self.failed.extend(below.iter().map(|x| x.pid));
+        below
+            .into_iter()
+            .map(|x| (x, MessageType::Failed))
+            .collect()
+    }
+
+    /// Drops the failed pids that are no longer queued
+    fn forget(&mut self) {
+        let req = &self.req;
+        self.failed.retain(|p| req.iter().any(|x| x.pid == *p));
+    }
+
+    pub fn on_request(&mut self, new_req: Request) -> Result<Grants, String> {
+        let mut out = vec![];
+        if let Some(x) = self.req.iter().find(|x| **x == new_req) {
+            return Err(format!("Duplicate request: {:#?}, {:#?}.", x, new_req));
+        }
+        if let Some(t) = self.locked {
+            if t == new_req {
+                return Err(format!("Duplicate request: {:#?} {:#?}.", t, new_req));
+            }
+            if new_req < t || self.req.iter().any(|x| *x > new_req) {
+                // Below the locked request, or one already waiting
+                out.push((new_req, MessageType::Failed));
+                self.failed.insert(new_req.pid);
+            } else if !self.inq {
+                out.push((t, MessageType::Inquire));
+                self.inq = true;
+            }
+
+            // Need to put in queue anyway.
+            self.req.push(new_req);
+        } else if let Some(t) = self.phantom {
+            if t == (new_req.pid, new_req.ts) {
+                // The holder from before the crash is back
+                self.phantom = None;
+                self.locked = Some(new_req);
+                out.push((new_req, MessageType::Reply));
+                return Ok(out);
+            }
+            // No stream to inquire on, so older requests just wait. Ranked
+            // by `Request::cmp`, as the locked branch does.
+            let held = Request::new(t.1, t.0, usize::MAX);
+            if new_req < held || self.req.iter().any(|x| *x > new_req) {
+                out.push((new_req, MessageType::Failed));
+                self.failed.insert(new_req.pid);
+            }
+            self.req.push(new_req);
+        } else {
+            self.locked = Some(new_req);
+            self.inq = false;
+            out.push((new_req, MessageType::Reply));
+        }
+        Ok(out)
+    }
+
+    pub fn on_release(
+        &mut self,
+        pid: (u64, u64),
+        suspected: impl Fn((u64, u64)) -> bool,
+    ) -> Grants {
+        match self.locked {
+            Some(t) if t.pid == pid => {
+                self.inq = false;
+                self.grant_next(suspected)
+            }
+            None if self.phantom.is_some_and(|t| t.0 == pid) => {
+                self.phantom = None;
+                self.grant_next(suspected)
+            }
+            // The requester routed around us before we granted it,
+            // or we already took the lock back.
+            _ => {
+                self.req.retain(|x| x.pid != pid);
+                self.forget();
+                vec![]
+            }
+        }
+    }
+
+    /// `seq` is the arbiter's Lamport clock.
+    pub fn on_yield(
+        &mut self,
+        pid: (u64, u64),
+        ts: u128,
+        seq: u128,
+        suspected: impl Fn((u64, u64)) -> bool,
+    ) -> Result<Grants, String> {
+        self.inq = false;
+        match self.locked {
+            Some(t) if t.pid == pid => {
+                if self.req.is_empty() {
+                    if ts < seq {
+                        return Err("Bad yield".to_string());
+                    }
+                } else {
+                    let t = self.locked.take().unwrap();
+                    let mut out = self.grant_next(suspected);
+                    // The `Failed` that made it yield may since have been
+                    // answered, so it is told again that it lost
+                    self.req.push(t);
+                    out.extend(self.fail_below());
+                    return Ok(out);
+                }
+            }
+            None => {
+                return Err("Yielding when locked empty.".to_string());
+            }
+            Some(t) => {
+                if ts < t.ts {
+                    return Err("Weird yield".to_string());
+                }
+            } // Old yield?
+        }
+        Ok(vec![])
+    }
+
+    /// The connection a request came in on is gone.
+    pub fn on_disconnect(&mut self, stream: usize) {
+        if let Some(t) = self.locked.filter(|t| t.stream == stream) {
+            self.phantom = Some((t.pid, t.ts));
+            self.locked = None;
+            self.inq = false;
+        }
+        self.req.retain(|x| x.stream != stream);
+        self.forget();
+    }
+
+    /// Crashed nodes never release, so take the lock back.
+    pub fn reclaim(&mut self, suspected: impl Fn((u64, u64)) -> bool) -> Grants {
+        if self.locked.is_some_and(|t| suspected(t.pid)) {
+            self.locked = None;
+            self.inq = false;
+        }
+        if self.phantom.is_some_and(|t| suspected(t.0)) {
+            self.phantom = None;
+        }
+        if self.locked.is_none() && self.phantom.is_none() && !self.req.is_empty() {
+            return self.grant_next(suspected);
+        }
+        vec![]
+    }
+}
+
+impl PartialEq for Arbiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.locked == other.locked
+            && self.phantom == other.phantom
+            && self.inq == other.inq
+            && self.failed == other.failed
+            && self.req.clone().into_sorted_vec() == other.req.clone().into_sorted_vec()
+    }
+}
+
+impl Eq for Arbiter {}
+
+impl Hash for Arbiter {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.locked.hash(state);
+        self.phantom.hash(state);
+        self.inq.hash(state);
+        self.failed.hash(state);
+        self.req.clone().into_sorted_vec().hash(state);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestStatus {
+    Pending,
+    Granted,
+    Inquiring,
+    Failed,
+}
+
+/// Where a Maekawa requester stands with each member of its quorum, without
+/// the sockets. Members are indexed row-major.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Requester {
+    pub status: BTreeMap<usize, RequestStatus>,
+    pub released: BTreeSet<usize>, // Routed around and already released
+}
+
+impl Requester {
+    pub fn new(quorum: &[usize]) -> Self {
+        Self {
+            status: quorum
+                .iter()
+                .map(|&i| (i, RequestStatus::Pending))
+                .collect(),
+            released: BTreeSet::new(),
+        }
+    }
+
+    /// Still missing a grant from some member
+    pub fn waiting(&self) -> bool {
+        self.status
+            .values()
+            .any(|x| *x == RequestStatus::Pending || *x == RequestStatus::Failed)
+    }
+
+    /// Handles a message from member `from`, returning what to send to whom.
+    pub fn on_message(
+        &mut self,
+        from: usize,
+        typ: MessageType,
+    ) -> Result<Vec<(usize, MessageType)>, String> {
+        let mut out = vec![];
+        let Some(&stat) = self.status.get(&from) else {
+            // Late grant from a node we routed around
+            if let MessageType::Reply = typ {
+                if self.released.insert(from) {
+                    out.push((from, MessageType::Release));
+                }
+            }
+            return Ok(out);
+        };
+        match typ {
+            MessageType::Reply => {
+                if stat == RequestStatus::Granted {
+                    return Err("Duplicate reply".to_string());
+                }
+                self.status.insert(from, RequestStatus::Granted);
+            }
+            MessageType::Failed => {
+                if stat == RequestStatus::Failed {
+                    return Err("Duplicate fail".to_string());
+                }
+                self.status.insert(from, RequestStatus::Failed);
+                for (&i, stat) in self
+                    .status
+                    .iter_mut()
+                    .filter(|x| x.1 == &RequestStatus::Inquiring)
+                {
+                    out.push((i, MessageType::Yield));
+                    *stat = RequestStatus::Pending;
+                }
+            }
+            MessageType::Inquire => {
+                if stat == RequestStatus::Failed || stat == RequestStatus::Pending {
+                    // Orphan inquire, or one we already yielded to.
+                    return Ok(out);
+                }
+                if stat == RequestStatus::Inquiring {
+                    return Err("Duplicate inquire.".to_string());
+                }
+                if self.status.values().any(|x| *x == RequestStatus::Failed) {
+                    out.push((from, MessageType::Yield));
+                    self.status.insert(from, RequestStatus::Pending);
+                } else {
+                    self.status.insert(from, RequestStatus::Inquiring);
+                }
+            }
+            _ => return Err("Unexpected message".to_string()),
+        }
+        Ok(out)
+    }
+}
+
+/// What an RC listener does with a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Reply,
+    Defer,
+}
+
+/// Answer an RC request stamped `ts`, advancing the Lamport clock `seq`.
+pub fn rc_request(ts: u128, seq: &mut u64) -> Verdict {
+    if ts > *seq as u128 {
+        *seq = (ts + 1) as u64;
+        // Unfulfilled request
+        Verdict::Defer
+    } else {
+        Verdict::Reply
+    }
+}
+
+/// Queue of a central coordinator, without the sockets. Requests are granted
+/// one at a time, oldest timestamp first.
+#[derive(Debug, Clone, Default)]
+pub struct Coordinator {
+    pub locked: Option<Request>,
+    pub req: BinaryHeap<Request>,
+    released: HashMap<(u64, u64), u128>, // latest released timestamp, per requester
+}
+
+impl Coordinator {
+    /// Queues a request, and returns it if it gets the CS straight away.
+    /// Copies of a request already queued, granted or released are dropped.
+    pub fn on_request(&mut self, new_req: Request) -> Option<Request> {
+        let stale = self
+            .released
+            .get(&new_req.pid)
+            .is_some_and(|&ts| new_req.ts <= ts);
+        if stale || self.locked == Some(new_req) || self.req.iter().any(|x| *x == new_req) {
+            return None;
+        }
+        self.req.push(new_req);
+        if self.locked.is_some() {
+            return None;
+        }
+        self.locked = self.req.pop();
+        self.locked
+    }
+
+    /// Frees the CS if it is held for request `ts` of `pid`, and returns
+    /// the request granted next, if any.
+    pub fn on_release(&mut self, pid: (u64, u64), ts: u128) -> Option<Request> {
+        match self.locked {
+            Some(t) if t.pid == pid && t.ts == ts => {
+                self.released.insert(pid, ts);
+                self.locked = self.req.pop();
+                self.locked
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn req(ts: u128, node: u64) -> Request {
+        Request::new(ts, (0, node), node as usize)
+    }
+
+    fn sent(grants: &Grants) -> Vec<(u64, MessageType)> {
+        grants.iter().map(|(x, typ)| (x.pid.1, *typ)).collect()
+    }
+
+    #[test]
+    fn arbiter_inquires_and_fails() {
+        let mut arb = Arbiter::default();
+        let out = arb.on_request(req(1, 1)).unwrap();
+        assert_eq!(sent(&out), [(1, MessageType::Reply)]);
+
+        // Younger than the lock
+        let out = arb.on_request(req(2, 2)).unwrap();
+        assert_eq!(sent(&out), [(2, MessageType::Failed)]);
+
+        // Older: the holder is asked to give the lock back, once
+        let out = arb.on_request(req(0, 3)).unwrap();
+        assert_eq!(sent(&out), [(1, MessageType::Inquire)]);
+        assert!(arb.inq);
+        let out = arb.on_request(req(0, 4)).unwrap();
+        assert!(out.is_empty());
+
+        // Same timestamp as the lock, lower id
+        let out = arb.on_request(req(1, 0)).unwrap();
+        assert_eq!(sent(&out), [(0, MessageType::Failed)]);
+
+        assert!(arb.on_request(req(2, 2)).is_err());
+    }
+
+    #[test]
+    fn arbiter_fails_once_outranked() {
+        let mut arb = Arbiter::default();
+        arb.on_request(req(0, 0)).unwrap();
+
+        // Both outrank the lock, so neither is failed on arrival
+        let out = arb.on_request(req(0, 1)).unwrap();
+        assert_eq!(sent(&out), [(0, MessageType::Inquire)]);
+        assert!(arb.on_request(req(0, 2)).unwrap().is_empty());
+
+        // Node 1 would keep its other grants waiting for this one, so it is
+        // failed once node 2 gets the lock, as is node 0, which yielded
+        let out = arb.on_yield((0, 0), 0, 1, |_| false).unwrap();
+        assert_eq!(
+            sent(&out),
+            [
+                (2, MessageType::Reply),
+                (1, MessageType::Failed),
+                (0, MessageType::Failed)
+            ]
+        );
+        assert_eq!(arb.failed, BTreeSet::from([(0, 0), (0, 1)]));
+
+        // Only once
+        let out = arb.on_release((0, 2), |_| false);
+        assert_eq!(sent(&out), [(1, MessageType::Reply)]);
+
+        // Requests that are no longer queued are forgotten
+        let out = arb.on_release((0, 1), |_| false);
+        assert_eq!(sent(&out), [(0, MessageType::Reply)]);
+        assert!(arb.failed.is_empty());
+    }
+
+    #[test]
+    fn arbiter_fails_against_phantom() {
+        let mut arb = Arbiter {
+            phantom: Some(((0, 2), 5)),
+            ..Default::default()
+        };
+
+        // Same timestamp as the phantom, higher id: outranks it, so waits
+        let out = arb.on_request(req(5, 3)).unwrap();
+        assert!(out.is_empty());
+
+        // Same timestamp, lower id: loses to the phantom
+        let out = arb.on_request(req(5, 1)).unwrap();
+        assert_eq!(sent(&out), [(1, MessageType::Failed)]);
+
+        // Older than the phantom, but same timestamp as a queued request
+        // with a higher id
+        let out = arb.on_request(req(5, 0)).unwrap();
+        assert_eq!(sent(&out), [(0, MessageType::Failed)]);
+        let out = arb.on_request(req(4, 0)).unwrap();
+        assert!(out.is_empty());
+
+        // The holder turns up again and gets its lock back
+        let out = arb.on_request(req(5, 2)).unwrap();
+        assert_eq!(sent(&out), [(2, MessageType::Reply)]);
+        assert_eq!(arb.phantom, None);
+    }
+
+    #[test]
+    fn arbiter_yield_and_release() {
+        let mut arb = Arbiter::default();
+        arb.on_request(req(1, 1)).unwrap();
+        arb.on_request(req(0, 2)).unwrap();
+        arb.on_request(req(0, 3)).unwrap();
+
+        // The holder yields: the oldest request gets the lock, ties to the
+        // higher id, and the holder waits its turn again. Node 2 was only
+        // inquired for, so both are told they lost.
+        let out = arb.on_yield((0, 1), 1, 2, |_| false).unwrap();
+        assert_eq!(
+            sent(&out),
+            [
+                (3, MessageType::Reply),
+                (2, MessageType::Failed),
+                (1, MessageType::Failed)
+            ]
+        );
+        assert_eq!(arb.locked, Some(req(0, 3)));
+        assert!(!arb.inq);
+        assert!(arb.req.iter().any(|x| x.pid == (0, 1)));
+
+        let out = arb.on_release((0, 3), |_| false);
+        assert_eq!(sent(&out), [(2, MessageType::Reply)]);
+        let out = arb.on_release((0, 2), |_| false);
+        assert_eq!(sent(&out), [(1, MessageType::Reply)]);
+        let out = arb.on_release((0, 1), |_| false);
+        assert!(out.is_empty());
+        assert_eq!(arb.locked, None);
+
+        assert!(arb.on_yield((0, 1), 1, 2, |_| false).is_err());
+    }
+
+    #[test]
+    fn arbiter_reclaims_from_suspects() {
+        let mut arb = Arbiter::default();
+        arb.on_request(req(1, 1)).unwrap();
+        arb.on_request(req(2, 2)).unwrap();
+        arb.on_request(req(3, 3)).unwrap();
+
+        // Node 2 is skipped as well, but stays queued
+        let down = |x: (u64, u64)| x.1 == 1 || x.1 == 2;
+        let out = arb.reclaim(down);
+        assert_eq!(sent(&out), [(3, MessageType::Reply)]);
+        assert!(arb.req.iter().any(|x| x.pid == (0, 2)));
+        assert!(arb.reclaim(down).is_empty());
+
+        // A lock whose holder is still up stays where it is
+        let mut arb = Arbiter::default();
+        arb.on_request(req(1, 1)).unwrap();
+        arb.on_request(req(2, 2)).unwrap();
+        assert!(arb.reclaim(|x| x.1 == 2).is_empty());
+        assert_eq!(arb.locked, Some(req(1, 1)));
+    }
+
+    #[test]
+    fn requester_yields_once_failed() {
+        let mut r = Requester::new(&[0, 1, 2]);
+        assert!(r.on_message(0, MessageType::Reply).unwrap().is_empty());
+        assert!(r.on_message(1, MessageType::Reply).unwrap().is_empty());
+
+        // Nothing failed yet, so the lock is kept for now
+        assert!(r.on_message(0, MessageType::Inquire).unwrap().is_empty());
+        assert_eq!(r.status[&0], RequestStatus::Inquiring);
+        assert!(r.on_message(0, MessageType::Inquire).is_err());
+
+        // Now we cannot win, so the inquirer gets its lock back
+        let out = r.on_message(2, MessageType::Failed).unwrap();
+        assert_eq!(out, [(0, MessageType::Yield)]);
+        assert_eq!(r.status[&0], RequestStatus::Pending);
+        assert!(r.waiting());
+
+        // Once failed, inquiries are answered straight away
+        let out = r.on_message(1, MessageType::Inquire).unwrap();
+        assert_eq!(out, [(1, MessageType::Yield)]);
+        // An inquire we already yielded to
+        assert!(r.on_message(1, MessageType::Inquire).unwrap().is_empty());
+
+        for i in 0..3 {
+            r.on_message(i, MessageType::Reply).unwrap();
+        }
+        assert!(!r.waiting());
+        assert!(r.on_message(0, MessageType::Reply).is_err());
+    }
+
+    #[test]
+    fn requester_releases_late_grants() {
+        let mut r = Requester::new(&[0, 1]);
+        let out = r.on_message(5, MessageType::Reply).unwrap();
+        assert_eq!(out, [(5, MessageType::Release)]);
+        assert!(r.on_message(5, MessageType::Reply).unwrap().is_empty());
+    }
+
+    #[test]
+    fn coordinator_grants_in_timestamp_order() {
+        let mut c = Coordinator::default();
+        assert_eq!(c.on_request(req(5, 1)), Some(req(5, 1)));
+        assert_eq!(c.on_request(req(7, 2)), None);
+        assert_eq!(c.on_request(req(6, 3)), None);
+        // Same timestamp, higher id first
+        assert_eq!(c.on_request(req(6, 4)), None);
+
+        // Only the holder's release of that request frees the CS
+        assert_eq!(c.on_release((0, 2), 7), None);
+        assert_eq!(c.on_release((0, 1), 4), None);
+        assert_eq!(c.locked, Some(req(5, 1)));
+        assert_eq!(c.on_release((0, 1), 5), Some(req(6, 4)));
+        assert_eq!(c.on_release((0, 4), 6), Some(req(6, 3)));
+        assert_eq!(c.on_release((0, 3), 6), Some(req(7, 2)));
+        assert_eq!(c.on_release((0, 2), 7), None);
+        assert_eq!(c.locked, None);
+    }
+
+    #[test]
+    fn coordinator_drops_copies() {
+        let mut c = Coordinator::default();
+        assert!(c.on_request(req(1, 1)).is_some());
+        c.on_request(req(2, 2));
+        // Copies of the granted and the queued request
+        assert_eq!(c.on_request(req(1, 1)), None);
+        assert_eq!(c.on_request(req(2, 2)), None);
+        assert_eq!(c.req.len(), 1);
+
+        assert_eq!(c.on_release((0, 1), 1), Some(req(2, 2)));
+        assert_eq!(c.on_release((0, 2), 2), None);
+        // A late copy of a released request is not granted again, but a
+        // newer request is
+        assert_eq!(c.on_request(req(1, 1)), None);
+        assert_eq!(c.on_request(req(2, 2)), None);
+        assert_eq!(c.locked, None);
+        assert_eq!(c.on_request(req(3, 1)), Some(req(3, 1)));
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
     io::{Read, Write},
     net::{SocketAddr, TcpListener, TcpStream},
@@ -8,33 +8,64 @@ use std::{
         Arc, Mutex,
     },
     thread::{self, JoinHandle},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use either::Either::Right;
 use polling::{Event, Events, Poller};
-use rand::{distributions::Uniform, thread_rng};
+use rand::thread_rng;
 
 use crate::{
-    utils::{get_a_stream, get_msgs, Action, LogEntry, Message, MessageType},
+    chaos::Chaos,
+    clocks::{channel, Channel, Clock, ClockKind},
+    conn::{Conn, ACCEPT, MIN_BACKOFF},
+    detector::Detector,
+    membership::{joiner_addr, Changes},
+    protocol::{rc_request, Verdict},
+    snapshot::{InFlight, Local, Recorder, Side},
+    termination::{counts, detection, Safra, Step, ROUND_TIMEOUT},
+    topology::Router,
+    utils::{epoch_micros, get_msgs, Action, LogEntry, Message, MessageType},
+    wal::{Record, Wal},
     Params, Region,
 };
 
 pub struct RCNode {
     id: u128,
-    ips: HashMap<u128, SocketAddr>,
+    /// Grows as nodes join
+    ips: Mutex<HashMap<u128, SocketAddr>>,
     rx: TcpListener, // Listener
     pub init: Instant,
     seq: AtomicU64,
     pub mc: AtomicU64,
+    cs_mc: AtomicU64,       // Sent and read by the requester since the last exit
+    pub term_mc: AtomicU64, // Safra tokens and terminate notices, kept out of `mc`
     req_flag: AtomicBool,
     quorum: Mutex<HashMap<u128, (bool, Option<TcpStream>)>>,
+    fd: Option<Detector>,
+    done: AtomicBool,
+    wal: Option<Wal>,
+    chaos: Option<Arc<Chaos>>,
+    dir: String, // where logs go
+    changes: Mutex<Changes<u128>>,
+    join: bool,  // started outside the cluster
+    leave: bool, // leaves once done with its requests
+    leaving: AtomicBool,
+    term: Mutex<Safra<u128>>, // termination detection
+    snap: Mutex<Recorder<u128>>,
+    snapshots: Option<Duration>, // how often we start one, if at all
+    clock: Option<Mutex<Clock>>,
+    router: Option<Arc<Router>>, // sparse topology, if any
 }
 
 impl RCNode {
     pub fn new(id: u128, ips: HashMap<u128, SocketAddr>) -> Self {
         let rx = TcpListener::bind(ips.get(&id).unwrap()).unwrap();
+        Self::from_listener(id, ips, rx)
+    }
 
+    /// A node listening on `rx`, which is already bound
+    pub fn from_listener(id: u128, ips: HashMap<u128, SocketAddr>, rx: TcpListener) -> Self {
         let quorum = Mutex::new(
             (0..id)
                 .map(|x| (x, (true, None))) // Good stuff
@@ -45,35 +76,168 @@ impl RCNode {
         Self {
             id,
             rx,
-            ips,
+            ips: Mutex::new(ips),
             init: Instant::now(),
             seq: 0.into(),
             req_flag: false.into(),
             quorum,
             mc: 0.into(),
+            cs_mc: 0.into(),
+            term_mc: 0.into(),
+            fd: None,
+            done: false.into(),
+            wal: None,
+            chaos: None,
+            dir: "log/rc".to_string(),
+            changes: Mutex::default(),
+            join: false,
+            leave: false,
+            leaving: false.into(),
+            term: Mutex::default(),
+            snap: Mutex::default(),
+            snapshots: None,
+            clock: None,
+            router: None,
         }
     }
 
-    fn get_requester_poller(&self, streams: &Vec<(u128, TcpStream)>) -> Poller {
-        let poller = Poller::new().unwrap();
+    /// Sends every message through a fault-injecting transport scripted by
+    /// `path`, and records CS intervals for the safety check. `ips` lists all
+    /// node addresses in order.
+    pub fn with_chaos(mut self, path: &str, ips: &[SocketAddr]) -> Self {
+        let me = self.ips.get_mut().unwrap()[&self.id];
+        let chaos = Arc::new(Chaos::new(me, path, ips));
+        self.fd = self.fd.map(|fd| fd.with_chaos(chaos.clone()));
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Starts outside the cluster and joins it. `ips` lists every node that
+    /// may be a member; those that cannot be reached are taken to be out.
+    /// A newcomer holds no permissions, so it asks every member before its
+    /// first CS.
+    pub fn with_join(mut self, ips: HashMap<u128, SocketAddr>) -> Self {
+        let q = self.quorum.get_mut().unwrap();
+        *q = ips.keys().map(|&x| (x, (x != self.id, None))).collect();
+        self.ips = Mutex::new(ips);
+        self.join = true;
+        self
+    }
+
+    /// Leaves the cluster once done with its requests, instead of serving
+    /// the others until they are all done. Its peers stop asking for its
+    /// permission, which amounts to handing it to them.
+    pub fn with_leave(mut self) -> Self {
+        self.leave = true;
+        self
+    }
+
+    /// Starts a global snapshot every `period`, written to the log
+    /// directory as `snapshot_<number>_<id>.json` by every node.
+    pub fn with_snapshots(mut self, period: Duration) -> Self {
+        self.snapshots = Some(period);
+        self
+    }
+
+    /// Stamps every message and log entry with a causal clock. Nodes are
+    /// numbered by id.
+    pub fn with_clock(mut self, kind: ClockKind) -> Self {
+        self.clock = Some(Mutex::new(Clock::new(kind, self.id as usize)));
+        self
+    }
+
+    /// Only connects to neighbours in a topology, and relays for the others.
+    pub fn with_router(mut self, router: Router) -> Self {
+        self.router = Some(Arc::new(router));
+        self
+    }
+
+    pub fn with_log_dir(mut self, dir: &str) -> Self {
+        self.dir = dir.to_string();
+        self
+    }
+
+    /// Persists the clock and permissions to `path`, restoring them if the
+    /// node is restarting after a crash.
+    pub fn with_wal(mut self, path: &str) -> Self {
+        let wal = Wal::open(path);
+        self.seq = wal.state.seq.into();
+        for (pid, x) in self.quorum.get_mut().unwrap().iter_mut() {
+            if let Some(&perm) = wal.state.quorum.get(pid) {
+                x.0 = perm;
+            }
+        }
+        println!("Recovered: {:?}", wal.state);
+        self.wal = Some(wal);
+        self
+    }
+
+    fn persist(&self, rec: Record) {
+        if let Some(wal) = &self.wal {
+            wal.append(rec);
+        }
+    }
+
+    /// Runs a heartbeat failure detector alongside the listener.
+    pub fn with_detector(mut self, timeout: Duration) -> Self {
+        let peers = self
+            .ips
+            .get_mut()
+            .unwrap()
+            .iter()
+            .map(|(&x, &a)| (Right(x), a))
+            .collect();
+        let fd = Detector::new(Right(self.id), peers, timeout);
+        self.fd = Some(match &self.chaos {
+            Some(chaos) => fd.with_chaos(chaos.clone()),
+            None => fd,
+        });
+        self
+    }
+
+    fn suspected(&self, pid: u128) -> bool {
+        self.fd.as_ref().is_some_and(|fd| fd.suspected(&Right(pid)))
+    }
+
+    /// Pollers wake up every heartbeat period to notice suspicions.
+    fn poll_timeout(&self) -> Option<Duration> {
+        self.fd.as_ref().map(|fd| fd.period())
+    }
+
+    /// The listener wakes it up when the membership changes.
+    fn get_requester_poller(&self, streams: &[(u128, Conn)]) -> Arc<Poller> {
+        let poller = Arc::new(Poller::new().unwrap());
         streams.iter().enumerate().for_each(|(i, (_, x))| unsafe {
-            poller.add(x, Event::readable(i)).unwrap();
+            poller.add(x.stream().unwrap(), Event::readable(i)).unwrap();
         });
+        self.changes.lock().unwrap().set_waker(poller.clone());
         poller
     }
 
-    fn request_cs(&self, streams: &Vec<(u128, TcpStream)>) -> usize {
+    /// Returns the nodes whose permission we are waiting for, and the
+    /// timestamp of the request.
+    fn request_cs(
+        &self,
+        streams: &mut Vec<(u128, Conn)>,
+        poller: &Poller,
+    ) -> (HashSet<u128>, u128) {
         self.req_flag.store(true, Ordering::SeqCst);
+        let ts = self.seq.load(Ordering::SeqCst) as u128;
+        self.persist(Record::Clock(ts as u64 + 1));
         let q = self.quorum.lock().unwrap();
-        let mut count = 0;
-        for (pid, stream) in streams.iter() {
-            if q[&pid].0 {
-                count += 1;
-                self.send(stream, MessageType::Request);
+        // The listener only gives a newcomer our permission after telling us
+        // it joined, so under the lock every node we must ask has a stream.
+        self.catch_up(streams, poller, false);
+        let mut waiting = HashSet::new();
+        for (pid, conn) in streams.iter_mut() {
+            // A crashed node will never reply, so don't wait for it.
+            if q.get(pid).is_some_and(|x| x.0) && !self.suspected(*pid) {
+                waiting.insert(*pid);
+                self.send_at(conn, *pid, MessageType::Request, ts);
             }
         }
         self.seq.fetch_add(1, Ordering::SeqCst);
-        count
+        (waiting, ts)
     }
 
     fn log(&self, out: &mut Vec<LogEntry>, act: Action) {
@@ -81,94 +245,261 @@ impl RCNode {
             pid: Right(self.id),
             ts: self.init.elapsed().as_micros(),
             act,
+            clock: self.clock.as_ref().map(|x| x.lock().unwrap().tick()),
         });
     }
 
-    fn send(&self, mut stream: &TcpStream, typ: MessageType) {
+    /// A message from us, stamped with our clock if we keep one. `to` is the
+    /// channel it goes on, if it has one.
+    fn message(&self, typ: MessageType, ts: u128, to: Option<Channel>) -> Message {
+        let msg = Message::new_rc(self.id, typ, ts);
+        match &self.clock {
+            Some(clock) => msg.with_clock(clock.lock().unwrap().send(to)),
+            None => msg,
+        }
+    }
+
+    /// Takes in the clock of a message from `from`.
+    fn receive(&self, from: u128, msg: &Message) {
+        if let Some(clock) = self.clock.as_ref().filter(|_| !msg.clock.is_empty()) {
+            clock.lock().unwrap().receive(from as usize, &msg.clock);
+        }
+    }
+
+    /// Sends on an incoming stream. A broken stream is left for the poller to
+    /// notice; the requester on the other end will resend.
+    fn send(&self, mut stream: &TcpStream, to: u128, typ: MessageType) {
         self.mc.fetch_add(1, Ordering::SeqCst);
-        stream
-            .write_all(&*<Message as Into<Vec<u8>>>::into(Message::new_rc(
-                self.id,
-                typ,
-                self.seq.load(Ordering::SeqCst) as u128,
-            )))
-            .unwrap();
+        let seq = self.seq.load(Ordering::SeqCst) as u128;
+        let msg = self.message(typ, seq, channel(stream));
+        let _ = match &self.chaos {
+            Some(chaos) => chaos
+                .write(stream, self.ips.lock().unwrap()[&to], msg)
+                .map(|_| ()),
+            None => stream.write_all(&Vec::from(msg)),
+        };
     }
 
-    fn get_listener_poller(&self, params: &Params) -> (Poller, Vec<TcpStream>) {
-        let mut streams = vec![];
+    fn send_at(&self, conn: &mut Conn, to: u128, typ: MessageType, ts: u128) {
+        self.mc.fetch_add(1, Ordering::SeqCst);
+        self.cs_mc.fetch_add(1, Ordering::SeqCst);
+        let msg = self.message(typ, ts, conn.stream().and_then(channel));
+        let copies = conn.send(msg);
+        if counts(typ) {
+            self.term.lock().unwrap().sent(to, copies);
+        }
+    }
 
+    /// Connections are accepted as they come, so restarted peers can rejoin.
+    fn get_listener_poller(&self) -> Poller {
         let poller = Poller::new().unwrap();
-        for stream in self.rx.incoming() {
-            match stream {
-                Ok(x) => {
-                    unsafe { poller.add(&x, Event::readable(streams.len())).unwrap() };
-                    streams.push(x);
+        unsafe { poller.add(&self.rx, Event::readable(ACCEPT)).unwrap() };
+        poller
+    }
+
+    /// A newcomer connects to whoever is up, and announces itself before
+    /// anything else so its requests are never seen before its join.
+    fn get_all_streams(&self) -> Vec<(u128, Conn)> {
+        let ips = self.ips.lock().unwrap().clone();
+        let mut pids: Vec<_> = ips.keys().copied().collect();
+        pids.sort();
+        let mut out = vec![];
+        for pid in pids {
+            let conn = if self.join {
+                Conn::try_new(ips[&pid], self.router.clone())
+            } else {
+                Some(Conn::new(ips[&pid], self.router.clone()))
+            };
+            match conn {
+                Some(conn) => out.push((pid, conn.with_chaos(self.chaos.clone()))),
+                None => {
+                    self.quorum.lock().unwrap().remove(&pid);
                 }
-                Err(_) => {
-                    // thread::sleep(Duration::from_micros(10));
+            }
+        }
+        if self.join {
+            let port = ips[&self.id].port() as u128;
+            for (pid, conn) in out.iter_mut() {
+                self.send_at(conn, *pid, MessageType::Join, port);
+            }
+        }
+        out
+    }
+
+    /// Acts on the joins and leaves the listener has seen: newcomers get a
+    /// connection, and departed nodes a `Leave` after our last message to
+    /// them. Returns the departed nodes.
+    fn catch_up(&self, streams: &mut Vec<(u128, Conn)>, poller: &Poller, done: bool) -> Vec<u128> {
+        let (joined, left) = self.changes.lock().unwrap().take(done);
+        for (pid, addr) in joined {
+            let slot = streams.iter().position(|x| x.0 == pid);
+            if slot.is_some_and(|i| !streams[i].1.is_closed()) {
+                continue;
+            }
+            // Gone again already
+            let Some(conn) = Conn::try_new(addr, self.router.clone()) else {
+                continue;
+            };
+            let i = slot.unwrap_or(streams.len());
+            unsafe {
+                poller
+                    .add(conn.stream().unwrap(), Event::readable(i))
+                    .unwrap()
+            };
+            let conn = conn.with_chaos(self.chaos.clone());
+            self.term.lock().unwrap().reset(pid);
+            match slot {
+                Some(i) => streams[i].1 = conn,
+                None => streams.push((pid, conn)),
+            }
+        }
+        for &pid in left.iter() {
+            if let Some(i) = streams.iter().position(|x| x.0 == pid) {
+                self.snap.lock().unwrap().closed(Side::Requester, i);
+                let conn = &mut streams[i].1;
+                self.send_at(
+                    conn,
+                    pid,
+                    MessageType::Leave,
+                    self.seq.load(Ordering::SeqCst) as u128,
+                );
+                if let Some(stream) = conn.stream() {
+                    poller.delete(stream).unwrap();
                 }
+                conn.close();
             }
+        }
+        left
+    }
 
-            // All connections made.
-            if streams.len() == params.n {
-                break;
+    /// Reads whatever a node sent, with ids as `RCNode` ids. On EOF the
+    /// connection is dropped from the poller and marked down.
+    fn read_from(&self, conn: &mut Conn, poller: &Poller, key: usize) -> Vec<Message> {
+        let Some(mut stream) = conn.stream() else {
+            return vec![];
+        };
+        let mut buf = [0; 1024];
+        match stream.read(&mut buf) {
+            Ok(ref b) if *b > 0 => {
+                poller.modify(stream, Event::readable(key)).unwrap();
+                let mut msgs = get_msgs(conn.inbox(), &buf[..*b]);
+                self.cs_mc.fetch_add(msgs.len() as u64, Ordering::SeqCst);
+                for msg in msgs.iter_mut() {
+                    msg.flip();
+                    self.receive(msg.id.expect_right(""), msg);
+                }
+                msgs
+            }
+            _ => {
+                println!("Lost connection to node {}", key);
+                poller.delete(stream).unwrap();
+                conn.down();
+                self.snap.lock().unwrap().closed(Side::Requester, key);
+                vec![]
             }
         }
+    }
 
-        (poller, streams)
+    /// Retry dropped connections. Returns the nodes we got back.
+    fn reconnect(&self, streams: &mut [(u128, Conn)], poller: &Poller) -> Vec<u128> {
+        let mut out = vec![];
+        for (i, (pid, conn)) in streams.iter_mut().enumerate() {
+            if !self.suspected(*pid) && conn.reconnect() {
+                println!("Reconnected to {}", pid);
+                unsafe {
+                    poller
+                        .add(conn.stream().unwrap(), Event::readable(i))
+                        .unwrap()
+                };
+                // Requests are resent by the caller if still needed
+                conn.retain(|x| x.typ != MessageType::Request);
+                self.term.lock().unwrap().reset(*pid);
+                conn.flush();
+                out.push(*pid);
+            }
+        }
+        out
     }
 
-    fn get_all_streams(&self, params: &Params) -> Vec<(u128, TcpStream)> {
-        (0..params.n as u128)
-            // .filter(|x| *x != self.id)
-            .map(|x| (x, get_a_stream(&self.ips[&x])))
-            .collect()
+    /// Wakes up often enough to retry dropped connections.
+    fn requester_timeout(&self, streams: &[(u128, Conn)]) -> Option<Duration> {
+        if streams.iter().any(|x| !x.1.is_up() && !x.1.is_closed()) {
+            Some(MIN_BACKOFF)
+        } else {
+            self.poll_timeout()
+        }
     }
 
-    fn enter_cs(&self, streams: &mut Vec<(u128, TcpStream)>, poller: &Poller) -> Vec<LogEntry> {
+    fn enter_cs(&self, streams: &mut Vec<(u128, Conn)>, poller: &Poller) -> Vec<LogEntry> {
         // Send a request to all the nodes in the quorum
-        let c = self.request_cs(streams);
+        let (mut waiting, ts) = self.request_cs(streams, poller);
         // println!("Request sent: {c}");
 
         // wait for the quorum to reply
-        let mut replies = 0;
         let mut out = vec![];
+        self.log(&mut out, Action::Ask(ts));
 
         let mut events = Events::new();
-        while replies < c {
+        while waiting.iter().any(|&x| !self.suspected(x)) {
             events.clear();
-            poller.wait(&mut events, None).unwrap();
+            poller
+                .wait(&mut events, self.requester_timeout(streams))
+                .unwrap();
+
+            // The node may have restarted and lost our request
+            for pid in self.reconnect(streams, poller) {
+                if waiting.contains(&pid) {
+                    let conn = &mut streams.iter_mut().find(|x| x.0 == pid).unwrap().1;
+                    self.send_at(conn, pid, MessageType::Request, ts);
+                }
+            }
+
+            // A node that left no longer has a say
+            for pid in self.catch_up(streams, poller, false) {
+                waiting.remove(&pid);
+            }
 
             for ev in events.iter() {
-                let (_, stream) = streams.get_mut(ev.key).unwrap();
-                let mut buf = [0; 1024];
-                match stream.read(&mut buf) {
-                    Ok(ref b) if *b > 0 => {
-                        let mut msg = Message::from(&buf[..*b]);
-                        msg.flip();
-                        match msg.typ {
-                            MessageType::Reply => {
-                                replies += 1;
-                                self.log(&mut out, Action::Reply(msg.id));
-                            }
-                            _ => {
-                                panic!("Unexpected message")
-                            }
-                        }
+                let pid = streams[ev.key].0;
+                for msg in self.read_from(&mut streams[ev.key].1, poller, ev.key) {
+                    if msg.typ != MessageType::Marker {
+                        self.snap.lock().unwrap().on_message(
+                            Side::Requester,
+                            ev.key,
+                            InFlight {
+                                from: pid,
+                                typ: msg.typ,
+                                ts: msg.ts,
+                            },
+                        );
                     }
-                    _ => {
-                        // thread::sleep(Duration::from_micros(10));
+                    match msg.typ {
+                        MessageType::Reply => {
+                            waiting.remove(&pid);
+                            self.log(&mut out, Action::Reply(msg.id));
+                        }
+                        MessageType::Marker => {
+                            let mut from: Vec<_> = waiting.iter().copied().collect();
+                            from.sort();
+                            let state = Local::Waiting { ts, from };
+                            self.snapshot_requester(streams, ev.key, msg.ts as u64, state);
+                        }
+                        _ => {
+                            panic!("Unexpected message")
+                        }
                     }
                 }
-                buf.fill(0);
-                poller.modify(stream, Event::readable(ev.key)).unwrap();
             }
+            self.save_snapshot();
         }
 
-        // We are in CS now. Set everything to false.
+        // We are in CS now. Set everything to false, except for suspected
+        // nodes that never replied: we still need to ask them once they are back.
         for x in self.quorum.lock().unwrap().iter_mut() {
-            x.1 .0 = false;
+            if !waiting.contains(x.0) && x.1 .0 {
+                self.persist(Record::Permission(*x.0, false));
+                x.1 .0 = false;
+            }
         }
 
         // println!("CS acquired");
@@ -179,102 +510,364 @@ impl RCNode {
     fn exit_cs(&self) {
         self.req_flag.store(false, Ordering::SeqCst);
         let mut q = self.quorum.lock().unwrap();
-        for (_, maybe) in q.iter_mut() {
-            if let Some(out) = &maybe.1 {
-                self.send(out, MessageType::Reply);
-                maybe.1 = None;
+        for (pid, maybe) in q.iter_mut() {
+            if let Some(out) = maybe.1.take() {
+                if !self.suspected(*pid) {
+                    self.send(&out, *pid, MessageType::Reply);
+                }
             }
         }
         // println!("CS exited: {c}");
         // todo!()
     }
 
-    fn terminate(&self, streams: &mut Vec<(u128, TcpStream)>) {
-        for (_, stream) in streams.iter() {
-            self.send(stream, MessageType::Terminate);
+    /// Done requesting: tells everyone if we are leaving, and lets the
+    /// termination token through. From here on the listener answers
+    /// newcomers.
+    fn terminate(&self, streams: &mut Vec<(u128, Conn)>, poller: &Poller) {
+        if self.leave {
+            // Whatever we deferred, and everything from now on, gets a reply
+            self.leaving.store(true, Ordering::SeqCst);
+            self.exit_cs();
+        }
+        self.catch_up(streams, poller, true);
+        if self.leave {
+            for (pid, conn) in streams.iter_mut() {
+                if !self.suspected(*pid) {
+                    self.send_at(
+                        conn,
+                        *pid,
+                        MessageType::Leave,
+                        self.seq.load(Ordering::SeqCst) as u128,
+                    );
+                }
+            }
         }
+        self.term.lock().unwrap().passive();
+        self.pass_token();
+    }
+
+    /// Live members in id order, the ring the termination token goes round.
+    /// A leaving node is not on it.
+    fn ring(&self) -> Vec<u128> {
+        let mut out: Vec<_> = self
+            .quorum
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .filter(|&x| !self.suspected(x))
+            .filter(|&x| !self.leave || x != self.id)
+            .collect();
+        out.sort();
+        out
     }
 
-    fn listen(&self, poller: Poller, streams: &Vec<TcpStream>, params: &Params) -> Vec<LogEntry> {
+    /// Passes the termination token on if we hold it, or tells everyone the
+    /// run is over.
+    fn pass_token(&self) {
+        let ring = self.ring();
+        let step = self.term.lock().unwrap().step(self.id, &ring);
+        match step {
+            Step::Pass(to, token) => self.tell(to, MessageType::Token, token.into()),
+            Step::Announce => {
+                let ts = self.seq.load(Ordering::SeqCst) as u128;
+                for &pid in ring.iter() {
+                    self.tell(pid, MessageType::Terminate, ts);
+                }
+            }
+            Step::Idle => {}
+        }
+    }
+
+    /// A leaving node is done once every peer has answered its `Leave`, the
+    /// others once termination is detected.
+    fn finished(&self) -> bool {
+        if self.leaving.load(Ordering::SeqCst) {
+            let q = self.quorum.lock().unwrap();
+            q.keys().all(|&x| self.suspected(x))
+        } else {
+            self.term.lock().unwrap().is_done()
+        }
+    }
+
+    /// Wakes up often enough to resend a lost token.
+    fn listener_timeout(&self) -> Option<Duration> {
+        Some(
+            self.poll_timeout()
+                .map_or(ROUND_TIMEOUT, |x| x.min(ROUND_TIMEOUT)),
+        )
+    }
+
+    /// Deliver whatever is still queued for nodes that are down.
+    fn drain(&self, streams: &mut [(u128, Conn)], poller: &Poller) {
+        let mut events = Events::new();
+        while streams
+            .iter()
+            .any(|(pid, conn)| conn.has_queued() && !self.suspected(*pid))
+        {
+            events.clear();
+            poller.wait(&mut events, Some(MIN_BACKOFF)).unwrap();
+            for ev in events.iter() {
+                // Nothing left to act on, but EOF still needs noticing
+                self.read_from(&mut streams[ev.key].1, poller, ev.key);
+            }
+            self.reconnect(streams, poller);
+        }
+    }
+
+    fn listen(&self, poller: Poller) -> Vec<LogEntry> {
+        let mut streams = vec![];
+        let mut open = HashSet::new(); // streams not at EOF yet
+        let mut pending = vec![]; // part of a message, per stream
         let mut out = vec![];
-        let mut term = 0;
         let mut events = Events::new();
 
-        while term < params.n {
+        while !self.finished() {
             events.clear();
-            poller.wait(&mut events, None).unwrap();
+            poller.wait(&mut events, self.listener_timeout()).unwrap();
             for ev in events.iter() {
-                let mut stream = streams.get(ev.key).unwrap();
+                if ev.key == ACCEPT {
+                    let accepted = self.rx.accept().ok().and_then(|(x, _)| match &self.router {
+                        Some(router) => router.accept(x),
+                        None => Some(x),
+                    });
+                    if let Some(x) = accepted {
+                        unsafe { poller.add(&x, Event::readable(streams.len())).unwrap() };
+                        open.insert(streams.len());
+                        pending.push(vec![]);
+                        streams.push(x);
+                    }
+                    poller.modify(&self.rx, Event::readable(ACCEPT)).unwrap();
+                    continue;
+                }
+
+                let mut stream = &streams[ev.key];
                 let mut buf = [0; 128];
-                match stream.read(&mut buf) {
-                    Ok(ref b) if *b > 0 => {
-                        let msgs = get_msgs(&mut buf, b);
-
-                        for mut msg in msgs {
-                            msg.flip();
-                            let id = msg.id.expect_right("");
-                            match msg.typ {
-                                MessageType::Request => {
-                                    self.log(&mut out, Action::Query(msg.id));
-                                    // Lamport clock
-                                    if msg.ts > self.seq.load(Ordering::SeqCst) as u128 {
-                                        self.seq.store((msg.ts + 1) as u64, Ordering::SeqCst);
-
-                                        // Unfulfilled request
-                                        self.quorum.lock().unwrap().get_mut(&id).unwrap().1 =
-                                            Some(stream.try_clone().unwrap());
-                                    } else if self.req_flag.load(Ordering::SeqCst) {
-                                        // Request is queued
-                                        self.send(stream, MessageType::Reply);
-                                        self.quorum.lock().unwrap().get_mut(&id).unwrap().0 = true;
-                                    } else {
-                                        self.send(stream, MessageType::Reply);
-                                        self.quorum.lock().unwrap().get_mut(&id).unwrap().0 = true;
-                                    }
-                                }
-                                MessageType::Reply => {
-                                    // println!("Nope.");
-                                    dbg!(msg);
-                                    // self.log(&mut out, Action::Reply(msg.id));
-                                    // if id != self.id {self.quorum.lock().unwrap().get_mut(&msg.id.expect_right("")).unwrap().0 = true;}
-                                }
-                                MessageType::Terminate => {
-                                    term += 1;
+                let msgs = match stream.read(&mut buf) {
+                    Ok(ref b) if *b > 0 => get_msgs(&mut pending[ev.key], &buf[..*b]),
+                    _ => {
+                        // The requester will reconnect and resend if it still cares.
+                        poller.delete(stream).unwrap();
+                        open.remove(&ev.key);
+                        self.snap.lock().unwrap().closed(Side::Listener, ev.key);
+                        continue;
+                    }
+                };
+                // Reset poller
+                poller.modify(stream, Event::readable(ev.key)).unwrap();
+
+                for mut msg in msgs {
+                    msg.flip();
+                    let id = msg.id.expect_right("");
+                    self.receive(id, &msg);
+                    if counts(msg.typ) {
+                        self.term.lock().unwrap().received(id, ev.key);
+                    }
+                    if msg.typ != MessageType::Marker {
+                        self.snap.lock().unwrap().on_message(
+                            Side::Listener,
+                            ev.key,
+                            InFlight {
+                                from: id,
+                                typ: msg.typ,
+                                ts: msg.ts,
+                            },
+                        );
+                    }
+                    match msg.typ {
+                        MessageType::Request => {
+                            self.log(&mut out, Action::Query(msg.id));
+                            // Lamport clock
+                            let mut seq = self.seq.load(Ordering::SeqCst);
+                            let verdict = rc_request(msg.ts, &mut seq);
+                            let mut q = self.quorum.lock().unwrap();
+                            match verdict {
+                                // A leaving node has nothing left to wait for
+                                Verdict::Defer if !self.leaving.load(Ordering::SeqCst) => {
+                                    self.persist(Record::Clock(seq));
+                                    self.seq.store(seq, Ordering::SeqCst);
+                                    q.get_mut(&id).unwrap().1 = Some(stream.try_clone().unwrap());
                                 }
                                 _ => {
-                                    dbg!(msg);
-                                    panic!("Unexpected message")
+                                    self.persist(Record::Permission(id, true));
+                                    self.send(stream, id, MessageType::Reply);
+                                    q.get_mut(&id).unwrap().0 = true;
                                 }
                             }
                         }
-                    }
-                    _ => {
-                        // thread::sleep(Duration::from_micros(10))
+                        MessageType::Reply => {
+                            // println!("Nope.");
+                            dbg!(msg);
+                            // self.log(&mut out, Action::Reply(msg.id));
+                            // if id != self.id {self.quorum.lock().unwrap().get_mut(&msg.id.expect_right("")).unwrap().0 = true;}
+                        }
+                        MessageType::Terminate => {
+                            self.log(&mut out, Action::Terminate);
+                            self.term.lock().unwrap().finish();
+                        }
+                        MessageType::Token => {
+                            self.term.lock().unwrap().on_token(msg.ts.into());
+                        }
+                        MessageType::Marker => {
+                            self.snapshot_listener(&streams, &open, msg.ts as u64, ev.key);
+                        }
+                        MessageType::Join => {
+                            self.log(&mut out, Action::Join(msg.id));
+                            let addr = joiner_addr(stream, &msg);
+                            self.ips.lock().unwrap().insert(id, addr);
+                            // We hold the permission until it asks
+                            self.quorum
+                                .lock()
+                                .unwrap()
+                                .entry(id)
+                                .or_insert((false, None));
+                            self.term.lock().unwrap().changed();
+                            // A node that left said so to everyone but the newcomer
+                            if !self.changes.lock().unwrap().join(id, addr) && self.leave {
+                                let ts = self.seq.load(Ordering::SeqCst) as u128;
+                                self.tell(id, MessageType::Leave, ts);
+                            }
+                        }
+                        MessageType::Leave => {
+                            self.log(&mut out, Action::Leave(msg.id));
+                            let member = self.quorum.lock().unwrap().remove(&id).is_some();
+                            self.term.lock().unwrap().changed();
+                            // Answer for a requester that is done. A leaving
+                            // node has already said `Leave` to everyone.
+                            if member && !self.changes.lock().unwrap().leave(id) && !self.leave {
+                                let ts = self.seq.load(Ordering::SeqCst) as u128;
+                                self.tell(id, MessageType::Leave, ts);
+                            }
+                        }
+                        _ => {
+                            dbg!(msg);
+                            panic!("Unexpected message")
+                        }
                     }
                 }
-                // Clear buffer
-                buf.fill(0);
-                // Reset poller
-                poller.modify(stream, Event::readable(ev.key)).unwrap();
             }
+            self.pass_token();
+            self.save_snapshot();
         }
 
         out
     }
 
-    fn listener_thread(&self, params: Params) -> Vec<LogEntry> {
-        let (poller, streams) = self.get_listener_poller(&params);
-
-        self.listen(poller, &streams, &params)
+    /// Records the listener's part of snapshot `n`, whose marker came on
+    /// stream `key`, and passes the marker on to every requester. The
+    /// requester sends deferred replies under the same lock, so each is
+    /// either recorded as deferred or sent before our marker.
+    fn snapshot_listener(&self, streams: &[TcpStream], open: &HashSet<usize>, n: u64, key: usize) {
+        if !self.snap.lock().unwrap().on_marker(Side::Listener, n, key) {
+            return;
+        }
+        let q = self.quorum.lock().unwrap();
+        let mut permissions: Vec<_> = q.iter().map(|(&pid, x)| (pid, x.0)).collect();
+        permissions.sort();
+        let mut deferred: Vec<_> = q
+            .iter()
+            .filter(|x| x.1 .1.is_some())
+            .map(|x| *x.0)
+            .collect();
+        deferred.sort();
+        let state = Local::Rc {
+            seq: self.seq.load(Ordering::SeqCst),
+            requesting: self.req_flag.load(Ordering::SeqCst),
+            permissions,
+            deferred,
+        };
+        for &i in open.iter() {
+            let marker = self.message(MessageType::Marker, n as u128, channel(&streams[i]));
+            let _ = (&streams[i]).write_all(&Vec::from(marker));
+        }
+        drop(q);
+        let open = open.iter().copied().filter(|&i| i != key);
+        self.snap
+            .lock()
+            .unwrap()
+            .record(Side::Listener, state, open);
     }
 
-    fn requester_thread(
+    /// Records the requester's part of snapshot `n`, whose marker came on
+    /// stream `key`, and passes the marker on to every listener.
+    fn snapshot_requester(
         &self,
-        params: Params,
-        mut streams: Vec<(u128, TcpStream)>,
-    ) -> Vec<LogEntry> {
+        streams: &mut [(u128, Conn)],
+        key: usize,
+        n: u64,
+        state: Local<u128>,
+    ) {
+        if !self.snap.lock().unwrap().on_marker(Side::Requester, n, key) {
+            return;
+        }
+        for (_, conn) in streams.iter_mut().filter(|x| x.1.is_up()) {
+            let msg = self.message(
+                MessageType::Marker,
+                n as u128,
+                conn.stream().and_then(channel),
+            );
+            conn.send(msg);
+        }
+        let open = streams
+            .iter()
+            .enumerate()
+            .filter(|(i, x)| *i != key && x.1.is_up())
+            .map(|(i, _)| i);
+        self.snap
+            .lock()
+            .unwrap()
+            .record(Side::Requester, state, open);
+    }
+
+    /// Writes our share of a snapshot once both processes have recorded it.
+    fn save_snapshot(&self) {
+        let snap = self.snap.lock().unwrap().take(self.id);
+        if let Some(snap) = snap {
+            snap.write(&format!(
+                "{}/snapshot_{}_{}.json",
+                self.dir, snap.number, self.id
+            ));
+        }
+    }
+
+    /// Starts a snapshot every period, by sending ourselves a marker, until
+    /// the node is done.
+    fn snapshot_thread(&self) {
+        let Some(period) = self.snapshots else {
+            return;
+        };
+        while !self.done.load(Ordering::SeqCst) {
+            thread::sleep(period);
+            let n = self.snap.lock().unwrap().next();
+            self.tell(self.id, MessageType::Marker, n as u128);
+        }
+    }
+
+    /// Sends one message on a connection of its own, to a node the
+    /// requester has no connection to or is done with.
+    fn tell(&self, pid: u128, typ: MessageType, ts: u128) {
+        let addr = self.ips.lock().unwrap()[&pid];
+        if let Some(mut conn) = Conn::try_new(addr, self.router.clone()) {
+            if detection(typ) {
+                self.term_mc.fetch_add(1, Ordering::SeqCst);
+            } else {
+                self.mc.fetch_add(1, Ordering::SeqCst);
+            }
+            conn.send(self.message(typ, ts, None));
+        } else {
+            println!("Could not reach node {}", pid);
+        }
+    }
+
+    fn listener_thread(&self) -> Vec<LogEntry> {
+        let poller = self.get_listener_poller();
+
+        self.listen(poller)
+    }
+
+    fn requester_thread(&self, params: Params, mut streams: Vec<(u128, Conn)>) -> Vec<LogEntry> {
         let mut rng = thread_rng();
-        let u = Uniform::new(0.0, 1.0);
         let mut out = vec![];
 
         // All the nodes in the quorum
@@ -282,46 +875,93 @@ impl RCNode {
         let poller = self.get_requester_poller(&streams);
 
         // Send a request to all the nodes in the quorum
-        for _i in 0..params.k {
+        for i in 0..params.requests() {
             self.log(&mut out, Action::Internal);
-            params.sleep(u, &mut rng, Region::Out);
+            params.sleep(&mut rng, Region::Out, i, self.init);
 
             let replies = self.enter_cs(&mut streams, &poller);
             out.extend(replies);
 
             self.log(&mut out, Action::Acquire);
-            params.sleep(u, &mut rng, Region::In);
+            if let Some(chaos) = &self.chaos {
+                chaos.enter();
+            }
+            params.sleep(&mut rng, Region::In, i, self.init);
 
-            self.exit_cs();
+            if let Some(chaos) = &self.chaos {
+                chaos.exit();
+            }
+            // Before anyone hears of it, so the exit happens before the next entry
             self.log(&mut out, Action::Exit);
+            self.exit_cs();
+            self.log(
+                &mut out,
+                Action::Messages(self.cs_mc.swap(0, Ordering::SeqCst)),
+            );
         }
 
-        self.terminate(&mut streams);
+        self.terminate(&mut streams, &poller);
+        self.drain(&mut streams, &poller);
+        self.snap.lock().unwrap().requester_done();
+        self.save_snapshot();
 
         out
         // }
     }
 
-    fn listener_spawn(self: Arc<Self>, params: Params) -> JoinHandle<Vec<LogEntry>> {
-        thread::spawn(move || self.listener_thread(params))
+    /// Runs heartbeat rounds until the node is done, logging suspicions.
+    fn detector_thread(&self) -> Vec<LogEntry> {
+        let mut out = vec![];
+        let Some(fd) = &self.fd else {
+            return out;
+        };
+        while !self.done.load(Ordering::SeqCst) {
+            for (pid, up) in fd.tick() {
+                self.term.lock().unwrap().changed();
+                self.log(
+                    &mut out,
+                    if up {
+                        Action::Recover(pid)
+                    } else {
+                        Action::Suspect(pid)
+                    },
+                );
+            }
+        }
+        out
+    }
+
+    fn detector_spawn(self: Arc<Self>) -> JoinHandle<Vec<LogEntry>> {
+        thread::spawn(move || self.detector_thread())
+    }
+
+    fn listener_spawn(self: Arc<Self>) -> JoinHandle<Vec<LogEntry>> {
+        thread::spawn(move || self.listener_thread())
     }
 
     fn requester_spawn(
         self: Arc<Self>,
         params: Params,
-        streams: Vec<(u128, TcpStream)>,
+        streams: Vec<(u128, Conn)>,
     ) -> JoinHandle<Vec<LogEntry>> {
         thread::spawn(move || self.requester_thread(params, streams))
     }
 
     pub fn spawn(self: Arc<Self>, params: Params) {
-        let mut file = File::create(format!("log/rc/node_{}.log", self.id)).unwrap();
+        let mut file = File::create(format!("{}/node_{}.log", self.dir, self.id)).unwrap();
 
         // let init = Instant::now();
+        // Spawn a new thread to watch for crashed peers
+        let detector = self.clone().detector_spawn();
+
         // Spawn a new thread to listen for incoming messages
-        let listener = self.clone().listener_spawn(params);
+        let listener = self.clone().listener_spawn();
 
-        let streams = self.clone().get_all_streams(&params);
+        // Not joined: it may be asleep until the next snapshot
+        let snap = self.clone();
+        thread::spawn(move || snap.snapshot_thread());
+
+        let streams = self.clone().get_all_streams();
 
         println!("Connections established.");
 
@@ -331,13 +971,34 @@ impl RCNode {
         // Wait for the threads to finish
         let listener_log = listener.join().unwrap();
         let node_log = node.join().unwrap();
+        self.done.store(true, Ordering::SeqCst);
+        let detector_log = detector.join().unwrap();
+
+        // Lets logs of different nodes be put on one timeline
+        let start = LogEntry {
+            pid: Right(self.id),
+            ts: 0,
+            act: Action::Start(epoch_micros() - self.init.elapsed().as_micros()),
+            clock: None,
+        };
 
-        let mut log = [listener_log, node_log].concat();
+        let mut log = [vec![start], listener_log, node_log, detector_log].concat();
         log.sort_by_key(|x| x.ts);
 
         // TODO: Make a new display function for LogEntry
         for entry in log.iter() {
             writeln!(file, "{}", entry).unwrap();
         }
+
+        if let Some(chaos) = &self.chaos {
+            chaos.dump(&format!("{}/cs_{}.log", self.dir, self.id));
+        }
+        if let Some(clock) = &self.clock {
+            let path = format!("{}/clock_{}.json", self.dir, self.id);
+            clock.lock().unwrap().stats.write(&path);
+        }
+        if let Some(router) = &self.router {
+            router.linger(&self.rx);
+        }
     }
 }
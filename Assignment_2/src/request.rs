@@ -1,34 +1,34 @@
-use std::net::TcpStream;
+use std::hash::{Hash, Hasher};
 
 #[derive(Debug, Clone, Copy)]
-pub struct Request<'a> {
+pub struct Request {
     pub ts: u128,
     pub pid: (u64, u64),
-    pub stream: &'a TcpStream,
+    pub stream: usize, // index of the connection in the listener
 }
 
-impl<'a> Request<'a> {
-    pub fn new(ts: u128, pid: (u64, u64), stream: &'a TcpStream) -> Self {
+impl Request {
+    pub fn new(ts: u128, pid: (u64, u64), stream: usize) -> Self {
         Self { ts, pid, stream }
     }
 }
 
-impl<'a> PartialEq for Request<'a> {
+impl PartialEq for Request {
     fn eq(&self, other: &Self) -> bool {
         self.ts == other.ts && self.pid == other.pid
     }
 }
 
-impl<'a> PartialOrd for Request<'a> {
+impl PartialOrd for Request {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         // We want a minheap, coz least timestamp gets priority
-        other.ts.partial_cmp(&self.ts)
+        Some(self.cmp(other))
     }
 }
 
-impl<'a> Eq for Request<'a> {}
+impl Eq for Request {}
 
-impl<'a> Ord for Request<'a> {
+impl Ord for Request {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         if self.ts != other.ts {
             other.ts.cmp(&self.ts)
@@ -37,3 +37,10 @@ impl<'a> Ord for Request<'a> {
         }
     }
 }
+
+impl Hash for Request {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ts.hash(state);
+        self.pid.hash(state);
+    }
+}
@@ -0,0 +1,313 @@
+use std::{collections::HashSet, fs, hash::Hash, mem};
+
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::utils::MessageType;
+
+/// The two processes of a node. Each records its own state and the channels
+/// into it: the listener those from requesters, the requester those from
+/// listeners.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Listener,
+    Requester,
+}
+
+/// What a process was doing when it recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Local<P> {
+    /// RC listener. `permissions` lists, for each member, whether we must
+    /// ask it before entering the CS.
+    Rc {
+        seq: u64,
+        requesting: bool,
+        permissions: Vec<(P, bool)>,
+        deferred: Vec<P>,
+    },
+    /// Maekawa arbiter, with its queue in priority order.
+    Maekawa {
+        seq: u64,
+        locked: Option<(P, u128)>,
+        queue: Vec<(P, u128)>,
+        phantom: Option<(P, u128)>,
+        inquired: bool,
+    },
+    /// A requester still missing replies to its request `ts`.
+    Waiting { ts: u128, from: Vec<P> },
+    /// A requester done with its requests.
+    Done,
+}
+
+/// A message that was on a channel when the snapshot was taken.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InFlight<P> {
+    pub from: P,
+    pub typ: MessageType,
+    pub ts: u128,
+}
+
+/// One node's share of a global snapshot, as written to disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot<P> {
+    pub number: u64,
+    pub id: P,
+    pub listener: Local<P>,
+    pub requester: Local<P>,
+    pub to_listener: Vec<InFlight<P>>,
+    pub to_requester: Vec<InFlight<P>>,
+}
+
+impl<P: Serialize> Snapshot<P> {
+    pub fn write(&self, path: &str) {
+        fs::write(path, serde_json::to_string_pretty(self).unwrap()).unwrap();
+    }
+}
+
+#[derive(Debug)]
+struct Part<P> {
+    state: Option<Local<P>>,
+    recording: HashSet<usize>, // channels still waiting for a marker
+    msgs: Vec<InFlight<P>>,
+}
+
+impl<P> Default for Part<P> {
+    fn default() -> Self {
+        Self {
+            state: None,
+            recording: HashSet::new(),
+            msgs: vec![],
+        }
+    }
+}
+
+impl<P> Part<P> {
+    fn complete(&self) -> bool {
+        self.state.is_some() && self.recording.is_empty()
+    }
+}
+
+/// Chandy–Lamport snapshots of a node, over the FIFO streams it already has.
+///
+/// Markers carry the snapshot number in `ts`. A process records its state
+/// on the first marker of a snapshot, sends a marker on each of its
+/// outgoing streams, and records what arrives on each incoming stream until
+/// that stream's marker or EOF. Nodes that initiate the same number at once
+/// end up in one snapshot; a higher number abandons a snapshot in progress.
+///
+/// The clock and RC permissions are shared by both processes, and are
+/// recorded by the listener. One-off connections, such as those carrying
+/// the termination token, and streams opened after a process recorded are
+/// not recorded.
+#[derive(Debug)]
+pub struct Recorder<P> {
+    number: u64, // latest seen
+    listener: Part<P>,
+    requester: Part<P>,
+    requester_done: bool,
+    written: bool,
+}
+
+impl<P> Default for Recorder<P> {
+    fn default() -> Self {
+        Self {
+            number: 0,
+            listener: Part::default(),
+            requester: Part::default(),
+            requester_done: false,
+            written: false,
+        }
+    }
+}
+
+impl<P: Copy + Eq + Hash> Recorder<P> {
+    fn part(&mut self, side: Side) -> &mut Part<P> {
+        match side {
+            Side::Listener => &mut self.listener,
+            Side::Requester => &mut self.requester,
+        }
+    }
+
+    /// Number for a snapshot this node starts.
+    pub fn next(&self) -> u64 {
+        self.number + 1
+    }
+
+    /// A marker for snapshot `n` arrived on stream `key`. Returns whether
+    /// the process has to record now.
+    pub fn on_marker(&mut self, side: Side, n: u64, key: usize) -> bool {
+        if n < self.number {
+            return false;
+        }
+        if n > self.number {
+            self.number = n;
+            self.listener = Part::default();
+            self.requester = Part::default();
+            if self.requester_done {
+                self.requester.state = Some(Local::Done);
+            }
+            self.written = false;
+        }
+        let part = self.part(side);
+        part.recording.remove(&key);
+        part.state.is_none()
+    }
+
+    /// The process recorded `state` and sent its markers. `open` are its
+    /// incoming streams, bar the one the marker came on.
+    pub fn record(&mut self, side: Side, state: Local<P>, open: impl Iterator<Item = usize>) {
+        let part = self.part(side);
+        part.state = Some(state);
+        part.recording = open.collect();
+    }
+
+    /// Keeps `msg` if it arrived on a stream being recorded.
+    pub fn on_message(&mut self, side: Side, key: usize, msg: InFlight<P>) {
+        let part = self.part(side);
+        if part.recording.contains(&key) {
+            part.msgs.push(msg);
+        }
+    }
+
+    /// Nothing more can arrive on stream `key`.
+    pub fn closed(&mut self, side: Side, key: usize) {
+        self.part(side).recording.remove(&key);
+    }
+
+    /// The requester exited. It stays done in later snapshots, and whatever
+    /// it was still recording is kept as is.
+    pub fn requester_done(&mut self) {
+        self.requester_done = true;
+        self.requester.state.get_or_insert(Local::Done);
+        self.requester.recording.clear();
+    }
+
+    /// The node's share of the current snapshot, once both processes are
+    /// done recording. Given out once per snapshot.
+    pub fn take(&mut self, id: P) -> Option<Snapshot<P>> {
+        if self.written || !self.listener.complete() || !self.requester.complete() {
+            return None;
+        }
+        self.written = true;
+        Some(Snapshot {
+            number: self.number,
+            id,
+            listener: self.listener.state.clone().unwrap(),
+            requester: self.requester.state.clone().unwrap(),
+            to_listener: mem::take(&mut self.listener.msgs),
+            to_requester: mem::take(&mut self.requester.msgs),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rc(seq: u64) -> Local<usize> {
+        Local::Rc {
+            seq,
+            requesting: false,
+            permissions: vec![],
+            deferred: vec![],
+        }
+    }
+
+    fn msg(from: usize, ts: u128) -> InFlight<usize> {
+        InFlight {
+            from,
+            typ: MessageType::Request,
+            ts,
+        }
+    }
+
+    #[test]
+    fn given_out_once_both_sides_are_done() {
+        let mut r = Recorder::default();
+        assert_eq!(r.next(), 1);
+        assert!(r.on_marker(Side::Listener, 1, 0));
+        r.record(Side::Listener, rc(3), [1, 2].into_iter());
+        assert!(r.on_marker(Side::Requester, 1, 5));
+        r.record(
+            Side::Requester,
+            Local::Waiting {
+                ts: 2,
+                from: vec![1],
+            },
+            [6].into_iter(),
+        );
+        assert!(r.take(0).is_none());
+
+        // Recorded only until the stream's marker
+        r.on_message(Side::Listener, 1, msg(1, 4));
+        r.on_message(Side::Listener, 0, msg(0, 5));
+        assert!(!r.on_marker(Side::Listener, 1, 1));
+        r.on_message(Side::Listener, 1, msg(1, 6));
+        r.closed(Side::Listener, 2);
+        assert!(r.take(0).is_none());
+        r.on_message(Side::Requester, 6, msg(3, 7));
+        assert!(!r.on_marker(Side::Requester, 1, 6));
+
+        let snap = r.take(0).unwrap();
+        assert_eq!(snap.number, 1);
+        assert!(matches!(snap.listener, Local::Rc { seq: 3, .. }));
+        assert!(matches!(snap.requester, Local::Waiting { ts: 2, .. }));
+        let ts = |x: &[InFlight<usize>]| x.iter().map(|m| m.ts).collect::<Vec<_>>();
+        assert_eq!(ts(&snap.to_listener), [4]);
+        assert_eq!(ts(&snap.to_requester), [7]);
+        assert!(r.take(0).is_none());
+        assert_eq!(r.next(), 2);
+    }
+
+    #[test]
+    fn higher_marker_abandons_the_snapshot() {
+        let mut r = Recorder::default();
+        assert!(r.on_marker(Side::Listener, 1, 0));
+        r.record(Side::Listener, rc(1), [1].into_iter());
+        r.on_message(Side::Listener, 1, msg(1, 2));
+
+        // Someone started snapshot 2 before 1 was done here
+        assert!(r.on_marker(Side::Listener, 2, 1));
+        r.record(Side::Listener, rc(5), [0].into_iter());
+        assert!(r.on_marker(Side::Requester, 2, 3));
+        r.record(Side::Requester, Local::Done, std::iter::empty());
+        // A late marker of snapshot 1 changes nothing
+        assert!(!r.on_marker(Side::Listener, 1, 0));
+        assert!(r.take(0).is_none());
+        assert!(!r.on_marker(Side::Listener, 2, 0));
+
+        let snap = r.take(0).unwrap();
+        assert_eq!(snap.number, 2);
+        assert!(matches!(snap.listener, Local::Rc { seq: 5, .. }));
+        assert!(snap.to_listener.is_empty());
+    }
+
+    #[test]
+    fn requester_stays_done() {
+        let mut r = Recorder::default();
+        assert!(r.on_marker(Side::Requester, 1, 0));
+        r.record(
+            Side::Requester,
+            Local::Waiting {
+                ts: 1,
+                from: vec![],
+            },
+            [0].into_iter(),
+        );
+        // Exiting keeps what was recorded, and stops recording
+        r.requester_done();
+        assert!(r.on_marker(Side::Listener, 1, 0));
+        r.record(Side::Listener, rc(1), std::iter::empty());
+        let snap = r.take(0).unwrap();
+        assert!(matches!(snap.requester, Local::Waiting { ts: 1, .. }));
+
+        // Later snapshots need nothing from the requester
+        for n in 2..4 {
+            assert!(r.on_marker(Side::Listener, n, 0));
+            r.record(Side::Listener, rc(n), std::iter::empty());
+            let snap = r.take(0).unwrap();
+            assert_eq!(snap.number, n);
+            assert!(matches!(snap.requester, Local::Done));
+        }
+    }
+}
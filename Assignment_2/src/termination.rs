@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use crate::utils::MessageType;
+
+/// How long the initiator waits for its token before sending a fresh one,
+/// in case the old one went to a node that crashed or left.
+pub const ROUND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Messages a listener has to act on: those the requester sends. Replies to
+/// a requester that is done need no answer, so they are not counted.
+pub fn counts(typ: MessageType) -> bool {
+    matches!(
+        typ,
+        MessageType::Request | MessageType::Release | MessageType::Yield
+    )
+}
+
+/// Termination detection traffic, counted apart from the messages of the
+/// mutex protocol so it doesn't skew message complexity.
+pub fn detection(typ: MessageType) -> bool {
+    matches!(typ, MessageType::Token | MessageType::Terminate)
+}
+
+/// Safra's token, carried in a message's `ts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub round: u64,
+    pub count: i64, // sent minus received by the nodes it has visited
+    pub black: bool,
+}
+
+impl From<u128> for Token {
+    fn from(x: u128) -> Self {
+        Self {
+            round: (x >> 65) as u64,
+            count: x as u64 as i64,
+            black: (x >> 64) & 1 == 1,
+        }
+    }
+}
+
+impl From<Token> for u128 {
+    fn from(x: Token) -> Self {
+        (x.round as u128) << 65 | (x.black as u128) << 64 | x.count as u64 as u128
+    }
+}
+
+/// What a node does after `Safra::step`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step<P> {
+    Idle,
+    Pass(P, Token),
+    /// The run is over: tell every node, ourselves included.
+    Announce,
+}
+
+/// Safra's termination detection, over any kind of node id.
+///
+/// A node is passive once its requester is done. The token goes round the
+/// ring of live members in id order, started by the smallest, and is held
+/// by active nodes. Each node adds what it sent minus what it received, and
+/// blackens the token if it received anything since it last passed it on.
+/// The run is over when a white token comes back to a white initiator with
+/// a count of zero: every node is passive and nothing is in flight.
+///
+/// Counts are kept per peer, so crashed and departed nodes can be left out
+/// of the sum. A peer that comes and goes blackens the node, and so makes
+/// the round fail. The listener starts counting again when a peer's
+/// messages arrive on a new stream, and the requester when it reconnects,
+/// so a restarted node's counts still match.
+#[derive(Debug, Default)]
+pub struct Safra<P> {
+    sent: HashMap<P, i64>,
+    recv: HashMap<P, i64>,
+    via: HashMap<P, usize>, // stream the peer's messages are counted on
+    black: bool,
+    passive: bool,
+    token: Option<Token>,            // held until passive
+    round: u64,                      // latest seen
+    started: Option<(u64, Instant)>, // our last round as initiator
+    announced: bool,
+    done: bool,
+}
+
+impl<P: Copy + Eq + Hash + Ord> Safra<P> {
+    /// The requester wrote `copies` of a message to `to`.
+    pub fn sent(&mut self, to: P, copies: usize) {
+        *self.sent.entry(to).or_default() += copies as i64;
+    }
+
+    /// The listener read a message from `from` on stream `stream`. Streams
+    /// are numbered in the order they were accepted, so anything left on an
+    /// older one is not counted.
+    pub fn received(&mut self, from: P, stream: usize) {
+        match self.via.get(&from) {
+            Some(&s) if stream < s => return,
+            Some(&s) if stream == s => {}
+            _ => {
+                self.via.insert(from, stream);
+                self.recv.insert(from, 0);
+            }
+        }
+        *self.recv.entry(from).or_default() += 1;
+        self.black = true;
+    }
+
+    /// The requester reconnected to `to`, which may have restarted.
+    pub fn reset(&mut self, to: P) {
+        self.sent.insert(to, 0);
+        self.black = true;
+    }
+
+    /// A node joined, left, crashed or came back.
+    pub fn changed(&mut self) {
+        self.black = true;
+    }
+
+    /// The requester is done.
+    pub fn passive(&mut self) {
+        self.passive = true;
+    }
+
+    pub fn on_token(&mut self, token: Token) {
+        // Older rounds were given up on
+        if token.round >= self.round {
+            self.round = token.round;
+            self.token = Some(token);
+        }
+    }
+
+    /// Someone detected termination, possibly us.
+    pub fn finish(&mut self) {
+        self.done = true;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Passes on a token we hold if passive, and as the initiator decides
+    /// whether the run is over or starts a new round. `ring` is the sorted
+    /// list of live members.
+    pub fn step(&mut self, me: P, ring: &[P]) -> Step<P> {
+        if self.done || self.announced || !self.passive || ring.is_empty() {
+            return Step::Idle;
+        }
+        let count: i64 = ring
+            .iter()
+            .map(|p| self.sent.get(p).unwrap_or(&0) - self.recv.get(p).unwrap_or(&0))
+            .sum();
+        let next = *ring.iter().find(|&&x| x > me).unwrap_or(&ring[0]);
+
+        if ring[0] != me {
+            let Some(token) = self.token.take() else {
+                return Step::Idle;
+            };
+            let black = token.black || self.black;
+            self.black = false;
+            let token = Token {
+                count: token.count + count,
+                black,
+                ..token
+            };
+            return Step::Pass(next, token);
+        }
+
+        match self.token.take() {
+            Some(token) if self.started.is_some_and(|(r, _)| r == token.round) => {
+                if !token.black && !self.black && token.count + count == 0 {
+                    self.announced = true;
+                    return Step::Announce;
+                }
+            }
+            // Someone else's round, from before we were the initiator
+            Some(_) => {}
+            None if self
+                .started
+                .is_none_or(|(_, t)| t.elapsed() > ROUND_TIMEOUT) => {}
+            None => return Step::Idle,
+        }
+        self.round += 1;
+        self.black = false;
+        self.started = Some((self.round, Instant::now()));
+        Step::Pass(
+            next,
+            Token {
+                round: self.round,
+                count: 0,
+                black: false,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_round_trip() {
+        let tokens = [
+            Token {
+                round: 0,
+                count: 0,
+                black: false,
+            },
+            Token {
+                round: 7,
+                count: -3,
+                black: true,
+            },
+            Token {
+                round: u64::MAX >> 1,
+                count: i64::MIN,
+                black: false,
+            },
+            Token {
+                round: 1,
+                count: i64::MAX,
+                black: true,
+            },
+        ];
+        for token in tokens {
+            assert_eq!(Token::from(u128::from(token)), token);
+        }
+    }
+
+    /// Hands whatever `from` passes on to the next node, and returns it.
+    fn pass(nodes: &mut [Safra<usize>], from: usize) -> Token {
+        let ring = [0, 1, 2];
+        let Step::Pass(to, token) = nodes[from].step(from, &ring) else {
+            panic!("Node {} did not pass the token", from);
+        };
+        assert_eq!(to, (from + 1) % 3);
+        nodes[to].on_token(token);
+        token
+    }
+
+    #[test]
+    fn announces_after_a_clean_round() {
+        let mut nodes: Vec<Safra<usize>> = (0..3).map(|_| Safra::default()).collect();
+        nodes[1].sent(2, 1);
+        for x in nodes.iter_mut() {
+            x.passive();
+        }
+
+        // A message is in flight, so the count comes back non-zero
+        pass(&mut nodes, 0);
+        pass(&mut nodes, 1);
+        let token = pass(&mut nodes, 2);
+        assert_eq!((token.round, token.count, token.black), (1, 1, false));
+
+        // It arrives: the count adds up, but node 2 was active since
+        nodes[2].received(1, 0);
+        assert_eq!(pass(&mut nodes, 0).round, 2);
+        pass(&mut nodes, 1);
+        let token = pass(&mut nodes, 2);
+        assert_eq!((token.count, token.black), (0, true));
+
+        // Nothing happened during this one
+        assert_eq!(pass(&mut nodes, 0).round, 3);
+        pass(&mut nodes, 1);
+        let token = pass(&mut nodes, 2);
+        assert_eq!((token.count, token.black), (0, false));
+        assert_eq!(nodes[0].step(0, &[0, 1, 2]), Step::Announce);
+        assert_eq!(nodes[0].step(0, &[0, 1, 2]), Step::Idle);
+    }
+}
@@ -0,0 +1,467 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Write,
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use either::Either::Right;
+use rand::thread_rng;
+
+use crate::{
+    broadcast::Group,
+    chaos::Chaos,
+    detector::Detector,
+    topology::Router,
+    utils::{epoch_micros, Action, LogEntry, Message, MessageType},
+    Params, Region,
+};
+
+/// How long a node with no use for a token keeps it before passing it on,
+/// so an idle ring does not spin flat out
+pub const IDLE_PASS: Duration = Duration::from_millis(1);
+
+const PING: usize = 0; // the token that grants the CS
+const PONG: usize = 1; // only there to notice that the ping is lost
+
+/// A token to pass on: which, its incarnation and its hops so far
+type Pass = (usize, u64, u64);
+
+/// Where a node stands with the two tokens.
+#[derive(Debug, Default)]
+struct Tokens {
+    ping: Option<(u64, u64)>,         // held: incarnation and hops
+    wanting: bool,                    // from asking until leaving the CS
+    m: Option<(usize, u64)>,          // last token seen, Misra's `m`
+    seen: [(u64, u64); 2],            // newest of each token, so copies are dropped
+    passed: Option<(u128, u64, u64)>, // last pong sent: to whom, incarnation, hops
+    done: HashSet<u128>,              // nodes that have sent `Terminate`
+}
+
+/// What came of taking in a token
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Received {
+    out: Vec<Pass>,           // tokens to pass on
+    regenerated: Option<u64>, // the incarnation of a token made anew
+    granted: bool,            // the ping is ours to enter the CS with
+}
+
+impl Tokens {
+    /// Takes in a token, at a node with nobody to pass to if `alone`.
+    fn receive(&mut self, kind: usize, mut x: u64, hops: u64, alone: bool) -> Received {
+        let mut got = Received::default();
+        if (x, hops) <= self.seen[kind] {
+            // A copy, or older than one we have seen
+            return got;
+        }
+        self.seen[kind] = (x, hops);
+        let held = self.ping.is_some();
+        if self.m == Some((kind, x)) {
+            // Round again, and the other token has not been by
+            x += 1;
+            got.regenerated = Some(x);
+            self.seen[1 - kind] = (x, 0);
+            got.out.extend(self.take(1 - kind, x, 0, alone));
+        } else {
+            self.m = Some((kind, x));
+            if let (PONG, Some((y, h))) = (kind, self.ping) {
+                // They meet
+                x = x.max(y) + 1;
+                self.ping = Some((x, h));
+            }
+        }
+        got.out.extend(self.take(kind, x, hops, alone));
+        got.granted = !held && self.wanting && self.ping.is_some();
+        got
+    }
+
+    /// A token is here: the ping is kept if we want it, or have nobody to
+    /// pass it to. Returns the token if it is to be passed on.
+    fn take(&mut self, kind: usize, x: u64, hops: u64, alone: bool) -> Option<Pass> {
+        if kind == PING && (self.wanting || alone) {
+            self.ping = Some((x, hops));
+            return None;
+        }
+        Some((kind, x, hops))
+    }
+}
+
+/// Token ring mutual exclusion (Le Lann), with Misra's ping-pong scheme to
+/// regenerate a lost token.
+///
+/// Nodes pass the token round the ring in id order, and whoever wants the
+/// CS keeps it until it leaves. A second token, the pong, goes round too.
+/// Both carry the same incarnation number. A node records the last token
+/// it saw; if the same token comes round again without the other having
+/// been by, the other was lost, and the node makes a new one. When the two
+/// tokens meet at the node holding the ping, both move to the next
+/// incarnation, so a ping held for a long CS is not taken for lost.
+///
+/// With a failure detector the ring skips suspected nodes, and a pong
+/// passed to a node that is then suspected is passed again, so the pong
+/// survives the crash that loses the ping. Losing both tokens at once is
+/// not recovered from, and a wrong suspicion can make a second ping.
+///
+/// A `Token` carries its incarnation in `ts`, and which token it is and how
+/// many hops it has made in `clock`. Node 0 starts with both.
+pub struct TokenRingNode {
+    id: u128,
+    ips: HashMap<u128, SocketAddr>,
+    group: Group,
+    fd: Option<Detector>,
+    dir: String,
+    pub mc: AtomicU64,
+    cs_mc: AtomicU64, // Sent since the last exit
+    pub init: Instant,
+    tokens: Mutex<Tokens>,
+    log: Mutex<Vec<LogEntry>>,
+    granted: Sender<()>,
+    grants: Mutex<Receiver<()>>,
+    finished: Sender<()>, // everyone has terminated or is suspected
+    done: Mutex<Receiver<()>>,
+}
+
+impl TokenRingNode {
+    pub fn new(id: u128, ips: HashMap<u128, SocketAddr>) -> Self {
+        let (granted, grants) = mpsc::channel();
+        let (finished, done) = mpsc::channel();
+        let addrs = (0..ips.len() as u128).map(|i| ips[&i]).collect();
+        let mut tokens = Tokens::default();
+        if id == 0 {
+            tokens.seen = [(1, 0), (1, 0)];
+        }
+        Self {
+            id,
+            group: Group::new(id as usize, addrs),
+            ips,
+            fd: None,
+            dir: "log/ring".to_string(),
+            mc: 0.into(),
+            cs_mc: 0.into(),
+            init: Instant::now(),
+            tokens: Mutex::new(tokens),
+            log: Mutex::default(),
+            granted,
+            grants: Mutex::new(grants),
+            finished,
+            done: Mutex::new(done),
+        }
+    }
+
+    /// Runs a heartbeat failure detector, and skips suspected nodes.
+    pub fn with_detector(mut self, timeout: Duration) -> Self {
+        let peers = self.ips.iter().map(|(&x, &a)| (Right(x), a)).collect();
+        let fd = Detector::new(Right(self.id), peers, timeout);
+        self.fd = Some(match &self.group.chaos {
+            Some(chaos) => fd.with_chaos(chaos.clone()),
+            None => fd,
+        });
+        self
+    }
+
+    pub fn with_chaos(mut self, path: &str, ips: &[SocketAddr]) -> Self {
+        let me = self.ips[&self.id];
+        let chaos = Arc::new(Chaos::new(me, path, ips));
+        self.fd = self.fd.map(|fd| fd.with_chaos(chaos.clone()));
+        self.group.chaos = Some(chaos);
+        self
+    }
+
+    pub fn with_router(mut self, router: Router) -> Self {
+        self.group.router = Some(Arc::new(router));
+        self
+    }
+
+    pub fn with_log_dir(mut self, dir: &str) -> Self {
+        self.dir = dir.to_string();
+        self
+    }
+
+    fn entry(&self, act: Action) {
+        self.log.lock().unwrap().push(LogEntry {
+            pid: Right(self.id),
+            ts: self.init.elapsed().as_micros(),
+            act,
+            clock: None,
+        });
+    }
+
+    fn suspected(&self, pid: u128) -> bool {
+        self.fd.as_ref().is_some_and(|fd| fd.suspected(&Right(pid)))
+    }
+
+    /// The next live node round the ring, if anyone else is left
+    fn next(&self) -> Option<u128> {
+        let n = self.ips.len() as u128;
+        (1..n)
+            .map(|i| (self.id + i) % n)
+            .find(|&x| !self.suspected(x))
+    }
+
+    fn send(&self, to: u128, typ: MessageType, ts: u128, clock: Vec<u64>) {
+        self.mc.fetch_add(1, Ordering::SeqCst);
+        self.cs_mc.fetch_add(1, Ordering::SeqCst);
+        let msg = Message::new_rc(self.id, typ, ts).with_clock(clock);
+        self.group.send(to as usize, msg);
+    }
+
+    /// Passes a token on to the next live node. A lone node keeps the ping,
+    /// and lets the pong go.
+    fn pass(&self, tokens: &mut Tokens, kind: usize, (x, hops): (u64, u64)) {
+        let Some(to) = self.next() else {
+            if kind == PING {
+                tokens.ping = Some((x, hops));
+            }
+            return;
+        };
+        if kind == PONG {
+            tokens.passed = Some((to, x, hops + 1));
+        }
+        self.send(
+            to,
+            MessageType::Token,
+            x as u128,
+            vec![kind as u64, hops + 1],
+        );
+    }
+
+    /// Takes in a token. Returns the tokens to pass on, after a pause.
+    fn receive(&self, tokens: &mut Tokens, kind: usize, x: u64, hops: u64) -> Vec<Pass> {
+        let got = tokens.receive(kind, x, hops, self.next().is_none());
+        if let Some(x) = got.regenerated {
+            self.entry(Action::Regenerate(x as u128));
+        }
+        if got.granted {
+            let _ = self.granted.send(());
+        }
+        got.out
+    }
+
+    fn handle(&self, msg: Message) {
+        let from = msg.id.expect_right("");
+        match msg.typ {
+            MessageType::Token => {
+                let (kind, hops) = (msg.clock[0] as usize, msg.clock[1]);
+                let out = {
+                    let mut tokens = self.tokens.lock().unwrap();
+                    self.receive(&mut tokens, kind, msg.ts as u64, hops)
+                };
+                if !out.is_empty() {
+                    thread::sleep(IDLE_PASS);
+                }
+                for (kind, x, hops) in out {
+                    self.pass(&mut self.tokens.lock().unwrap(), kind, (x, hops));
+                }
+            }
+            MessageType::Terminate => {
+                self.tokens.lock().unwrap().done.insert(from);
+                self.check_done();
+            }
+            _ => {
+                dbg!(msg);
+                panic!("Unexpected message")
+            }
+        }
+    }
+
+    /// Lets the node go once every other node has terminated or is suspected.
+    fn check_done(&self) {
+        let tokens = self.tokens.lock().unwrap();
+        let n = self.ips.len() as u128;
+        if (0..n).all(|x| tokens.done.contains(&x) || self.suspected(x)) {
+            let _ = self.finished.send(());
+        }
+    }
+
+    /// Runs heartbeat rounds until the node is done, logging suspicions. A
+    /// pong passed to a node that is now suspected goes to the next one.
+    fn detector_thread(&self, stop: Receiver<()>) {
+        let Some(fd) = &self.fd else {
+            return;
+        };
+        while stop.try_recv().is_err() {
+            for (pid, up) in fd.tick() {
+                let pid = pid.expect_right("");
+                self.entry(if up {
+                    Action::Recover(Right(pid))
+                } else {
+                    Action::Suspect(Right(pid))
+                });
+                let mut tokens = self.tokens.lock().unwrap();
+                match tokens.passed {
+                    Some((to, x, hops)) if !up && to == pid && tokens.seen[PONG] < (x, hops) => {
+                        // With the hops it would have passed it on with, so
+                        // if it did, one of the two is dropped as a copy
+                        self.pass(&mut tokens, PONG, (x, hops));
+                    }
+                    _ => {}
+                }
+                drop(tokens);
+                self.check_done();
+            }
+        }
+    }
+
+    fn enter_cs(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.wanting = true;
+        if tokens.ping.is_some() {
+            return;
+        }
+        drop(tokens);
+        self.grants.lock().unwrap().recv().unwrap();
+    }
+
+    fn exit_cs(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.wanting = false;
+        if let Some(ping) = tokens.ping.take() {
+            self.pass(&mut tokens, PING, ping);
+        }
+    }
+
+    /// Connects to the others on `rx`, enters the CS as `params` says, and
+    /// keeps passing the tokens on until the others are done. The log goes
+    /// to `node_<id>.log`.
+    pub fn run(self: &Arc<Self>, rx: TcpListener, params: Params) {
+        let me = self.clone();
+        self.group
+            .start(rx, Arc::new(move |msg: Message| me.handle(msg)));
+        println!("Connections established.");
+        let (stop, stopped) = mpsc::channel();
+        let me = self.clone();
+        let detector = thread::spawn(move || me.detector_thread(stopped));
+
+        if self.id == 0 {
+            let mut tokens = self.tokens.lock().unwrap();
+            self.pass(&mut tokens, PONG, (1, 0));
+            self.pass(&mut tokens, PING, (1, 0));
+        }
+
+        let mut rng = thread_rng();
+        for i in 0..params.requests() {
+            self.entry(Action::Internal);
+            params.sleep(&mut rng, Region::Out, i, self.init);
+
+            self.entry(Action::Ask(i as u128));
+            self.enter_cs();
+            self.entry(Action::Acquire);
+            if let Some(chaos) = &self.group.chaos {
+                chaos.enter();
+            }
+            params.sleep(&mut rng, Region::In, i, self.init);
+
+            if let Some(chaos) = &self.group.chaos {
+                chaos.exit();
+            }
+            self.entry(Action::Exit);
+            self.exit_cs();
+            self.entry(Action::Messages(self.cs_mc.swap(0, Ordering::SeqCst)));
+        }
+
+        // The tokens keep going round until nobody needs them
+        self.entry(Action::Terminate);
+        self.mc
+            .fetch_add(self.ips.len() as u64 - 1, Ordering::SeqCst);
+        self.group
+            .send_all(Message::new_rc(self.id, MessageType::Terminate, 0));
+        self.handle(Message::new_rc(self.id, MessageType::Terminate, 0));
+        self.done.lock().unwrap().recv().unwrap();
+        let _ = stop.send(());
+        detector.join().unwrap();
+
+        let start = LogEntry {
+            pid: Right(self.id),
+            ts: 0,
+            act: Action::Start(epoch_micros() - self.init.elapsed().as_micros()),
+            clock: None,
+        };
+        let mut log = self.log.lock().unwrap();
+        log.push(start);
+        log.sort_by_key(|x| x.ts);
+        let mut file = File::create(format!("{}/node_{}.log", self.dir, self.id)).unwrap();
+        for x in log.iter() {
+            writeln!(file, "{}", x).unwrap();
+        }
+        if let Some(chaos) = &self.group.chaos {
+            chaos.dump(&format!("{}/cs_{}.log", self.dir, self.id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passes(got: &Received) -> Vec<Pass> {
+        let mut out = got.out.clone();
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn lost_ping_is_regenerated() {
+        let mut t = Tokens::default();
+        assert_eq!(passes(&t.receive(PING, 1, 1, false)), [(PING, 1, 1)]);
+        assert_eq!(passes(&t.receive(PONG, 1, 1, false)), [(PONG, 1, 1)]);
+        assert_eq!(t.receive(PONG, 1, 1, false), Received::default());
+
+        // The ping never comes round again, but the pong does
+        let got = t.receive(PONG, 1, 4, false);
+        assert_eq!(got.regenerated, Some(2));
+        assert_eq!(passes(&got), [(PING, 2, 0), (PONG, 2, 4)]);
+
+        // The new ping is not taken for lost when the pong is next by
+        let got = t.receive(PING, 2, 3, false);
+        assert_eq!(got.regenerated, None);
+        assert_eq!(passes(&got), [(PING, 2, 3)]);
+    }
+
+    #[test]
+    fn lost_pong_is_regenerated() {
+        let mut t = Tokens::default();
+        t.receive(PONG, 1, 1, false);
+        t.receive(PING, 1, 1, false);
+
+        t.wanting = true;
+        let got = t.receive(PING, 1, 4, false);
+        assert_eq!(got.regenerated, Some(2));
+        assert!(got.granted);
+        assert_eq!(passes(&got), [(PONG, 2, 0)]);
+        assert_eq!(t.ping, Some((2, 4)));
+    }
+
+    #[test]
+    fn long_cs_is_not_a_lost_ping() {
+        let mut t = Tokens {
+            wanting: true,
+            ..Default::default()
+        };
+        let got = t.receive(PING, 1, 1, false);
+        assert!(got.granted && got.out.is_empty());
+
+        // The pong goes round twice while the ping is held
+        for (x, hops) in [(1, 1), (2, 4), (3, 7)] {
+            let got = t.receive(PONG, x, hops, false);
+            assert_eq!(got.regenerated, None);
+            assert!(!got.granted);
+            assert_eq!(passes(&got), [(PONG, x + 1, hops)]);
+            assert_eq!(t.ping, Some((x + 1, 1)));
+        }
+    }
+
+    #[test]
+    fn lone_node_keeps_the_ping() {
+        let mut t = Tokens::default();
+        assert!(t.receive(PING, 1, 1, true).out.is_empty());
+        assert_eq!(t.ping, Some((1, 1)));
+        assert_eq!(passes(&t.receive(PONG, 1, 1, true)), [(PONG, 2, 1)]);
+    }
+}
@@ -0,0 +1,427 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt::Display,
+    fs,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    conn::{CONNECT_TIMEOUT, MAX_BACKOFF, MIN_BACKOFF},
+    utils::{Message, MessageType},
+};
+
+/// How long a relay keeps trying to reach the next hop, so nodes can start
+/// in any order
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a node that is done keeps relaying after the last traffic
+const RELAY_QUIET: Duration = Duration::from_secs(1);
+
+/// Which nodes may open TCP connections to each other. Nodes are numbered as
+/// in the config, and links go both ways.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Topology {
+    adj: BTreeMap<usize, BTreeSet<usize>>,
+}
+
+impl Topology {
+    /// An empty graph of `n` nodes
+    fn empty(n: usize) -> Self {
+        Self {
+            adj: (0..n).map(|i| (i, BTreeSet::new())).collect(),
+        }
+    }
+
+    fn link(&mut self, a: usize, b: usize) {
+        if a != b {
+            self.adj.get_mut(&a).unwrap().insert(b);
+            self.adj.get_mut(&b).unwrap().insert(a);
+        }
+    }
+
+    /// Reads an adjacency list as in `Assignment_1/inp-params.txt`: a first
+    /// line whose first number is `n`, then a line per node with its number
+    /// and its neighbours, counting from 1.
+    pub fn parse(x: &str) -> Result<Self, String> {
+        let mut lines = x
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.trim().is_empty() && !l.starts_with('#'));
+        let first = lines.next().ok_or("empty adjacency list")?.1;
+        let n: usize = first
+            .split_whitespace()
+            .next()
+            .and_then(|x| x.parse().ok())
+            .ok_or(format!("1: expected `n ...`, got {:?}", first))?;
+
+        let mut out = Self::empty(n);
+        for (i, l) in lines {
+            let bad = |what: &str| format!("{}: {}: {:?}", i + 1, what, l);
+            let nums = l
+                .split_whitespace()
+                .map(|x| x.parse::<usize>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| bad("expected node numbers"))?;
+            if let Some(x) = nums.iter().find(|&&x| x == 0 || x > n) {
+                return Err(bad(&format!("node {} is not between 1 and {}", x, n)));
+            }
+            for &j in &nums[1..] {
+                out.link(nums[0] - 1, j - 1);
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn read(path: &str) -> Result<Self, String> {
+        let buf = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&buf).map_err(|e| format!("{}:{}", path, e))
+    }
+
+    pub fn ring(n: usize) -> Self {
+        let mut out = Self::empty(n);
+        for i in 0..n {
+            out.link(i, (i + 1) % n);
+        }
+        out
+    }
+
+    /// Everyone linked to node 0
+    pub fn star(n: usize) -> Self {
+        let mut out = Self::empty(n);
+        for i in 1..n {
+            out.link(0, i);
+        }
+        out
+    }
+
+    /// Binary tree, in heap order
+    pub fn tree(n: usize) -> Self {
+        let mut out = Self::empty(n);
+        for i in 1..n {
+            out.link(i, (i - 1) / 2);
+        }
+        out
+    }
+
+    /// A random spanning tree, plus each other link with probability `p`.
+    /// The same seed gives every node the same graph.
+    pub fn random(n: usize, p: f64, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut out = Self::empty(n);
+        for i in 1..n {
+            out.link(i, rng.gen_range(0..i));
+        }
+        for i in 0..n {
+            for j in i + 1..n {
+                if rng.gen_bool(p) {
+                    out.link(i, j);
+                }
+            }
+        }
+        out
+    }
+
+    /// A topology as given in the config: `ring`, `star`, `tree`,
+    /// `random <p> [seed]`, or the path of an adjacency list, for `n` nodes
+    pub fn from_spec(spec: &str, n: usize) -> Result<Self, String> {
+        let mut it = spec.split_whitespace();
+        let out = match it.next() {
+            Some("ring") => Self::ring(n),
+            Some("star") => Self::star(n),
+            Some("tree") => Self::tree(n),
+            Some("random") => {
+                let bad = || format!("Expected `random <p> [seed]`, got {:?}", spec);
+                let p: f64 = it.next().and_then(|x| x.parse().ok()).ok_or_else(bad)?;
+                if !(0.0..=1.0).contains(&p) {
+                    return Err(bad());
+                }
+                let seed = match it.next() {
+                    Some(x) => x.parse().map_err(|_| bad())?,
+                    None => 0,
+                };
+                Self::random(n, p, seed)
+            }
+            _ => Self::read(spec)?,
+        };
+        if out.len() != n {
+            return Err(format!(
+                "{} has {} nodes, but {} are listed",
+                spec,
+                out.len(),
+                n
+            ));
+        }
+        Ok(out)
+    }
+
+    pub fn len(&self) -> usize {
+        self.adj.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.adj.is_empty()
+    }
+
+    pub fn neighbours(&self, i: usize) -> impl Iterator<Item = usize> + '_ {
+        self.adj.get(&i).into_iter().flatten().copied()
+    }
+
+    pub fn is_neighbour(&self, a: usize, b: usize) -> bool {
+        self.adj.get(&a).is_some_and(|x| x.contains(&b))
+    }
+
+    /// First hop of a shortest path from `from` to every node it can reach,
+    /// passing only through nodes below `relays`. Ties go to the lower
+    /// numbered neighbour.
+    pub fn next_hops(&self, from: usize, relays: usize) -> BTreeMap<usize, usize> {
+        let mut out = BTreeMap::new();
+        let mut queue = VecDeque::new();
+        for j in self.neighbours(from) {
+            out.insert(j, j);
+            queue.push_back(j);
+        }
+        while let Some(i) = queue.pop_front() {
+            if i >= relays {
+                continue;
+            }
+            for j in self.neighbours(i) {
+                if j != from && !out.contains_key(&j) {
+                    out.insert(j, out[&i]);
+                    queue.push_back(j);
+                }
+            }
+        }
+        out
+    }
+
+    /// Whether every node can reach every other through the first `relays`
+    pub fn connected(&self, relays: usize) -> bool {
+        self.adj
+            .keys()
+            .all(|&i| self.next_hops(i, relays).len() + 1 == self.len())
+    }
+}
+
+impl Display for Topology {
+    /// The adjacency list format `parse` reads
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.len())?;
+        for (i, x) in self.adj.iter() {
+            write!(f, "{}", i + 1)?;
+            for j in x {
+                write!(f, " {}", j + 1)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Multi-hop routing over a `Topology`.
+///
+/// A node only opens TCP connections to its neighbours. Every connection
+/// starts with a `Route` message naming the node it is for. A node that
+/// accepts a connection for someone else connects on to the next hop and
+/// splices the two streams together, so the ends see one FIFO stream, and
+/// EOF and crashes travel along it. Addresses the router does not know, such
+/// as those of spares that got a new port, are connected to directly.
+///
+/// Relays run in the node's process, so a node that is done lingers until
+/// it has nothing left to relay. Messages count once however many hops they
+/// take, and heartbeats, which go over UDP, are sent directly.
+#[derive(Debug, Clone)]
+pub struct Router {
+    me: usize,
+    addrs: Vec<SocketAddr>,
+    next: BTreeMap<usize, usize>,
+    last: Arc<Mutex<Instant>>, // when we last accepted or relayed anything
+}
+
+impl Router {
+    /// Router of node `me`. Only the first `relays` nodes forward, since the
+    /// spares may be out of the cluster.
+    pub fn new(topology: &Topology, me: usize, addrs: Vec<SocketAddr>, relays: usize) -> Self {
+        Self {
+            me,
+            next: topology.next_hops(me, relays),
+            addrs,
+            last: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    /// Opens a stream to the node at `addr`, through the next hop if it is
+    /// not a neighbour.
+    pub fn connect(&self, addr: &SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        let Some(to) = self.addrs.iter().position(|x| x == addr) else {
+            return TcpStream::connect_timeout(addr, timeout);
+        };
+        let hop = match self.next.get(&to) {
+            Some(&hop) => hop,
+            None if to == self.me => to,
+            None => return Err(io::Error::other(format!("No route to node {}", to))),
+        };
+        let mut stream = TcpStream::connect_timeout(&self.addrs[hop], timeout)?;
+        let header = Message::new_rc(self.me as u128, MessageType::Route, to as u128);
+        stream.write_all(&Vec::from(header))?;
+        Ok(stream)
+    }
+
+    /// Reads the header of an accepted stream. Returns the stream if it is
+    /// for us; otherwise relays it and returns `None`.
+    pub fn accept(&self, mut stream: TcpStream) -> Option<TcpStream> {
+        self.touch();
+        let mut buf = [0; 33];
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT)).ok()?;
+        stream.read_exact(&mut buf).ok()?;
+        stream.set_read_timeout(None).ok()?;
+        let msg = Message::from(&buf[..]);
+        if msg.typ != MessageType::Route {
+            println!("Dropping a connection without a route: {:?}", msg);
+            return None;
+        }
+        let to = msg.ts as usize;
+        if to == self.me {
+            return Some(stream);
+        }
+        let router = self.clone();
+        thread::spawn(move || router.relay(stream, to));
+        None
+    }
+
+    /// Forwards `from` to node `to`, until either side closes.
+    fn relay(&self, from: TcpStream, to: usize) {
+        let Some(&addr) = self.addrs.get(to) else {
+            return;
+        };
+        let deadline = Instant::now() + RELAY_TIMEOUT;
+        let mut delay = MIN_BACKOFF;
+        let onward = loop {
+            match self.connect(&addr, CONNECT_TIMEOUT) {
+                Ok(x) => break x,
+                Err(_) if Instant::now() < deadline => {
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_BACKOFF);
+                }
+                // Dropping `from` tells the sender
+                Err(_) => return,
+            }
+        };
+        let (a, b) = (from.try_clone().unwrap(), onward.try_clone().unwrap());
+        let router = self.clone();
+        let back = thread::spawn(move || router.splice(onward, from));
+        self.splice(a, b);
+        back.join().unwrap();
+    }
+
+    /// Copies `from` into `to`. A clean EOF is passed on as one; an error
+    /// closes both streams, so the copy the other way ends too.
+    fn splice(&self, mut from: TcpStream, mut to: TcpStream) {
+        let mut buf = [0; 1024];
+        loop {
+            let res = from.read(&mut buf).and_then(|n| {
+                self.touch();
+                to.write_all(&buf[..n]).map(|_| n)
+            });
+            match res {
+                Ok(0) => {
+                    let _ = to.shutdown(Shutdown::Write);
+                    return;
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    let _ = from.shutdown(Shutdown::Both);
+                    let _ = to.shutdown(Shutdown::Both);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Keeps relaying for the others once we are done, until nothing has
+    /// come through for a while, so that what they route through us is not
+    /// lost when we exit. Connections for us are dropped.
+    pub fn linger(&self, rx: &TcpListener) {
+        rx.set_nonblocking(true).unwrap();
+        while self.last.lock().unwrap().elapsed() < RELAY_QUIET {
+            match rx.accept() {
+                Ok((x, _)) => {
+                    x.set_nonblocking(false).unwrap();
+                    self.accept(x);
+                }
+                Err(_) => thread::sleep(MIN_BACKOFF),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_assignment_1_input() {
+        let t = Topology::parse(include_str!("../../Assignment_1/inp-params.txt")).unwrap();
+        assert_eq!(t, Topology::ring(15));
+        assert!(t.connected(15));
+    }
+
+    #[test]
+    fn rejects_bad_lists() {
+        assert!(Topology::parse("").is_err());
+        assert!(Topology::parse("x\n").is_err());
+        let e = Topology::parse("3\n1 2\n2 4\n").unwrap_err();
+        assert!(e.starts_with("3: node 4"), "{}", e);
+        assert!(Topology::parse("3\n0 1\n").is_err());
+        assert!(Topology::parse("3\n1 two\n").is_err());
+        // Comments and blank lines are skipped
+        let mut path = Topology::empty(3);
+        path.link(0, 1);
+        path.link(1, 2);
+        assert_eq!(Topology::parse("# line\n3\n\n1 2\n2 3\n"), Ok(path));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for t in [
+            Topology::ring(5),
+            Topology::star(4),
+            Topology::tree(7),
+            Topology::random(8, 0.3, 7),
+            Topology::empty(2),
+        ] {
+            assert_eq!(Topology::parse(&t.to_string()), Ok(t));
+        }
+    }
+
+    #[test]
+    fn next_hops_stop_at_relays() {
+        // 0 - 1 - 2 - 3, with 3 a spare
+        let mut t = Topology::empty(4);
+        t.link(0, 1);
+        t.link(1, 2);
+        t.link(2, 3);
+        assert_eq!(t.next_hops(0, 4), BTreeMap::from([(1, 1), (2, 1), (3, 1)]));
+        // Node 2 may not relay, so 3 is out of reach
+        assert_eq!(t.next_hops(0, 2), BTreeMap::from([(1, 1), (2, 1)]));
+        // A spare still reaches its neighbours
+        assert_eq!(t.next_hops(3, 2), BTreeMap::from([(2, 2)]));
+        assert!(t.connected(3));
+        assert!(!t.connected(2));
+
+        // Shortest path, ties to the lower neighbour
+        let r = Topology::ring(6);
+        assert_eq!(r.next_hops(0, 6)[&3], 1);
+        assert_eq!(r.next_hops(0, 6)[&4], 5);
+        assert!(Topology::star(5).next_hops(1, 5).values().all(|&x| x == 0));
+        assert!(!Topology::star(5).connected(0));
+    }
+}
@@ -1,14 +1,20 @@
 use std::{
-    collections::HashMap,
     fmt::Display,
-    fs::File,
-    io::Read,
     mem,
     net::{SocketAddr, TcpStream},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use either::Either::{self, Left, Right};
 
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{clocks::VectorClock, conn::connect};
+
+/// Node ID of either `MaekawaNode` or `RCNode`
+pub type Pid = Either<(u64, u64), u128>;
+
 #[derive(Debug, Clone, Copy)]
 pub enum Action {
     Internal,
@@ -24,7 +30,25 @@ pub enum Action {
     Release(Either<(u64, u64), u128>),
     Exit,
 
+    Suspect(Either<(u64, u64), u128>),
+    Recover(Either<(u64, u64), u128>),
+
     Terminate,
+
+    Join(Either<(u64, u64), u128>),
+    Leave(Either<(u64, u64), u128>),
+
+    Start(u128),   // microseconds since the epoch
+    Ask(u128),     // Lamport timestamp of a CS request
+    Messages(u64), // sent and received for the last CS
+
+    Broadcast(u128),    // value
+    Deliver(Pid, u128), // sender and value
+
+    Elect,       // stood for leader
+    Leader(Pid), // learned who the leader is
+
+    Regenerate(u128), // a lost ring token, with the incarnation it got
 }
 
 impl Display for Action {
@@ -49,12 +73,37 @@ impl Display for Action {
 
             Action::Exit => write!(f, "exited the critical section"),
 
+            Action::Suspect(Left(x)) => write!(f, "suspects process {:?} has crashed", x),
+            Action::Suspect(Right(x)) => write!(f, "suspects process {} has crashed", x),
+            Action::Recover(Left(x)) => write!(f, "stopped suspecting process {:?}", x),
+            Action::Recover(Right(x)) => write!(f, "stopped suspecting process {}", x),
+
             Action::Terminate => write!(f, "terminated"),
+
+            Action::Join(Left(x)) => write!(f, "saw process {:?} join", x),
+            Action::Join(Right(x)) => write!(f, "saw process {} join", x),
+            Action::Leave(Left(x)) => write!(f, "saw process {:?} leave", x),
+            Action::Leave(Right(x)) => write!(f, "saw process {} leave", x),
+
+            Action::Start(x) => write!(f, "started {}us after the epoch", x),
+            Action::Ask(x) => write!(f, "requested the CS with timestamp {}", x),
+            Action::Messages(x) => write!(f, "exchanged {} messages for the CS", x),
+
+            Action::Broadcast(x) => write!(f, "broadcast message {}", x),
+            Action::Deliver(Left(p), x) => {
+                write!(f, "delivered message {} from process {:?}", x, p)
+            }
+            Action::Deliver(Right(p), x) => write!(f, "delivered message {} from process {}", x, p),
+
+            Action::Elect => write!(f, "stood for leader"),
+            Action::Leader(Left(p)) => write!(f, "learned that process {:?} is the leader", p),
+            Action::Leader(Right(p)) => write!(f, "learned that process {} is the leader", p),
+            Action::Regenerate(x) => write!(f, "regenerated a lost token at incarnation {}", x),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum MessageType {
     Request,
     Reply,
@@ -63,12 +112,44 @@ pub enum MessageType {
     Inquire,
     Yield,
     Terminate,
+    Heartbeat,
+    Join,      // `ts` carries the sender's listening port
+    Leave,     // the sender will send no more requests, and may be forgotten
+    Token,     // termination detection, packed into `ts`
+    Marker,    // snapshot, `ts` carries its number
+    Route,     // starts a connection, `ts` carries the node it is for
+    Broadcast, // causal broadcast, `ts` carries the value
+    Multicast, // total-order broadcast, `ts` carries the value
+    Propose,   // priority proposed for a `Multicast`
+    Agree,     // priority agreed on for a `Multicast`
+    Elect,     // leader election, `ts` carries the candidate
+    Alive,     // answer to an `Elect`
+    Leader,    // `ts` carries the elected leader
+}
+/// Size of a message without a clock
+const MSG_SIZE: usize = 33;
+/// Set in the type byte when a clock follows the message
+const CLOCK: u8 = 0x80;
+
+/// Length of the message at the start of `x`, if all of it is there
+pub fn frame_len(x: &[u8]) -> Option<usize> {
+    if x.len() < MSG_SIZE {
+        return None;
+    }
+    if x[16] & CLOCK == 0 {
+        return Some(MSG_SIZE);
+    }
+    let n = u16::from_ne_bytes(x.get(MSG_SIZE..MSG_SIZE + 2)?.try_into().unwrap());
+    let len = MSG_SIZE + 2 + 8 * n as usize;
+    (x.len() >= len).then_some(len)
 }
+
 #[derive(Debug, Clone)]
 pub struct Message {
     pub id: Either<(u64, u64), u128>,
     pub typ: MessageType,
     pub ts: u128,
+    pub clock: Vec<u64>, // causal clock of the sender, if it keeps one
 }
 
 impl Message {
@@ -77,6 +158,7 @@ impl Message {
             id: Left(id),
             typ,
             ts,
+            clock: vec![],
         }
     }
 
@@ -85,23 +167,33 @@ impl Message {
             id: Right(id),
             typ,
             ts,
+            clock: vec![],
         }
     }
 
+    pub fn with_clock(mut self, clock: Vec<u64>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     pub fn flip(&mut self) {
         self.id = match self.id {
-            Left(x) => Right(unsafe { mem::transmute(x) }),
-            Right(x) => Left(unsafe { mem::transmute(x) }),
+            Left(x) => Right(unsafe { mem::transmute::<(u64, u64), u128>(x) }),
+            Right(x) => Left(unsafe { mem::transmute::<u128, (u64, u64)>(x) }),
         };
     }
 }
 
-impl Into<Vec<u8>> for Message {
-    fn into(self) -> Vec<u8> {
+impl From<Message> for Vec<u8> {
+    fn from(msg: Message) -> Self {
         let mut out = vec![];
-        let id: [u8; 16] = unsafe { mem::transmute_copy(&self.id) };
-        let ts: [u8; 16] = unsafe { mem::transmute_copy(&self.ts) };
-        let typ: u8 = match self.typ {
+        // Copy the id itself, not the `Either` tag.
+        let id: [u8; 16] = match msg.id {
+            Left(x) => unsafe { mem::transmute::<(u64, u64), [u8; 16]>(x) },
+            Right(x) => x.to_ne_bytes(),
+        };
+        let ts: [u8; 16] = unsafe { mem::transmute_copy(&msg.ts) };
+        let typ: u8 = match msg.typ {
             MessageType::Request => 1,
             MessageType::Reply => 2,
             MessageType::Release => 3,
@@ -109,10 +201,33 @@ impl Into<Vec<u8>> for Message {
             MessageType::Inquire => 5,
             MessageType::Yield => 6,
             MessageType::Terminate => 7,
+            MessageType::Heartbeat => 8,
+            MessageType::Join => 9,
+            MessageType::Leave => 10,
+            MessageType::Token => 11,
+            MessageType::Marker => 12,
+            MessageType::Route => 13,
+            MessageType::Broadcast => 14,
+            MessageType::Multicast => 15,
+            MessageType::Propose => 16,
+            MessageType::Agree => 17,
+            MessageType::Elect => 18,
+            MessageType::Alive => 19,
+            MessageType::Leader => 20,
         };
         out.extend(id);
-        out.push(typ);
-        out.extend(ts);
+        // The top bit says a clock follows
+        if msg.clock.is_empty() {
+            out.push(typ);
+            out.extend(ts);
+        } else {
+            out.push(typ | CLOCK);
+            out.extend(ts);
+            out.extend((msg.clock.len() as u16).to_ne_bytes());
+            for x in msg.clock {
+                out.extend(x.to_ne_bytes());
+            }
+        }
         out
     }
 }
@@ -125,7 +240,14 @@ impl From<&[u8]> for Message {
         ts.copy_from_slice(&x[17..33]);
         let id: (u64, u64) = unsafe { mem::transmute_copy(&id) };
         let ts: u128 = unsafe { mem::transmute_copy(&ts) };
-        let typ = match x[16] {
+        let clock = match frame_len(x) {
+            Some(len) if len > MSG_SIZE => x[MSG_SIZE + 2..len]
+                .chunks(8)
+                .map(|x| u64::from_ne_bytes(x.try_into().unwrap()))
+                .collect(),
+            _ => vec![],
+        };
+        let typ = match x[16] & !CLOCK {
             1 => MessageType::Request,
             2 => MessageType::Reply,
             3 => MessageType::Release,
@@ -133,12 +255,26 @@ impl From<&[u8]> for Message {
             5 => MessageType::Inquire,
             6 => MessageType::Yield,
             7 => MessageType::Terminate,
+            8 => MessageType::Heartbeat,
+            9 => MessageType::Join,
+            10 => MessageType::Leave,
+            11 => MessageType::Token,
+            12 => MessageType::Marker,
+            13 => MessageType::Route,
+            14 => MessageType::Broadcast,
+            15 => MessageType::Multicast,
+            16 => MessageType::Propose,
+            17 => MessageType::Agree,
+            18 => MessageType::Elect,
+            19 => MessageType::Alive,
+            20 => MessageType::Leader,
             _ => unreachable!("{}", x[16]),
         };
         Self {
             id: Left(id),
             typ,
             ts,
+            clock,
         }
     }
 }
@@ -149,6 +285,7 @@ pub struct LogEntry {
     pub pid: Either<(u64, u64), u128>,
     pub ts: u128,
     pub act: Action,
+    pub clock: Option<VectorClock>, // when the node keeps a causal clock
 }
 
 impl Display for LogEntry {
@@ -157,52 +294,135 @@ impl Display for LogEntry {
             Left(x) => format!("{:?}", x),
             Right(x) => format!("{:?}", x),
         };
-        write!(f, "Process {:?} {} at time {:?}", pid, self.act, self.ts,)
+        write!(f, "Process {:?} {} at time {:?}", pid, self.act, self.ts,)?;
+        match &self.clock {
+            Some(x) => write!(f, " with clock {}", x),
+            None => Ok(()),
+        }
     }
 }
 
-pub fn get_ips() -> (HashMap<(u64, u64), SocketAddr>, HashMap<u128, SocketAddr>) {
-    // Read all ip addresses from a file
-    let mut file = File::open("ips.txt").unwrap();
-    let mut buf = String::new();
-    file.read_to_string(&mut buf).unwrap();
-    let mut out = (HashMap::new(), HashMap::new());
-    for (i, l) in buf.lines().enumerate() {
-        let mut it = l.split_whitespace();
-        let id = (
-            it.next().unwrap().parse().unwrap(),
-            it.next().unwrap().parse().unwrap(),
-        );
-        let ip = it.next().unwrap();
-        let port = it.next().unwrap();
-        let addr = format!("{}:{}", ip, port).parse::<SocketAddr>().unwrap();
-        out.0.insert(id, addr);
-        out.1.insert(i as u128, addr);
+/// Reads back a process id as `LogEntry` and `Action` print it.
+fn parse_pid(x: &str) -> Option<Pid> {
+    match x.strip_prefix('(').and_then(|x| x.strip_suffix(')')) {
+        Some(x) => {
+            let (a, b) = x.split_once(", ")?;
+            Some(Left((a.parse().ok()?, b.parse().ok()?)))
+        }
+        None => Some(Right(x.parse().ok()?)),
     }
-    out
 }
 
-pub fn get_a_stream(addr: &SocketAddr) -> TcpStream {
-    loop {
-        match TcpStream::connect(addr) {
-            Ok(x) => {
-                // println!("Connected to {}", addr);
-                break x;
-            }
-            Err(_) => {
-                // thread::sleep(Duration::from_micros(100));
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(x: &str) -> Result<Self, Self::Err> {
+        let pid = |p: &str, s: &str| {
+            x.strip_prefix(p)
+                .and_then(|x| x.strip_suffix(s))
+                .and_then(parse_pid)
+        };
+        let num = |p: &str, s: &str| {
+            x.strip_prefix(p)
+                .and_then(|x| x.strip_suffix(s))
+                .and_then(|x| x.parse().ok())
+        };
+        Ok(match x {
+            "executed an internal action" => Action::Internal,
+            "acquired the CS" => Action::Acquire,
+            "exited the critical section" => Action::Exit,
+            "terminated" => Action::Terminate,
+            "stood for leader" => Action::Elect,
+            _ => {
+                if let Some(p) = pid("received request from Process ", "") {
+                    Action::Query(p)
+                } else if let Some(p) = pid("sent request to process ", "") {
+                    Action::Request(p)
+                } else if let Some(p) = pid("sent reply to Process ", "") {
+                    Action::Grant(p)
+                } else if let Some(p) = pid("received reply from Process ", "") {
+                    Action::Reply(p)
+                } else if let Some(p) = pid("received release from process ", "") {
+                    Action::Release(p)
+                } else if let Some(p) = pid("suspects process ", " has crashed") {
+                    Action::Suspect(p)
+                } else if let Some(p) = pid("stopped suspecting process ", "") {
+                    Action::Recover(p)
+                } else if let Some(p) = pid("saw process ", " join") {
+                    Action::Join(p)
+                } else if let Some(p) = pid("saw process ", " leave") {
+                    Action::Leave(p)
+                } else if let Some(t) = num("started ", "us after the epoch") {
+                    Action::Start(t)
+                } else if let Some(t) = num("requested the CS with timestamp ", "") {
+                    Action::Ask(t)
+                } else if let Some(x) = num("exchanged ", " messages for the CS") {
+                    Action::Messages(x as u64)
+                } else if let Some(x) = num("broadcast message ", "") {
+                    Action::Broadcast(x)
+                } else if let Some((v, p)) = x
+                    .strip_prefix("delivered message ")
+                    .and_then(|x| x.split_once(" from process "))
+                    .and_then(|(v, p)| Some((v.parse().ok()?, parse_pid(p)?)))
+                {
+                    Action::Deliver(p, v)
+                } else if let Some(p) = pid("learned that process ", " is the leader") {
+                    Action::Leader(p)
+                } else if let Some(x) = num("regenerated a lost token at incarnation ", "") {
+                    Action::Regenerate(x)
+                } else {
+                    return Err(format!("Unknown action: {}", x));
+                }
             }
-        }
+        })
     }
 }
 
-pub fn get_msgs(buf: &mut [u8], b: &usize) -> Vec<Message> {
-    let msgsz = 33;
-    let msgc = *b / msgsz;
-    let out = (0..msgc)
-        .map(|i| Message::from(&buf[i * msgsz..(i + 1) * msgsz]))
-        .collect::<Vec<Message>>();
-    buf.fill(0);
+impl FromStr for LogEntry {
+    type Err = String;
+
+    fn from_str(x: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("Bad log entry: {}", x);
+        let rest = x.strip_prefix("Process \"").ok_or_else(bad)?;
+        let (pid, rest) = rest.split_once("\" ").ok_or_else(bad)?;
+        let (act, ts) = rest.rsplit_once(" at time ").ok_or_else(bad)?;
+        let (ts, clock) = match ts.split_once(" with clock ") {
+            Some((ts, x)) => (ts, Some(x.parse()?)),
+            None => (ts, None),
+        };
+        Ok(Self {
+            pid: parse_pid(pid).ok_or_else(bad)?,
+            ts: ts.parse().map_err(|_| bad())?,
+            act: act.parse()?,
+            clock,
+        })
+    }
+}
+
+/// Wall clock time, in microseconds since the epoch
+pub fn epoch_micros() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros()
+}
+
+/// Connects to `addr`, backing off until the node is up
+pub fn get_a_stream(addr: &SocketAddr) -> TcpStream {
+    connect(addr)
+}
+
+/// Parses the messages in `pending` followed by `buf`. A message cut off by
+/// the end of the read is kept in `pending` for the next one.
+pub fn get_msgs(pending: &mut Vec<u8>, buf: &[u8]) -> Vec<Message> {
+    pending.extend_from_slice(buf);
+    let mut out = vec![];
+    let mut at = 0;
+    while let Some(len) = frame_len(&pending[at..]) {
+        out.push(Message::from(&pending[at..at + len]));
+        at += len;
+    }
+    pending.drain(..at);
     dbg!(&out);
     out
 }
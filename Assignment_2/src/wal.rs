@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    sync::Mutex,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// A change to protocol state that must survive a crash.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Record {
+    /// Next Lamport timestamp this node may use.
+    Clock(u64),
+    /// `RCNode::quorum` flag: whether we must ask this node for permission.
+    Permission(u128, bool),
+    /// Maekawa arbiter: current lock holder and its request timestamp.
+    Lock(Option<((u64, u64), u128)>),
+}
+
+/// Protocol state rebuilt from the log.
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    pub seq: u64,
+    pub quorum: HashMap<u128, bool>,
+    pub locked: Option<((u64, u64), u128)>,
+}
+
+impl State {
+    fn apply(&mut self, rec: Record) {
+        match rec {
+            Record::Clock(x) => self.seq = self.seq.max(x),
+            Record::Permission(pid, x) => {
+                self.quorum.insert(pid, x);
+            }
+            Record::Lock(x) => self.locked = x,
+        }
+    }
+
+    /// Smallest set of records that rebuilds this state.
+    fn records(&self) -> Vec<Record> {
+        let mut out = vec![Record::Clock(self.seq), Record::Lock(self.locked)];
+        out.extend(
+            self.quorum
+                .iter()
+                .map(|(&pid, &x)| Record::Permission(pid, x)),
+        );
+        out
+    }
+}
+
+/// Write-ahead log of protocol state, one JSON record per line.
+///
+/// Records are appended before the message they justify is sent, so a node
+/// restarted from its log never reuses a timestamp or hands out a permission
+/// it no longer holds.
+pub struct Wal {
+    file: Mutex<File>,
+    pub state: State, // As recovered when the log was opened
+}
+
+impl Wal {
+    /// Opens the log at `path`, replaying and compacting whatever is there.
+    pub fn open(path: &str) -> Self {
+        let mut state = State::default();
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                // A torn last line means we crashed mid-write; nothing was sent after it.
+                let Ok(line) = line else { break };
+                match serde_json::from_str(&line) {
+                    Ok(rec) => state.apply(rec),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        for rec in state.records() {
+            writeln!(file, "{}", serde_json::to_string(&rec).unwrap()).unwrap();
+        }
+        file.sync_data().unwrap();
+
+        Self {
+            file: Mutex::new(file),
+            state,
+        }
+    }
+
+    pub fn append(&self, rec: Record) {
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", serde_json::to_string(&rec).unwrap()).unwrap();
+        file.sync_data().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    fn path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("wal_{}_{}.log", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn replays_records() {
+        let path = path("replay");
+        let wal = Wal::open(&path);
+        assert_eq!(wal.state.seq, 0);
+        wal.append(Record::Clock(3));
+        wal.append(Record::Permission(1, false));
+        wal.append(Record::Lock(Some(((0, 1), 2))));
+        wal.append(Record::Clock(7));
+        wal.append(Record::Permission(1, true));
+        wal.append(Record::Permission(2, false));
+        wal.append(Record::Lock(None));
+        wal.append(Record::Lock(Some(((1, 0), 5))));
+        drop(wal);
+
+        let state = Wal::open(&path).state;
+        assert_eq!(state.seq, 7);
+        assert_eq!(state.quorum, HashMap::from([(1, true), (2, false)]));
+        assert_eq!(state.locked, Some(((1, 0), 5)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stops_at_a_torn_line() {
+        let path = path("torn");
+        let wal = Wal::open(&path);
+        wal.append(Record::Clock(4));
+        wal.append(Record::Permission(3, true));
+        drop(wal);
+
+        // Cut off mid-record
+        let mut buf = fs::read(&path).unwrap();
+        buf.extend_from_slice(b"{\"Clock\":9");
+        fs::write(&path, &buf).unwrap();
+        let state = Wal::open(&path).state;
+        assert_eq!(state.seq, 4);
+        assert_eq!(state.quorum, HashMap::from([(3, true)]));
+
+        // Cut off mid-character, and anything after it is not read either
+        let mut buf = fs::read(&path).unwrap();
+        buf.extend_from_slice(b"{\"Clock\":\xe2\x82\n{\"Clock\":9}\n");
+        fs::write(&path, &buf).unwrap();
+        assert_eq!(Wal::open(&path).state.seq, 4);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compacts_on_open() {
+        let path = path("compact");
+        let wal = Wal::open(&path);
+        for x in 0..10 {
+            wal.append(Record::Clock(x));
+            wal.append(Record::Permission(1, x % 2 == 0));
+        }
+        drop(wal);
+        fs::write(&path, fs::read_to_string(&path).unwrap() + "{\"Lock\":").unwrap();
+
+        let wal = Wal::open(&path);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "{\"Clock\":9}\n{\"Lock\":null}\n{\"Permission\":[1,false]}\n"
+        );
+
+        // Appends go after the compacted records
+        wal.append(Record::Clock(12));
+        drop(wal);
+        assert_eq!(Wal::open(&path).state.seq, 12);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+        fs::remove_file(&path).unwrap();
+    }
+}